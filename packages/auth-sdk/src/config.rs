@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 /// Simple token validation configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TokenValidationConfig {
     /// List of valid issuers and their JWKS URLs
     pub jwks_issuers: HashMap<String, String>,
@@ -12,16 +12,6 @@ pub struct TokenValidationConfig {
     pub allow_test_tokens: bool,
 }
 
-impl Default for TokenValidationConfig {
-    fn default() -> Self {
-        Self {
-            jwks_issuers: HashMap::new(),
-            ship_symmetric_key: None,
-            allow_test_tokens: false,
-        }
-    }
-}
-
 impl TokenValidationConfig {
     pub fn new() -> Self {
         Self::default()
@@ -119,6 +119,7 @@ fn extract_roles_from_claims(claims: &Claims) -> Vec<String> {
 
 /// Token validation result
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum TokenValidationResult {
     Valid { claims: Claims },
     Invalid { reason: String },
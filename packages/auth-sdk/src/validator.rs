@@ -131,16 +131,15 @@ impl TokenValidator {
                     claims: token_data.claims,
                 });
             }
-            Err(e) => match e.kind() {
-                jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
+            Err(e) => {
+                if let jsonwebtoken::errors::ErrorKind::ExpiredSignature = e.kind() {
                     return Ok(TokenValidationResult::Expired);
                 }
-                _ => (),
-            },
+            }
         }
 
         Ok(TokenValidationResult::Invalid {
-            reason: format!("All key formats failed"),
+            reason: "All key formats failed".to_string(),
         })
     }
 
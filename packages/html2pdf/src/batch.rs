@@ -0,0 +1,309 @@
+use std::{
+    collections::HashSet,
+    io::{Cursor, Write},
+};
+
+use axum::{
+    Json,
+    extract::{Extension, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine as _, engine::general_purpose};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    AppState, cnfg,
+    error::HttpError,
+    html2pdf::{Html2PdfRequest, accepts, render_pdf},
+    render_queue::Priority,
+};
+
+/// Item name together with its rendered PDF or the reason it failed.
+type ItemOutcome = (Option<String>, Result<Vec<u8>, HttpError>);
+
+/// Name of the archive entry listing the items that failed in ZIP mode.
+const ERRORS_ENTRY: &str = "errors.json";
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchFormat {
+    Json,
+    Zip,
+}
+
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    pub items: Vec<BatchItem>,
+    /// Response format; falls back to the `Accept` header, then to JSON
+    pub format: Option<BatchFormat>,
+}
+
+#[derive(Deserialize)]
+pub struct BatchItem {
+    /// File name inside the ZIP archive, also echoed back in JSON results
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub request: Html2PdfRequest,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchItemResult {
+    pub index: usize,
+    pub name: Option<String>,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pdf_base64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub async fn html2pdf_batch(
+    State(app_state): State<AppState>,
+    Extension(priority): Extension<Priority>,
+    headers: HeaderMap,
+    Json(payload): Json<BatchRequest>,
+) -> Result<Response, HttpError> {
+    tracing::debug!("Received batch of {} HTML2PDF items", payload.items.len());
+
+    check_size(payload.items.len(), cnfg::get().batch_max_items)?;
+
+    let format = payload.format.unwrap_or_else(|| {
+        if accepts(&headers, "application/zip") {
+            BatchFormat::Zip
+        } else {
+            BatchFormat::Json
+        }
+    });
+
+    // Items are rendered concurrently up to the pool's limit, results stay in request order
+    let concurrency = app_state.browser_pool.concurrency_limit();
+    let results: Vec<ItemOutcome> = futures::stream::iter(payload.items)
+        .map(|item| {
+            let app_state = app_state.clone();
+            async move {
+                let result = match item.request.source() {
+                    Ok(source) => {
+                        render_pdf(&app_state, source, &item.request.options, priority).await
+                    }
+                    Err(err) => Err(err),
+                };
+                (item.name, result)
+            }
+        })
+        .buffered(concurrency)
+        .collect()
+        .await;
+
+    match format {
+        BatchFormat::Json => Ok(Json(json_results(results)).into_response()),
+        BatchFormat::Zip => {
+            let archive = tokio::task::spawn_blocking(move || zip_results(results)).await??;
+
+            let mut response = archive.into_response();
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/zip"),
+            );
+            response.headers_mut().insert(
+                header::CONTENT_DISPOSITION,
+                HeaderValue::from_static("attachment; filename=\"batch.zip\""),
+            );
+            Ok(response)
+        }
+    }
+}
+
+/// Batches need at least one item and at most `BATCH_MAX_ITEMS`.
+fn check_size(items: usize, max_items: usize) -> Result<(), HttpError> {
+    if items == 0 {
+        return Err(HttpError::BadRequest(anyhow::anyhow!("Empty batch")));
+    }
+    if items > max_items {
+        return Err(HttpError::BadRequest(anyhow::anyhow!(
+            "Batch has {} items, at most {} are allowed",
+            items,
+            max_items
+        )));
+    }
+
+    Ok(())
+}
+
+fn json_results(results: Vec<ItemOutcome>) -> Vec<BatchItemResult> {
+    results
+        .into_iter()
+        .enumerate()
+        .map(|(index, (name, result))| match result {
+            Ok(pdf) => BatchItemResult {
+                index,
+                name,
+                status: StatusCode::OK.as_u16(),
+                pdf_base64: Some(general_purpose::STANDARD.encode(pdf)),
+                error: None,
+            },
+            Err(err) => failed_item(index, name, &err),
+        })
+        .collect()
+}
+
+/// Pack the successful PDFs into a ZIP archive; failures are listed in `errors.json`.
+fn zip_results(results: Vec<ItemOutcome>) -> anyhow::Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    let mut used_names = HashSet::new();
+    let mut errors = Vec::new();
+
+    for (index, (name, result)) in results.into_iter().enumerate() {
+        match result {
+            Ok(pdf) => {
+                let entry = unique_entry_name(name.as_deref(), index, &mut used_names);
+                zip.start_file(entry, options)?;
+                zip.write_all(&pdf)?;
+            }
+            Err(err) => errors.push(failed_item(index, name, &err)),
+        }
+    }
+
+    if !errors.is_empty() {
+        zip.start_file(ERRORS_ENTRY, options)?;
+        zip.write_all(&serde_json::to_vec_pretty(&errors)?)?;
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+fn failed_item(index: usize, name: Option<String>, err: &HttpError) -> BatchItemResult {
+    if let HttpError::InternalServerError(err) = err {
+        tracing::error!("Batch item {} failed: {}", index, err);
+    }
+
+    BatchItemResult {
+        index,
+        name,
+        status: err.status().as_u16(),
+        pdf_base64: None,
+        error: Some(err.to_string()),
+    }
+}
+
+/// A flat, `.pdf` suffixed archive entry name that does not collide with earlier ones.
+fn unique_entry_name(name: Option<&str>, index: usize, used: &mut HashSet<String>) -> String {
+    let base = name
+        .map(|name| {
+            name.rsplit(['/', '\\'])
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .trim_end_matches(".pdf")
+                .to_string()
+        })
+        .filter(|name| !name.is_empty() && name != "." && name != "..")
+        .unwrap_or_else(|| format!("document-{}", index + 1));
+
+    let mut candidate = format!("{}.pdf", base);
+    let mut suffix = 2;
+    while !used.insert(candidate.clone()) {
+        candidate = format!("{}-{}.pdf", base, suffix);
+        suffix += 1;
+    }
+
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use zip::ZipArchive;
+
+    use super::*;
+
+    fn entry_names(names: &[Option<&str>]) -> Vec<String> {
+        let mut used = HashSet::new();
+        names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| unique_entry_name(*name, index, &mut used))
+            .collect()
+    }
+
+    #[test]
+    fn entry_names_are_flat_and_unique() {
+        assert_eq!(
+            entry_names(&[
+                Some("invoice.pdf"),
+                Some("invoice"),
+                Some("../../etc/invoice.pdf"),
+                None,
+                Some("  "),
+                Some(".."),
+                Some("dir\\report"),
+            ]),
+            vec![
+                "invoice.pdf",
+                "invoice-2.pdf",
+                "invoice-3.pdf",
+                "document-4.pdf",
+                "document-5.pdf",
+                "document-6.pdf",
+                "report.pdf",
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_items_are_listed_in_errors_json() {
+        let results: Vec<ItemOutcome> = vec![
+            (Some("a".to_string()), Ok(b"%PDF-a".to_vec())),
+            (
+                Some("b".to_string()),
+                Err(HttpError::BadRequest(anyhow::anyhow!("Empty HTML content"))),
+            ),
+            (None, Ok(b"%PDF-c".to_vec())),
+        ];
+
+        let archive = zip_results(results).unwrap();
+        let mut archive = ZipArchive::new(Cursor::new(archive)).unwrap();
+        let mut names: Vec<_> = archive.file_names().map(str::to_string).collect();
+        names.sort();
+        assert_eq!(names, vec!["a.pdf", "document-3.pdf", ERRORS_ENTRY]);
+
+        let mut pdf = Vec::new();
+        archive
+            .by_name("a.pdf")
+            .unwrap()
+            .read_to_end(&mut pdf)
+            .unwrap();
+        assert_eq!(pdf, b"%PDF-a");
+
+        let errors: serde_json::Value =
+            serde_json::from_reader(archive.by_name(ERRORS_ENTRY).unwrap()).unwrap();
+        assert_eq!(errors[0]["index"], 1);
+        assert_eq!(errors[0]["name"], "b");
+        assert_eq!(errors[0]["status"], 400);
+        assert_eq!(errors.as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn archives_without_failures_have_no_errors_entry() {
+        let archive = zip_results(vec![(None, Ok(b"%PDF".to_vec()))]).unwrap();
+        let archive = ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(
+            archive.file_names().collect::<Vec<_>>(),
+            vec!["document-1.pdf"]
+        );
+    }
+
+    #[test]
+    fn batch_size_is_bounded() {
+        assert!(check_size(1, 3).is_ok());
+        assert!(check_size(3, 3).is_ok());
+        assert!(matches!(check_size(0, 3), Err(HttpError::BadRequest(_))));
+        assert!(matches!(check_size(4, 3), Err(HttpError::BadRequest(_))));
+    }
+}
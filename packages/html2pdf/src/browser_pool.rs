@@ -1,129 +1,695 @@
+use std::{
+    future::Future,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
 use anyhow::Result;
+use axum::body::Bytes;
+use base64::{Engine as _, engine::general_purpose};
 use chromiumoxide::{
     Page,
-    browser::{Browser, BrowserConfig},
-    cdp::browser_protocol::page::{PrintToPdfParams, PrintToPdfParamsBuilder},
+    cdp::browser_protocol::{
+        emulation::ClearDeviceMetricsOverrideParams,
+        io::{CloseParams, ReadParams},
+        page::{PrintToPdfParams, PrintToPdfParamsBuilder, PrintToPdfTransferMode},
+    },
 };
-use futures::StreamExt;
-use tokio::sync::{Mutex, Semaphore};
+use futures::{StreamExt, stream::BoxStream};
+use tokio::sync::mpsc;
+use url::Url;
+
+use crate::{
+    browser_shard::{BrowserHealth, BrowserShard, PooledPage, ShardLoad},
+    cnfg,
+    error::RenderError,
+    font_store::FontStore,
+    outline::{self, OutlineOptions, PageOutline},
+    render_queue::{Priority, QueueStats, RenderPermit, RenderQueue, WaitStats},
+    resources::{Interception, ResourcePolicy},
+    screenshot::ScreenshotOptions,
+    wait::{ReadyWaiter, WaitOptions},
+};
+
+/// Number of bytes requested from Chrome per `IO.read` call when streaming.
+const STREAM_CHUNK_SIZE: i64 = 256 * 1024;
+/// How often idle pages are closed and, with adaptive sizing, the concurrency limit revisited.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(15);
 
+/// Deadlines of a render and of its phases, from `AppConfig`. Running into one fails the render
+/// and the page is closed instead of going back to the pool.
+struct RenderDeadlines {
+    overall: Duration,
+    load: Duration,
+    wait: Duration,
+    print: Duration,
+}
+
+impl RenderDeadlines {
+    fn from_config() -> Self {
+        let config = cnfg::get();
+        Self {
+            overall: Duration::from_secs(config.render_timeout_secs),
+            load: Duration::from_secs(config.render_load_timeout_secs),
+            wait: Duration::from_secs(config.render_wait_timeout_secs),
+            print: Duration::from_secs(config.render_print_timeout_secs),
+        }
+    }
+}
+
+/// Run `future`, failing with a [`RenderError::RenderTimeout`] for `phase` once `limit` passed.
+async fn with_deadline<T>(
+    phase: &'static str,
+    limit: Duration,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    tokio::time::timeout(limit, future)
+        .await
+        .map_err(|_| RenderError::RenderTimeout {
+            phase,
+            timeout_ms: limit.as_millis(),
+        })?
+}
+
+/// PDF bytes streamed out of Chrome chunk by chunk.
+pub type PdfStream = BoxStream<'static, Result<Bytes>>;
+
+/// What a pooled page should load before printing.
+pub enum RenderSource {
+    /// Inline HTML written straight into the page.
+    Html(String),
+    /// A page the browser navigates to. Must already be allowed by the configured `UrlPolicy`.
+    Url(Url),
+    /// Entry point of an uploaded bundle on its virtual origin. The page and everything it
+    /// references has to be attached as assets of the resource policy.
+    Bundle(Url),
+}
+
+/// Everything needed to bring a pooled page into a printable state.
+pub struct PageLoad {
+    pub source: RenderSource,
+    pub wait: Option<WaitOptions>,
+    pub resources: ResourcePolicy,
+    /// Lane the render waits in for a free tab
+    pub priority: Priority,
+}
+
+/// Pages spread over several Chrome processes, so a crashing or runaway browser only affects
+/// the renders it hosts.
 pub struct BrowserPool {
-    browser: Browser,
-    page_pool: Mutex<Vec<Page>>,
-    semaphore: Semaphore,
-    max_pool_size: usize,
+    shards: Vec<Arc<BrowserShard>>,
+    queue: Arc<RenderQueue>,
+    /// Bounds of the concurrency limit under adaptive sizing
+    min_tabs: usize,
+    max_tabs: usize,
+    /// Organization fonts made available to every render
+    fonts: Arc<FontStore>,
+}
+
+/// A pooled page checked out together with the permit that allowed it.
+///
+/// A lease that is dropped without being released belongs to a render that failed, timed out or
+/// was cancelled because the client went away. Its page may be stuck mid-load, so it is closed
+/// instead of going back to the pool.
+struct PageLease {
+    pooled: Option<PooledPage>,
+    /// Request interception set up for the render's resource policy
+    interception: Option<Interception>,
+    load: ShardLoad,
+    _permit: RenderPermit,
+}
+
+impl PageLease {
+    fn page(&self) -> &Page {
+        &self.pooled.as_ref().expect("page of a released lease").page
+    }
+}
+
+impl Drop for PageLease {
+    fn drop(&mut self) {
+        if let Some(pooled) = self.pooled.take() {
+            let shard = Arc::clone(self.load.shard());
+            tokio::spawn(async move { shard.discard_page(pooled).await });
+        }
+    }
 }
 
 impl BrowserPool {
-    pub async fn new() -> Result<Self> {
-        Self::new_with_pool_size(10).await
+    pub async fn new(fonts: Arc<FontStore>) -> Result<Arc<Self>> {
+        let config = cnfg::get();
+        Self::new_with_pool_size(
+            fonts,
+            config.page_pool_min_size,
+            config.page_pool_max_size,
+            config.browser_count,
+        )
+        .await
     }
 
-    pub async fn new_with_pool_size(max_concurrent_tabs: usize) -> Result<Self> {
-        // Initialize the browser
-        let config = BrowserConfig::builder().build().map_err(|e| {
-            eprintln!("Failed to create browser config: {}", e);
-            anyhow::anyhow!("Browser config error")
-        })?;
+    /// Launch `browsers` Chrome processes sharing up to `max_concurrent_tabs` concurrent renders,
+    /// with `min_tabs` tabs opened up front and kept open while the pool is idle.
+    ///
+    /// With adaptive sizing the concurrency limit starts at `min_tabs` and moves between the two
+    /// bounds, otherwise it is `max_concurrent_tabs`.
+    pub async fn new_with_pool_size(
+        fonts: Arc<FontStore>,
+        min_tabs: usize,
+        max_concurrent_tabs: usize,
+        browsers: usize,
+    ) -> Result<Arc<Self>> {
+        let config = cnfg::get();
+        let browsers = browsers.max(1);
+        let max_tabs = max_concurrent_tabs.max(1);
+        let min_tabs = min_tabs.min(max_tabs);
 
-        let (browser, mut handler) = Browser::launch(config).await?;
+        // Idle pages are kept per browser, enough to serve its share of the renders
+        let min_pages = min_tabs.div_ceil(browsers);
+        let max_idle_pages = max_tabs.div_ceil(browsers);
+        let shards = futures::future::try_join_all(
+            (0..browsers).map(|index| BrowserShard::launch(index, min_pages, max_idle_pages)),
+        )
+        .await?;
 
-        // Spawn handler properly - this is crucial for chromiumoxide to work
-        // Don't break on errors as some WebSocket deserialization errors are normal
-        tokio::task::spawn(async move {
-            while let Some(_) = handler.next().await {
-                // Continue processing regardless of errors
-                // WebSocket deserialization errors are common and shouldn't stop the handler
-            }
+        let capacity = if config.page_pool_adaptive {
+            min_tabs.max(1)
+        } else {
+            max_tabs
+        };
+        let queue = RenderQueue::new(
+            capacity,
+            config.render_queue_max_backlog,
+            Duration::from_secs(config.render_queue_max_wait_secs),
+        );
+
+        let pool = Arc::new(BrowserPool {
+            shards,
+            queue,
+            min_tabs,
+            max_tabs,
+            fonts,
         });
+        tokio::spawn(Self::maintain(Arc::downgrade(&pool)));
 
-        Ok(BrowserPool {
-            browser,
-            page_pool: Mutex::new(Vec::new()),
-            semaphore: Semaphore::new(max_concurrent_tabs),
-            max_pool_size: max_concurrent_tabs,
-        })
+        Ok(pool)
+    }
+
+    /// Periodically close pages of idle browsers, top the pools back up after pages were
+    /// recycled, and resize the concurrency limit. The task ends with the pool.
+    async fn maintain(pool: Weak<Self>) {
+        let config = cnfg::get();
+        let idle_after = Duration::from_secs(config.page_pool_idle_secs);
+        let grow_wait = Duration::from_millis(config.page_pool_grow_wait_ms);
+
+        loop {
+            tokio::time::sleep(MAINTENANCE_INTERVAL).await;
+            let Some(pool) = pool.upgrade() else {
+                return;
+            };
+
+            for shard in &pool.shards {
+                if config.page_pool_idle_secs > 0 {
+                    shard.shrink_idle(idle_after).await;
+                }
+                BrowserShard::spawn_prewarm(shard);
+            }
+            if config.page_pool_adaptive {
+                pool.adapt_capacity(grow_wait);
+            }
+        }
+    }
+
+    /// Resize the render queue to the capacity the waits since the last check call for.
+    fn adapt_capacity(&self, grow_wait: Duration) {
+        let stats = self.queue.take_wait_stats();
+        let capacity = self.queue.capacity();
+
+        let target = adapted_capacity(&stats, capacity, self.min_tabs, self.max_tabs, grow_wait);
+        if target != capacity {
+            tracing::info!(
+                "Resizing render pool from {} to {} tabs (average wait {:?})",
+                capacity,
+                target,
+                stats.average_wait()
+            );
+            self.queue.resize(target);
+        }
+    }
+
+    /// Status of every browser, for health checks.
+    pub fn health(&self) -> Vec<BrowserHealth> {
+        self.shards.iter().map(|shard| shard.health()).collect()
+    }
+
+    /// Occupancy of the render queue, for health checks.
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue.stats()
     }
 
     pub async fn print_to_pdf(
         &self,
-        html: &str,
+        load: &PageLoad,
         custom_params: Option<PrintToPdfParams>,
+        outline: Option<&OutlineOptions>,
     ) -> Result<Vec<u8>> {
-        // Acquire semaphore permit to limit concurrent usage
-        //
-        let _permit = self.semaphore.acquire().await?;
+        let deadlines = RenderDeadlines::from_config();
+        let params = Self::pdf_params(custom_params);
 
-        // Try to get a page from the pool, or create a new one
-        let page = self.get_or_create_page().await?;
+        let (lease, pdf_result) = with_deadline("render", deadlines.overall, async {
+            let mut lease = self.acquire_page(load.priority).await?;
+
+            // Load the HTML content or target URL and wait until it is ready
+            self.load_page(&mut lease, load, &deadlines).await?;
+
+            let outline = match outline {
+                Some(outline) => Some(outline.prepare(lease.page()).await?),
+                None => None,
+            };
+
+            // Generate PDF
+            let mut pdf_result = with_deadline("print", deadlines.print, async {
+                Ok(lease.page().pdf(params.clone()).await?)
+            })
+            .await?;
+
+            if let Some(outline) = outline {
+                pdf_result =
+                    Self::outline_pdf(lease.page(), pdf_result, outline, params, &deadlines)
+                        .await?;
+            }
+
+            Ok((lease, pdf_result))
+        })
+        .await?;
+
+        // Return the page to the pool instead of closing it
+        self.release_page(lease).await;
+
+        Ok(pdf_result)
+    }
+
+    /// Add bookmarks for the headings of a printed page. With a table of contents, the page
+    /// numbers the first print gave the headings are filled in and the page is printed again.
+    async fn outline_pdf(
+        page: &Page,
+        pdf: Vec<u8>,
+        outline: PageOutline,
+        params: PrintToPdfParams,
+        deadlines: &RenderDeadlines,
+    ) -> Result<Vec<u8>> {
+        let outline = Arc::new(outline);
+
+        let pdf = if outline.toc {
+            let headings = Arc::clone(&outline);
+            let pages = tokio::task::spawn_blocking(move || {
+                outline::heading_pages(&pdf, &headings.headings)
+            })
+            .await??;
+            outline.fill_toc(page, &pages).await?;
+
+            with_deadline("print", deadlines.print, async {
+                Ok(page.pdf(params).await?)
+            })
+            .await?
+        } else {
+            pdf
+        };
+
+        tokio::task::spawn_blocking(move || outline::add_outline(pdf, &outline.headings)).await?
+    }
+
+    /// Like [`BrowserPool::print_to_pdf`], but asks Chrome to keep the PDF in an IO stream and
+    /// forwards it in chunks, so the document is never held in memory as a whole.
+    ///
+    /// The page and its permit stay checked out until the stream is drained or dropped.
+    ///
+    /// Failures before the first chunk are returned as errors. A failure after that ends the
+    /// stream with an `Err` item: the response status has already been sent, so the body is
+    /// aborted instead and hyper resets the connection rather than finishing the response. The
+    /// client sees an incomplete transfer, never a truncated PDF that looks complete.
+    pub async fn print_to_pdf_stream(
+        self: &Arc<Self>,
+        load: &PageLoad,
+        custom_params: Option<PrintToPdfParams>,
+    ) -> Result<PdfStream> {
+        let deadlines = RenderDeadlines::from_config();
+        let started = tokio::time::Instant::now();
+
+        let (lease, handle) = with_deadline("render", deadlines.overall, async {
+            let mut lease = self.acquire_page(load.priority).await?;
+
+            self.load_page(&mut lease, load, &deadlines).await?;
+
+            let params = PrintToPdfParams {
+                transfer_mode: Some(PrintToPdfTransferMode::ReturnAsStream),
+                ..Self::pdf_params(custom_params)
+            };
+            let handle = with_deadline("print", deadlines.print, async {
+                Ok(lease.page().execute(params).await?)
+            })
+            .await?
+            .result
+            .stream
+            .ok_or_else(|| anyhow::anyhow!("Chrome did not return a PDF stream handle"))?;
+
+            Ok((lease, handle))
+        })
+        .await?;
+
+        let (tx, mut rx) = mpsc::channel::<Result<Bytes>>(4);
+        let pool = Arc::clone(self);
+
+        tokio::spawn(async move {
+            // The client receives what was read so far, the overall deadline still applies
+            let deadline = started + deadlines.overall;
+            let streamed = tokio::time::timeout_at(deadline, async {
+                loop {
+                    let read = ReadParams {
+                        handle: handle.clone(),
+                        offset: None,
+                        size: Some(STREAM_CHUNK_SIZE),
+                    };
+                    let chunk = lease.page().execute(read).await?.result;
+
+                    let bytes = if chunk.base64_encoded.unwrap_or(false) {
+                        Bytes::from(general_purpose::STANDARD.decode(&chunk.data)?)
+                    } else {
+                        Bytes::from(chunk.data)
+                    };
+
+                    // Stop reading as soon as the receiving side has gone away
+                    if !bytes.is_empty() && tx.send(Ok(bytes)).await.is_err() {
+                        return Ok(());
+                    }
+
+                    if chunk.eof {
+                        return Ok(());
+                    }
+                }
+            })
+            .await
+            .unwrap_or_else(|_| {
+                Err(RenderError::RenderTimeout {
+                    phase: "render",
+                    timeout_ms: deadlines.overall.as_millis(),
+                }
+                .into())
+            });
+
+            match streamed {
+                Ok(()) => {
+                    let _ = lease.page().execute(CloseParams::new(handle)).await;
+                    pool.release_page(lease).await;
+                }
+                // Dropping the lease closes the page along with the unfinished stream
+                Err(e) => {
+                    tracing::warn!("Aborting a streamed PDF response: {:?}", e);
+                    let _ = tx.send(Err(e)).await;
+                }
+            }
+        });
+
+        Ok(futures::stream::poll_fn(move |cx| rx.poll_recv(cx)).boxed())
+    }
+
+    /// Render `load` and capture it as an image, on the same pooled pages and permits as PDFs.
+    pub async fn screenshot(
+        &self,
+        load: &PageLoad,
+        options: &ScreenshotOptions,
+    ) -> Result<Vec<u8>> {
+        let deadlines = RenderDeadlines::from_config();
+        let max_pixels = cnfg::get().screenshot_max_pixels;
+
+        let (lease, image) = with_deadline("render", deadlines.overall, async {
+            let mut lease = self.acquire_page(load.priority).await?;
+
+            // The viewport has to be in place before the page is laid out
+            lease.page().execute(options.device_metrics()).await?;
+
+            self.load_page(&mut lease, load, &deadlines).await?;
+
+            let capture = with_deadline("capture", deadlines.print, async {
+                let content_size = if options.needs_content_size() {
+                    let metrics = lease.page().layout_metrics().await?;
+                    (
+                        metrics.css_content_size.width,
+                        metrics.css_content_size.height,
+                    )
+                } else {
+                    (0.0, 0.0)
+                };
+                Ok(lease
+                    .page()
+                    .execute(options.capture_params(content_size, max_pixels))
+                    .await?
+                    .result)
+            })
+            .await?;
+            let image = general_purpose::STANDARD.decode(&capture.data)?;
+
+            // Pooled pages are shared with PDF renders, which must see the default viewport
+            lease
+                .page()
+                .execute(ClearDeviceMetricsOverrideParams::default())
+                .await?;
 
-        // Set the HTML content
-        page.set_content(html).await?;
+            Ok((lease, image))
+        })
+        .await?;
+
+        self.release_page(lease).await;
+
+        Ok(image)
+    }
+
+    async fn load_page(
+        &self,
+        lease: &mut PageLease,
+        load: &PageLoad,
+        deadlines: &RenderDeadlines,
+    ) -> Result<()> {
+        let fonts = self.fonts.faces();
+        // Redirects and navigations started by the page must stay on allowed hosts, too
+        let config = cnfg::get();
+        let navigation = match load.source {
+            RenderSource::Url(_) => Some(&config.url_policy),
+            RenderSource::Html(_) | RenderSource::Bundle(_) => None,
+        };
+        lease.interception =
+            Interception::start(lease.page(), &load.resources, &fonts.assets, navigation).await?;
+        let page = lease.page();
+
+        // The waiter has to observe the load itself, e.g. to track network activity
+        let waiter = ReadyWaiter::prepare(page, load.wait.as_ref()).await?;
+
+        with_deadline("load", deadlines.load, async {
+            Self::load_source(page, &load.source).await?;
+            if !fonts.is_empty() {
+                fonts.inject(page).await?;
+            }
+            Ok(())
+        })
+        .await?;
+
+        with_deadline("wait", deadlines.wait, waiter.wait(page)).await
+    }
+
+    async fn load_source(page: &Page, source: &RenderSource) -> Result<()> {
+        match source {
+            RenderSource::Html(html) => {
+                page.set_content(html).await?;
+            }
+            RenderSource::Url(url) => {
+                // `goto` waits for the load event of the navigation
+                page.goto(url.as_str()).await?;
+
+                // Interception already stopped disallowed hops, this is only a backstop
+                if let Some(final_url) = page.url().await? {
+                    let final_url = Url::parse(&final_url)?;
+                    cnfg::get().url_policy.check(&final_url)?;
+                }
+            }
+            RenderSource::Bundle(entry_point) => {
+                page.goto(entry_point.as_str()).await?;
+            }
+        }
 
+        Ok(())
+    }
+
+    fn pdf_params(custom_params: Option<PrintToPdfParams>) -> PrintToPdfParams {
         // Create default params with spread-like syntax
         let default_params = PrintToPdfParamsBuilder::default()
             .print_background(true)
             .build();
 
-        let params = custom_params.map_or(default_params, |custom| PrintToPdfParams {
+        custom_params.map_or(default_params, |custom| PrintToPdfParams {
             print_background: custom.print_background.or(Some(true)),
             ..custom
-        });
+        })
+    }
 
-        // Generate PDF
-        let pdf_result = page.pdf(params).await?;
+    async fn acquire_page(&self, priority: Priority) -> Result<PageLease> {
+        // Wait for a render slot; rejected right away when too many renders are queued
+        let permit = self.queue.acquire(priority).await?;
 
-        // Return the page to the pool instead of closing it
-        self.return_page_to_pool(page).await;
+        // Spread renders over the running browsers, least busy first
+        let index = least_loaded(
+            self.shards
+                .iter()
+                .map(|shard| (shard.is_running(), shard.active_pages())),
+        )
+        .ok_or_else(|| {
+            RenderError::BrowserUnavailable(
+                "all browsers are being relaunched, try again shortly".to_string(),
+            )
+        })?;
+        let load = ShardLoad::new(Arc::clone(&self.shards[index]));
 
-        Ok(pdf_result)
+        // Try to get a page from the pool, or create a new one
+        let page = load.shard().get_or_create_page().await?;
+
+        Ok(PageLease {
+            pooled: Some(page),
+            interception: None,
+            load,
+            _permit: permit,
+        })
     }
 
-    async fn get_or_create_page(&self) -> Result<Page> {
-        // Try to get a page from the pool first
+    /// Hand a page that finished its render cleanly back to its shard.
+    async fn release_page(&self, mut lease: PageLease) {
+        // A page that keeps pausing requests would stall the next render; dropping the lease
+        // closes it instead
+        if let Some(interception) = lease.interception.take()
+            && interception.stop(lease.page()).await.is_err()
         {
-            let mut pool = self.page_pool.lock().await;
-            if let Some(page) = pool.pop() {
-                return Ok(page);
-            }
+            return;
         }
 
-        // Create a new page if pool is empty
-        let page = self.browser.new_page("about:blank").await?;
-        Ok(page)
+        if let Some(pooled) = lease.pooled.take() {
+            lease.load.shard().return_page_to_pool(pooled).await;
+        }
     }
 
-    async fn return_page_to_pool(&self, page: Page) {
-        let mut pool = self.page_pool.lock().await;
-        if pool.len() < self.max_pool_size {
-            // Clear any existing content before returning to pool
-            let _ = page.goto("about:blank").await;
-            pool.push(page);
-        } else {
-            // If pool is full, close the page
-            let _ = page.close().await;
-        }
+    /// Maximum number of renders the pool runs at the same time, once fully grown
+    pub fn concurrency_limit(&self) -> usize {
+        self.max_tabs
     }
 
-    /// Get the number of available permits in the semaphore
+    /// Get the number of free render slots
     #[allow(dead_code)]
     pub fn available_permits(&self) -> usize {
-        self.semaphore.available_permits()
+        self.queue.available()
     }
 
     /// Get the current number of pages in the pool
     #[allow(dead_code)]
     pub async fn pool_size(&self) -> usize {
-        let pool = self.page_pool.lock().await;
-        pool.len()
+        let mut size = 0;
+        for shard in &self.shards {
+            size += shard.pool_size().await;
+        }
+        size
     }
 
     /// Cleanup all pages in the pool (useful for shutdown)
     #[allow(dead_code)]
     pub async fn cleanup(&self) {
-        let mut pool = self.page_pool.lock().await;
-        for page in pool.drain(..) {
-            let _ = page.close().await;
+        for shard in &self.shards {
+            shard.cleanup().await;
+        }
+    }
+}
+
+/// Index of the running shard with the fewest active pages, the first one among equals, from
+/// whether each shard is running and how many pages it has checked out.
+fn least_loaded(shards: impl IntoIterator<Item = (bool, usize)>) -> Option<usize> {
+    shards
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (running, _))| *running)
+        .min_by_key(|(_, (_, active))| *active)
+        .map(|(index, _)| index)
+}
+
+/// One more concurrent render while renders waited `grow_wait` on average for a slot, one less
+/// while slots stayed unused, always within `min..=max` and at least one.
+fn adapted_capacity(
+    stats: &WaitStats,
+    capacity: usize,
+    min: usize,
+    max: usize,
+    grow_wait: Duration,
+) -> usize {
+    let target = if stats.waited > 0 && stats.average_wait() >= grow_wait {
+        capacity + 1
+    } else if stats.waited == 0 && stats.peak_in_use < capacity {
+        capacity - 1
+    } else {
+        capacity
+    };
+    target.clamp(min.max(1), max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GROW_WAIT: Duration = Duration::from_millis(100);
+
+    fn stats(renders: usize, waited: usize, total_wait_ms: u64, peak_in_use: usize) -> WaitStats {
+        WaitStats {
+            renders,
+            waited,
+            total_wait: Duration::from_millis(total_wait_ms),
+            peak_in_use,
         }
     }
+
+    #[test]
+    fn capacity_grows_while_renders_wait() {
+        // 4 renders waited 400ms in total, 100ms on average
+        let waits = stats(4, 2, 400, 4);
+        assert_eq!(adapted_capacity(&waits, 4, 1, 8, GROW_WAIT), 5);
+    }
+
+    #[test]
+    fn capacity_shrinks_while_slots_are_unused() {
+        let idle = stats(3, 0, 0, 2);
+        assert_eq!(adapted_capacity(&idle, 4, 1, 8, GROW_WAIT), 3);
+    }
+
+    #[test]
+    fn capacity_holds_otherwise() {
+        // Waits below the threshold
+        let short = stats(10, 2, 500, 4);
+        assert_eq!(adapted_capacity(&short, 4, 1, 8, GROW_WAIT), 4);
+        // Every slot in use, but nobody waited
+        let busy = stats(4, 0, 0, 4);
+        assert_eq!(adapted_capacity(&busy, 4, 1, 8, GROW_WAIT), 4);
+    }
+
+    #[test]
+    fn capacity_stays_within_bounds() {
+        let waits = stats(4, 4, 4000, 8);
+        assert_eq!(adapted_capacity(&waits, 8, 1, 8, GROW_WAIT), 8);
+        let idle = stats(0, 0, 0, 0);
+        assert_eq!(adapted_capacity(&idle, 2, 2, 8, GROW_WAIT), 2);
+        // Never below one slot, whatever the minimum
+        assert_eq!(adapted_capacity(&idle, 1, 0, 8, GROW_WAIT), 1);
+        // A capacity outside the bounds is brought back into them
+        assert_eq!(adapted_capacity(&waits, 12, 1, 8, GROW_WAIT), 8);
+    }
+
+    #[test]
+    fn renders_go_to_the_least_loaded_running_shard() {
+        assert_eq!(least_loaded([(true, 3), (true, 1), (true, 2)]), Some(1));
+        // Ties go to the first shard
+        assert_eq!(least_loaded([(true, 2), (true, 2)]), Some(0));
+        // A relaunching shard is skipped however idle it is
+        assert_eq!(least_loaded([(false, 0), (true, 5)]), Some(1));
+        assert_eq!(least_loaded([(false, 0), (false, 0)]), None);
+        assert_eq!(least_loaded([]), None);
+    }
 }
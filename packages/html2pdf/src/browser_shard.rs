@@ -0,0 +1,673 @@
+use std::{
+    sync::{
+        Arc, RwLock, Weak,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use chromiumoxide::{
+    Page,
+    browser::{Browser, BrowserConfig},
+    cdp::browser_protocol::{
+        browser::BrowserContextId,
+        target::{CreateBrowserContextParams, CreateTargetParams},
+    },
+    error::CdpError,
+};
+use futures::StreamExt;
+use serde::Serialize;
+use tokio::sync::{Mutex, mpsc};
+
+use crate::{
+    cnfg::{self, PageIsolation},
+    error::RenderError,
+};
+
+/// How long Chrome gets to answer a liveness probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay before the second relaunch attempt in a row, doubled for every further one.
+const INITIAL_RELAUNCH_BACKOFF: Duration = Duration::from_secs(1);
+/// How long a tab gets to navigate back to `about:blank` before it goes back to the pool.
+const PAGE_RESET_TIMEOUT: Duration = Duration::from_secs(5);
+/// Name of the `Performance.getMetrics` entry holding the used JS heap in bytes.
+const JS_HEAP_METRIC: &str = "JSHeapUsedSize";
+
+/// Whether a shard currently has a browser to render with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BrowserState {
+    Running,
+    /// The browser was lost and a new one is being launched
+    Relaunching,
+}
+
+/// Browser status as reported by `/healthz`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BrowserHealth {
+    pub shard: usize,
+    pub state: BrowserState,
+    /// Number of the running browser, 1 for the one launched at startup
+    pub generation: u64,
+    pub restarts: u64,
+    /// Pages currently checked out for renders
+    pub active_pages: usize,
+    /// Why the browser was last relaunched, or why relaunching it failed
+    pub last_error: Option<String>,
+}
+
+/// One launched Chrome process.
+struct BrowserInstance {
+    browser: Browser,
+    generation: u64,
+}
+
+/// A page together with the browser generation it was opened in. Pages of an earlier generation
+/// belong to a browser that is gone and are never handed out again.
+pub struct PooledPage {
+    pub page: Page,
+    pub generation: u64,
+    /// Incognito context owning the page, in `context` isolation
+    pub context: Option<BrowserContextId>,
+    /// Renders the page has finished
+    pub uses: u32,
+    pub opened_at: Instant,
+}
+
+/// Limits after which a pooled page is replaced, each disabled by 0.
+#[derive(Debug, Clone, Copy)]
+struct RecycleLimits {
+    max_uses: u32,
+    max_age_secs: u64,
+    max_js_heap_mb: u64,
+}
+
+impl RecycleLimits {
+    fn configured() -> Self {
+        let config = cnfg::get();
+        Self {
+            max_uses: config.page_max_uses,
+            max_age_secs: config.page_max_age_secs,
+            max_js_heap_mb: config.page_max_js_heap_mb,
+        }
+    }
+
+    fn too_old(&self, age: Duration) -> bool {
+        self.max_age_secs > 0 && age >= Duration::from_secs(self.max_age_secs)
+    }
+
+    /// The limit a page with `uses` renders, of `age` and with a JS heap of `heap` bytes has
+    /// reached, if any. `heap` is `None` when it was not measured.
+    fn reached(&self, uses: u32, age: Duration, heap: Option<f64>) -> Option<&'static str> {
+        if self.max_uses > 0 && uses >= self.max_uses {
+            return Some("use");
+        }
+        if self.too_old(age) {
+            return Some("age");
+        }
+        if self.max_js_heap_mb > 0
+            && heap.is_some_and(|heap| heap > (self.max_js_heap_mb * 1024 * 1024) as f64)
+        {
+            return Some("JS heap");
+        }
+        None
+    }
+}
+
+/// One Chrome process of the pool with its idle pages. A shard is supervised and replaced on
+/// its own, without disturbing renders on the other shards.
+///
+/// The shard keeps at least `min_pages` tabs open, so renders after startup or after a quiet
+/// period do not pay for creating them. Idle tabs beyond that are closed once the shard has not
+/// been used for a while.
+///
+/// In `context` isolation the idle pages are unused pages of pre-warmed incognito contexts;
+/// a context is disposed after its render instead of going back to the pool.
+pub struct BrowserShard {
+    index: usize,
+    instance: RwLock<Arc<BrowserInstance>>,
+    health: RwLock<BrowserHealth>,
+    /// Generations whose CDP handler loop has ended, consumed by the supervisor
+    disconnected: mpsc::UnboundedSender<u64>,
+    page_pool: Mutex<Vec<PooledPage>>,
+    min_pages: usize,
+    max_idle_pages: usize,
+    active: AtomicUsize,
+    /// When a page was last checked out
+    last_used: RwLock<Instant>,
+    isolation: PageIsolation,
+    /// Held while contexts are pre-warmed, so only one task tops up the pool
+    prewarming: Mutex<()>,
+}
+
+/// Counts a checked out page against its shard for as long as it lives.
+pub struct ShardLoad(Arc<BrowserShard>);
+
+impl ShardLoad {
+    pub fn new(shard: Arc<BrowserShard>) -> Self {
+        shard.active.fetch_add(1, Ordering::SeqCst);
+        Self(shard)
+    }
+
+    pub fn shard(&self) -> &Arc<BrowserShard> {
+        &self.0
+    }
+}
+
+impl Drop for ShardLoad {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl BrowserShard {
+    /// Launch the shard's browser and a supervisor task that relaunches it whenever it is lost.
+    /// Returns once the initial pages are open.
+    pub async fn launch(
+        index: usize,
+        min_pages: usize,
+        max_idle_pages: usize,
+    ) -> Result<Arc<Self>> {
+        let (disconnected_tx, disconnected_rx) = mpsc::unbounded_channel();
+        let instance = Self::launch_browser(index, 1, disconnected_tx.clone()).await?;
+
+        let shard = Arc::new(BrowserShard {
+            index,
+            instance: RwLock::new(Arc::new(instance)),
+            health: RwLock::new(BrowserHealth {
+                shard: index,
+                state: BrowserState::Running,
+                generation: 1,
+                restarts: 0,
+                active_pages: 0,
+                last_error: None,
+            }),
+            disconnected: disconnected_tx,
+            page_pool: Mutex::new(Vec::new()),
+            min_pages,
+            max_idle_pages,
+            active: AtomicUsize::new(0),
+            last_used: RwLock::new(Instant::now()),
+            isolation: cnfg::get().browser_isolation,
+            prewarming: Mutex::new(()),
+        });
+
+        tokio::spawn(Self::supervise(Arc::downgrade(&shard), disconnected_rx));
+        shard.prewarm().await;
+
+        Ok(shard)
+    }
+
+    async fn launch_browser(
+        index: usize,
+        generation: u64,
+        disconnected: mpsc::UnboundedSender<u64>,
+    ) -> Result<BrowserInstance> {
+        let config = BrowserConfig::builder()
+            .build()
+            .map_err(|e| anyhow::anyhow!("Failed to create browser config: {}", e))?;
+
+        let (browser, mut handler) = Browser::launch(config).await?;
+
+        // Spawn handler properly - this is crucial for chromiumoxide to work
+        tokio::task::spawn(async move {
+            while let Some(event) = handler.next().await {
+                // Messages chromiumoxide can not deserialize are common and harmless, but a
+                // websocket error means the connection to Chrome is gone for good
+                if let Err(CdpError::Ws(e)) = event {
+                    tracing::warn!("Browser {}.{} websocket failed: {}", index, generation, e);
+                    break;
+                }
+            }
+            let _ = disconnected.send(generation);
+        });
+
+        Ok(BrowserInstance {
+            browser,
+            generation,
+        })
+    }
+
+    /// Relaunch the browser once its handler loop ends or it stops answering probes.
+    ///
+    /// Relaunches back off exponentially while the new browsers keep failing; a successful probe
+    /// resets the backoff. The task ends with the shard.
+    async fn supervise(shard: Weak<Self>, mut disconnected: mpsc::UnboundedReceiver<u64>) {
+        let config = cnfg::get();
+        let interval = Duration::from_secs(config.browser_health_check_secs);
+        let max_backoff = Duration::from_secs(config.browser_relaunch_max_backoff_secs);
+        let mut backoff = Duration::ZERO;
+
+        loop {
+            let lost = tokio::select! {
+                generation = disconnected.recv() => match generation {
+                    Some(generation) => Some((generation, "connection to Chrome closed".to_string())),
+                    None => return,
+                },
+                _ = tokio::time::sleep(interval) => {
+                    let Some(shard) = shard.upgrade() else {
+                        return;
+                    };
+                    let instance = shard.current();
+                    match Self::probe(&instance).await {
+                        Ok(()) => {
+                            backoff = Duration::ZERO;
+                            None
+                        }
+                        Err(e) => Some((instance.generation, format!("health check failed: {}", e))),
+                    }
+                }
+            };
+
+            let Some((generation, reason)) = lost else {
+                continue;
+            };
+            let Some(shard) = shard.upgrade() else {
+                return;
+            };
+            // A handler loop of an already replaced browser ending is expected
+            if generation != shard.current().generation {
+                continue;
+            }
+
+            shard.relaunch(reason, &mut backoff, max_backoff).await;
+            Self::spawn_prewarm(&shard);
+        }
+    }
+
+    async fn probe(instance: &BrowserInstance) -> Result<()> {
+        tokio::time::timeout(PROBE_TIMEOUT, instance.browser.version())
+            .await
+            .map_err(|_| anyhow::anyhow!("no answer within {:?}", PROBE_TIMEOUT))??;
+        Ok(())
+    }
+
+    /// Replace the current browser, retrying until a new one is up.
+    async fn relaunch(&self, reason: String, backoff: &mut Duration, max_backoff: Duration) {
+        tracing::error!(
+            "Browser {} is unavailable ({}), relaunching",
+            self.index,
+            reason
+        );
+        {
+            let mut health = self.health.write().unwrap();
+            health.state = BrowserState::Relaunching;
+            health.last_error = Some(reason);
+        }
+        // Pages of the lost browser are useless, drop them right away
+        self.page_pool.lock().await.clear();
+
+        let generation = self.current().generation + 1;
+        let instance = loop {
+            tokio::time::sleep(*backoff).await;
+            *backoff = (*backoff * 2).clamp(INITIAL_RELAUNCH_BACKOFF, max_backoff);
+
+            match Self::launch_browser(self.index, generation, self.disconnected.clone()).await {
+                Ok(instance) => break instance,
+                Err(e) => {
+                    tracing::error!("Failed to relaunch browser {}: {}", self.index, e);
+                    self.health.write().unwrap().last_error =
+                        Some(format!("relaunch failed: {}", e));
+                }
+            }
+        };
+
+        let previous = std::mem::replace(&mut *self.instance.write().unwrap(), Arc::new(instance));
+        {
+            let mut health = self.health.write().unwrap();
+            health.state = BrowserState::Running;
+            health.generation = generation;
+            health.restarts += 1;
+        }
+        tracing::info!(
+            "Browser {} relaunched as generation {}",
+            self.index,
+            generation
+        );
+
+        // Make sure a hung Chrome does not linger; if a render still holds on to it, dropping the
+        // last reference kills it instead
+        if let Some(mut previous) = Arc::into_inner(previous) {
+            let _ = previous.browser.kill().await;
+        }
+    }
+
+    fn current(&self) -> Arc<BrowserInstance> {
+        Arc::clone(&self.instance.read().unwrap())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.health.read().unwrap().state == BrowserState::Running
+    }
+
+    /// Pages currently checked out from this shard.
+    pub fn active_pages(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub fn health(&self) -> BrowserHealth {
+        BrowserHealth {
+            active_pages: self.active_pages(),
+            ..self.health.read().unwrap().clone()
+        }
+    }
+
+    pub async fn get_or_create_page(self: &Arc<Self>) -> Result<PooledPage> {
+        // Fail fast instead of waiting on a browser that is known to be gone
+        if !self.is_running() {
+            return Err(RenderError::BrowserUnavailable(
+                "the browser is being relaunched, try again shortly".to_string(),
+            )
+            .into());
+        }
+        let instance = self.current();
+        *self.last_used.write().unwrap() = Instant::now();
+
+        // Try to get a page from the pool first, skipping pages that aged out while idle
+        let pooled = loop {
+            let Some(page) = self.page_pool.lock().await.pop() else {
+                break None;
+            };
+            if page.generation != instance.generation {
+                continue;
+            }
+            if RecycleLimits::configured().too_old(page.opened_at.elapsed()) {
+                Self::close_page(&instance, page).await;
+                continue;
+            }
+            break Some(page);
+        };
+        if self.isolation == PageIsolation::Context {
+            // Replace the context this render takes, or the one it has to wait for
+            Self::spawn_prewarm(self);
+        }
+        if let Some(page) = pooled {
+            return Ok(page);
+        }
+
+        // Create a new page if pool is empty
+        Self::new_page(&instance, self.isolation).await
+    }
+
+    async fn new_page(instance: &BrowserInstance, isolation: PageIsolation) -> Result<PooledPage> {
+        let context = match isolation {
+            PageIsolation::Shared => None,
+            PageIsolation::Context => Some(
+                instance
+                    .browser
+                    .create_browser_context(CreateBrowserContextParams::default())
+                    .await?,
+            ),
+        };
+
+        let params = CreateTargetParams {
+            browser_context_id: context.clone(),
+            ..CreateTargetParams::new("about:blank")
+        };
+        let page = match instance.browser.new_page(params).await {
+            Ok(page) => page,
+            Err(e) => {
+                if let Some(context) = context {
+                    let _ = instance.browser.dispose_browser_context(context).await;
+                }
+                return Err(e.into());
+            }
+        };
+
+        Ok(PooledPage {
+            page,
+            generation: instance.generation,
+            context,
+            uses: 0,
+            opened_at: Instant::now(),
+        })
+    }
+
+    pub fn spawn_prewarm(shard: &Arc<Self>) {
+        let shard = Arc::clone(shard);
+        tokio::spawn(async move { shard.prewarm().await });
+    }
+
+    fn idle_target(&self) -> usize {
+        idle_target(
+            self.isolation,
+            cnfg::get().browser_prewarmed_contexts,
+            self.min_pages,
+            self.active_pages(),
+            self.max_idle_pages,
+        )
+    }
+
+    /// Top up the pool with fresh pages, so renders do not wait for one to be created.
+    async fn prewarm(&self) {
+        let Ok(_prewarming) = self.prewarming.try_lock() else {
+            return;
+        };
+
+        while self.is_running() && self.page_pool.lock().await.len() < self.idle_target() {
+            let instance = self.current();
+            match Self::new_page(&instance, self.isolation).await {
+                Ok(page) => self.page_pool.lock().await.push(page),
+                Err(e) => {
+                    tracing::warn!("Failed to pre-warm a page on browser {}: {}", self.index, e);
+                    return;
+                }
+            }
+        }
+    }
+
+    pub async fn return_page_to_pool(&self, mut page: PooledPage) {
+        // The browser this page belongs to has been replaced, there is nothing left to close
+        let instance = self.current();
+        if page.generation != instance.generation {
+            return;
+        }
+
+        // A used context is never reused
+        page.uses += 1;
+        if page.context.is_some() || self.should_recycle(&page).await {
+            Self::close_page(&instance, page).await;
+            return;
+        }
+
+        // No point in resetting a tab that has no room in the pool
+        if self.page_pool.lock().await.len() >= self.max_idle_pages {
+            Self::close_page(&instance, page).await;
+            return;
+        }
+
+        // Clear any existing content before returning to pool; a tab that can not even do that
+        // is stuck and gets closed. The pool stays unlocked meanwhile, so a slow reset does not
+        // hold up other renders of this shard.
+        let reset = tokio::time::timeout(PAGE_RESET_TIMEOUT, page.page.goto("about:blank")).await;
+        if !matches!(reset, Ok(Ok(_))) {
+            Self::close_page(&instance, page).await;
+            return;
+        }
+
+        let mut pool = self.page_pool.lock().await;
+        if pool.len() < self.max_idle_pages {
+            pool.push(page);
+        } else {
+            // Filled up while the tab was reset
+            drop(pool);
+            Self::close_page(&instance, page).await;
+        }
+    }
+
+    /// Close a page whose render did not finish cleanly, rather than reusing it.
+    pub async fn discard_page(&self, page: PooledPage) {
+        let instance = self.current();
+        if page.generation == instance.generation {
+            Self::close_page(&instance, page).await;
+        }
+    }
+
+    /// Whether a page that finished a render has served long enough. Long-lived tabs keep
+    /// accumulating memory, so they are replaced after a number of renders, after some time, or
+    /// once their JS heap grows too large.
+    async fn should_recycle(&self, page: &PooledPage) -> bool {
+        let limits = RecycleLimits::configured();
+
+        let heap = if limits.max_js_heap_mb > 0 {
+            match page.page.metrics().await {
+                Ok(metrics) => metrics
+                    .iter()
+                    .find(|metric| metric.name == JS_HEAP_METRIC)
+                    .map(|metric| metric.value),
+                // A page that can not even report its metrics is not worth keeping
+                Err(_) => return true,
+            }
+        } else {
+            None
+        };
+
+        match limits.reached(page.uses, page.opened_at.elapsed(), heap) {
+            Some(limit) => {
+                tracing::debug!(
+                    "Recycling page on browser {}, {} limit reached",
+                    self.index,
+                    limit
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Close a page of `instance`; pages of an incognito context go with their context.
+    async fn close_page(instance: &BrowserInstance, page: PooledPage) {
+        match page.context {
+            Some(context) => {
+                let _ = instance.browser.dispose_browser_context(context).await;
+            }
+            None => {
+                let _ = page.page.close().await;
+            }
+        }
+    }
+
+    /// Close idle pages beyond the configured minimum once the shard has not been used for
+    /// `idle_after`, oldest first.
+    pub async fn shrink_idle(&self, idle_after: Duration) {
+        if self.last_used.read().unwrap().elapsed() < idle_after {
+            return;
+        }
+
+        let instance = self.current();
+        let surplus = {
+            let mut pool = self.page_pool.lock().await;
+            let excess = pool.len().saturating_sub(self.idle_target());
+            pool.drain(..excess).collect::<Vec<_>>()
+        };
+        if surplus.is_empty() {
+            return;
+        }
+
+        tracing::debug!(
+            "Closing {} idle pages on browser {}",
+            surplus.len(),
+            self.index
+        );
+        for page in surplus {
+            if page.generation == instance.generation {
+                Self::close_page(&instance, page).await;
+            }
+        }
+    }
+
+    /// Number of idle pages kept for reuse.
+    pub async fn pool_size(&self) -> usize {
+        self.page_pool.lock().await.len()
+    }
+
+    /// Close all idle pages.
+    pub async fn cleanup(&self) {
+        let instance = self.current();
+        let mut pool = self.page_pool.lock().await;
+        for page in pool.drain(..) {
+            Self::close_page(&instance, page).await;
+        }
+    }
+}
+
+/// Idle pages a shard keeps open: fresh contexts in `context` isolation, otherwise enough tabs to
+/// reach `min_pages` together with the `active` ones, never more than `max_idle`.
+fn idle_target(
+    isolation: PageIsolation,
+    prewarmed_contexts: usize,
+    min_pages: usize,
+    active: usize,
+    max_idle: usize,
+) -> usize {
+    let target = match isolation {
+        PageIsolation::Context => prewarmed_contexts,
+        PageIsolation::Shared => min_pages.saturating_sub(active),
+    };
+    target.min(max_idle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: f64 = 1024.0 * 1024.0;
+
+    fn limits(max_uses: u32, max_age_secs: u64, max_js_heap_mb: u64) -> RecycleLimits {
+        RecycleLimits {
+            max_uses,
+            max_age_secs,
+            max_js_heap_mb,
+        }
+    }
+
+    #[test]
+    fn pages_are_recycled_after_their_uses() {
+        let limits = limits(10, 0, 0);
+        assert_eq!(limits.reached(9, Duration::ZERO, None), None);
+        assert_eq!(limits.reached(10, Duration::ZERO, None), Some("use"));
+    }
+
+    #[test]
+    fn pages_are_recycled_by_age() {
+        let limits = limits(0, 60, 0);
+        assert!(!limits.too_old(Duration::from_secs(59)));
+        assert!(limits.too_old(Duration::from_secs(60)));
+        assert_eq!(
+            limits.reached(1, Duration::from_secs(60), None),
+            Some("age")
+        );
+    }
+
+    #[test]
+    fn pages_are_recycled_by_heap() {
+        let limits = limits(0, 0, 512);
+        assert_eq!(limits.reached(1, Duration::ZERO, Some(512.0 * MB)), None);
+        assert_eq!(
+            limits.reached(1, Duration::ZERO, Some(513.0 * MB)),
+            Some("JS heap")
+        );
+        // A heap that was not measured never counts against the page
+        assert_eq!(limits.reached(1, Duration::ZERO, None), None);
+    }
+
+    #[test]
+    fn zero_disables_a_limit() {
+        let limits = limits(0, 0, 0);
+        let age = Duration::from_secs(365 * 24 * 3600);
+        assert!(!limits.too_old(age));
+        assert_eq!(limits.reached(u32::MAX, age, Some(f64::MAX)), None);
+    }
+
+    #[test]
+    fn idle_pages_top_up_to_the_minimum() {
+        assert_eq!(idle_target(PageIsolation::Shared, 2, 4, 1, 10), 3);
+        assert_eq!(idle_target(PageIsolation::Shared, 2, 4, 6, 10), 0);
+        assert_eq!(idle_target(PageIsolation::Shared, 2, 4, 0, 3), 3);
+        // Contexts are used once, so a fixed number is kept ready however many are active
+        assert_eq!(idle_target(PageIsolation::Context, 2, 4, 6, 10), 2);
+        assert_eq!(idle_target(PageIsolation::Context, 5, 4, 0, 3), 3);
+    }
+}
@@ -0,0 +1,489 @@
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Read},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Extension, FromRequest, Multipart, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    response::Response,
+};
+use base64::{Engine as _, engine::general_purpose};
+use url::Url;
+use zip::ZipArchive;
+
+use crate::{
+    AppState,
+    browser_pool::RenderSource,
+    cnfg,
+    error::{HttpError, RenderError},
+    html2pdf::{PdfOptions, pdf_reply},
+    render_queue::Priority,
+    resources::Asset,
+};
+
+/// Origin the files of a bundle are served from. `.invalid` never resolves, so nothing on it
+/// can reach the network.
+const BUNDLE_ORIGIN: &str = "https://bundle.invalid/";
+/// Document the page is rendered from.
+const ENTRY_POINT: &str = "index.html";
+/// Render options inside a ZIP bundle, or the name of the form field holding them.
+const OPTIONS_ENTRY: &str = "options.json";
+const OPTIONS_FIELD: &str = "options";
+
+/// An HTML document with the files it references, by their path relative to the document.
+struct Bundle {
+    files: BTreeMap<String, Vec<u8>>,
+    options: PdfOptions,
+}
+
+/// Size limits of an unpacked bundle, from `AppConfig`.
+struct BundleLimits {
+    max_files: usize,
+    max_unpacked_bytes: u64,
+}
+
+/// Render an uploaded bundle, either a ZIP archive (`application/zip`) or a form
+/// (`multipart/form-data`), containing `index.html` and the assets it references by relative
+/// path.
+///
+/// Render options go into `options.json` inside the archive or into the `options` form field.
+/// Assets are served to the page from a virtual origin, so no data URIs are needed.
+pub async fn html2pdf_bundle(
+    State(app_state): State<AppState>,
+    Extension(priority): Extension<Priority>,
+    headers: HeaderMap,
+    request: Request,
+) -> Result<Response, HttpError> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    let limits = BundleLimits::from_config();
+
+    let bundle = if mime.eq_ignore_ascii_case("application/zip") {
+        let body = Bytes::from_request(request, &app_state)
+            .await
+            .map_err(|e| rejected(e.status(), e.body_text()))?;
+        tracing::debug!("Received bundle of {} bytes", body.len());
+        tokio::task::spawn_blocking(move || Bundle::from_zip(&body, &limits)).await??
+    } else if mime.eq_ignore_ascii_case("multipart/form-data") {
+        let form = Multipart::from_request(request, &app_state)
+            .await
+            .map_err(|e| rejected(e.status(), e.body_text()))?;
+        Bundle::from_form(form, &limits).await?
+    } else {
+        return Err(RenderError::InvalidBundle(
+            "expected an application/zip or multipart/form-data body".to_string(),
+        )
+        .into());
+    };
+
+    let (source, options) = bundle.into_render()?;
+    pdf_reply(&app_state, &headers, source, options, priority).await
+}
+
+impl BundleLimits {
+    fn from_config() -> Self {
+        let config = cnfg::get();
+        Self {
+            max_files: config.bundle_max_files,
+            max_unpacked_bytes: config.bundle_max_unpacked_bytes,
+        }
+    }
+}
+
+impl Bundle {
+    fn from_zip(archive: &[u8], limits: &BundleLimits) -> Result<Self, RenderError> {
+        let invalid = |message: String| RenderError::InvalidBundle(message);
+
+        let mut archive = ZipArchive::new(Cursor::new(archive))
+            .map_err(|e| invalid(format!("not a readable ZIP archive: {}", e)))?;
+        if archive.len() > limits.max_files {
+            return Err(RenderError::BundleTooLarge(format!(
+                "{} entries, at most {} are allowed",
+                archive.len(),
+                limits.max_files
+            )));
+        }
+
+        let mut files = BTreeMap::new();
+        let mut unpacked = 0;
+        for index in 0..archive.len() {
+            let entry = archive
+                .by_index(index)
+                .map_err(|e| invalid(format!("entry {} can not be read: {}", index, e)))?;
+            if entry.is_dir() {
+                continue;
+            }
+            let path = bundle_path(entry.name())?;
+
+            // The declared size can not be trusted, so reading stops right after the limit
+            let remaining = limits.max_unpacked_bytes - unpacked;
+            let mut data = Vec::new();
+            entry
+                .take(remaining + 1)
+                .read_to_end(&mut data)
+                .map_err(|e| invalid(format!("{} can not be unpacked: {}", path, e)))?;
+            unpacked += data.len() as u64;
+            if unpacked > limits.max_unpacked_bytes {
+                return Err(RenderError::BundleTooLarge(format!(
+                    "unpacks to more than {} bytes",
+                    limits.max_unpacked_bytes
+                )));
+            }
+
+            files.insert(path, data);
+        }
+
+        strip_common_directory(&mut files);
+        let options = match files.remove(OPTIONS_ENTRY) {
+            Some(options) => serde_json::from_slice(&options)
+                .map_err(|e| invalid(format!("{} is invalid: {}", OPTIONS_ENTRY, e)))?,
+            None => PdfOptions::default(),
+        };
+
+        Ok(Self { files, options })
+    }
+
+    /// Read the files of a form as they stream in, stopping as soon as a limit is exceeded.
+    async fn from_form(mut form: Multipart, limits: &BundleLimits) -> Result<Self, RenderError> {
+        let invalid = |message: String| RenderError::InvalidBundle(message);
+        let too_large = || {
+            RenderError::BundleTooLarge(format!(
+                "at most {} files and {} bytes are allowed",
+                limits.max_files, limits.max_unpacked_bytes
+            ))
+        };
+
+        let mut files = BTreeMap::new();
+        let mut options = PdfOptions::default();
+        let mut unpacked = 0;
+        while let Some(mut field) = form
+            .next_field()
+            .await
+            .map_err(|e| rejected(e.status(), e.body_text()))?
+        {
+            let name = field.name().map(str::to_string);
+            match (field.file_name().map(str::to_string), name) {
+                (None, Some(name)) if name == OPTIONS_FIELD => {
+                    let data = field
+                        .bytes()
+                        .await
+                        .map_err(|e| rejected(e.status(), e.body_text()))?;
+                    options = serde_json::from_slice(&data).map_err(|e| {
+                        invalid(format!("{} field is invalid: {}", OPTIONS_FIELD, e))
+                    })?;
+                }
+                // Other plain fields carry nothing to render
+                (None, _) => {}
+                (Some(filename), _) => {
+                    if files.len() >= limits.max_files {
+                        return Err(too_large());
+                    }
+                    let path = bundle_path(&filename)?;
+
+                    let mut data = Vec::new();
+                    while let Some(chunk) = field
+                        .chunk()
+                        .await
+                        .map_err(|e| rejected(e.status(), e.body_text()))?
+                    {
+                        unpacked += chunk.len() as u64;
+                        if unpacked > limits.max_unpacked_bytes {
+                            return Err(too_large());
+                        }
+                        data.extend_from_slice(&chunk);
+                    }
+                    files.insert(path, data);
+                }
+            }
+        }
+
+        Ok(Self { files, options })
+    }
+
+    /// Turn the bundle into a render of its entry point, with every file attached as an asset
+    /// of the virtual origin.
+    fn into_render(self) -> Result<(RenderSource, PdfOptions), RenderError> {
+        if !self.files.contains_key(ENTRY_POINT) {
+            return Err(RenderError::InvalidBundle(format!(
+                "{} is missing",
+                ENTRY_POINT
+            )));
+        }
+
+        let origin = Url::parse(BUNDLE_ORIGIN).expect("valid bundle origin");
+        let entry_point = origin.join(ENTRY_POINT).expect("valid entry point");
+
+        let mut options = self.options;
+        for (path, data) in self.files {
+            let url = origin
+                .join(&path)
+                .map_err(|e| RenderError::InvalidBundle(format!("{}: {}", path, e)))?;
+            options.resources.assets.push(Asset {
+                url: url.to_string(),
+                content_type: None,
+                data: general_purpose::STANDARD.encode(data),
+            });
+        }
+
+        Ok((RenderSource::Bundle(entry_point), options))
+    }
+}
+
+/// Normalize the path of a bundled file, rejecting anything that points outside the bundle.
+fn bundle_path(name: &str) -> Result<String, RenderError> {
+    let invalid = || RenderError::InvalidBundle(format!("invalid file path: {}", name));
+
+    if name.starts_with('/') || name.contains(['\\', '\0']) {
+        return Err(invalid());
+    }
+
+    let mut segments = Vec::new();
+    for segment in name.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return Err(invalid()),
+            // Drive letters and other things that are no plain names
+            segment if segment.contains(':') => return Err(invalid()),
+            segment => segments.push(segment),
+        }
+    }
+
+    if segments.is_empty() {
+        return Err(invalid());
+    }
+    Ok(segments.join("/"))
+}
+
+/// Archives of a folder put everything below that folder; move its content up when the entry
+/// point is only found there.
+fn strip_common_directory(files: &mut BTreeMap<String, Vec<u8>>) {
+    if files.contains_key(ENTRY_POINT) {
+        return;
+    }
+
+    let Some(prefix) = files
+        .keys()
+        .find_map(|path| path.strip_suffix(ENTRY_POINT))
+        .filter(|prefix| prefix.ends_with('/') && prefix.matches('/').count() == 1)
+        .map(str::to_string)
+    else {
+        return;
+    };
+    if !files.keys().all(|path| path.starts_with(&prefix)) {
+        return;
+    }
+
+    *files = std::mem::take(files)
+        .into_iter()
+        .map(|(path, data)| (path[prefix.len()..].to_string(), data))
+        .collect();
+}
+
+/// Error for a body that could not be read, keeping an exceeded body limit apart.
+fn rejected(status: StatusCode, message: String) -> RenderError {
+    if status == StatusCode::PAYLOAD_TOO_LARGE {
+        RenderError::BundleTooLarge(message)
+    } else {
+        RenderError::InvalidBundle(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use axum::body::Body;
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    use super::*;
+
+    const BOUNDARY: &str = "----bundle-boundary";
+
+    fn limits(max_files: usize, max_unpacked_bytes: u64) -> BundleLimits {
+        BundleLimits {
+            max_files,
+            max_unpacked_bytes,
+        }
+    }
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn files(paths: &[&str]) -> BTreeMap<String, Vec<u8>> {
+        paths
+            .iter()
+            .map(|path| (path.to_string(), Vec::new()))
+            .collect()
+    }
+
+    fn form(parts: &[(&str, &str)]) -> Vec<u8> {
+        let mut body = String::new();
+        for (disposition, data) in parts {
+            body.push_str(&format!(
+                "--{}\r\nContent-Disposition: {}\r\n\r\n{}\r\n",
+                BOUNDARY, disposition, data
+            ));
+        }
+        body.push_str(&format!("--{}--\r\n", BOUNDARY));
+        body.into_bytes()
+    }
+
+    async fn from_form(body: Vec<u8>, limits: &BundleLimits) -> Result<Bundle, RenderError> {
+        let request = Request::builder()
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(Body::from(body))
+            .unwrap();
+        let form = Multipart::from_request(request, &()).await.unwrap();
+        Bundle::from_form(form, limits).await
+    }
+
+    #[test]
+    fn bundle_path_normalizes_relative_paths() {
+        assert_eq!(bundle_path("index.html").unwrap(), "index.html");
+        assert_eq!(bundle_path("./a//b").unwrap(), "a/b");
+        assert_eq!(bundle_path("css/./site.css/").unwrap(), "css/site.css");
+    }
+
+    #[test]
+    fn bundle_path_rejects_paths_leaving_the_bundle() {
+        for path in [
+            "..",
+            "../secret",
+            "a/../../b",
+            "/abs",
+            "C:x",
+            "c:/windows/win.ini",
+            "a\\b",
+            "a\0b",
+            "",
+            "./",
+        ] {
+            assert!(bundle_path(path).is_err(), "{:?} was accepted", path);
+        }
+    }
+
+    #[test]
+    fn single_folder_is_unwrapped() {
+        let mut folder = files(&["site/index.html", "site/css/site.css"]);
+        strip_common_directory(&mut folder);
+        assert_eq!(folder, files(&["index.html", "css/site.css"]));
+
+        // Files outside the folder keep the layout as it is
+        let mut mixed = files(&["site/index.html", "other.txt"]);
+        strip_common_directory(&mut mixed);
+        assert_eq!(mixed, files(&["site/index.html", "other.txt"]));
+
+        let mut nested = files(&["a/b/index.html", "a/b/site.css"]);
+        strip_common_directory(&mut nested);
+        assert_eq!(nested, files(&["a/b/index.html", "a/b/site.css"]));
+
+        let mut flat = files(&["index.html", "site/index.html"]);
+        strip_common_directory(&mut flat);
+        assert_eq!(flat, files(&["index.html", "site/index.html"]));
+    }
+
+    #[test]
+    fn zip_reads_files_and_options() {
+        let archive = zip(&[
+            ("site/index.html", b"<h1>Hi</h1>"),
+            ("site/options.json", br#"{"filename": "hi.pdf"}"#),
+        ]);
+
+        let bundle = Bundle::from_zip(&archive, &limits(10, 1024)).unwrap();
+
+        assert_eq!(bundle.files["index.html"], b"<h1>Hi</h1>");
+        assert!(!bundle.files.contains_key(OPTIONS_ENTRY));
+        assert_eq!(bundle.options.filename.as_deref(), Some("hi.pdf"));
+    }
+
+    #[test]
+    fn zip_stops_unpacking_at_the_limit() {
+        let archive = zip(&[("index.html", &[b'a'; 60]), ("big.bin", &[0; 60])]);
+
+        assert!(Bundle::from_zip(&archive, &limits(10, 120)).is_ok());
+        assert!(matches!(
+            Bundle::from_zip(&archive, &limits(10, 119)),
+            Err(RenderError::BundleTooLarge(_))
+        ));
+        assert!(matches!(
+            Bundle::from_zip(&archive, &limits(1, 1024)),
+            Err(RenderError::BundleTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn zip_rejects_traversal_and_garbage() {
+        let archive = zip(&[("index.html", b""), ("../escape.txt", b"")]);
+        assert!(matches!(
+            Bundle::from_zip(&archive, &limits(10, 1024)),
+            Err(RenderError::InvalidBundle(_))
+        ));
+        assert!(Bundle::from_zip(b"not a zip", &limits(10, 1024)).is_err());
+    }
+
+    #[tokio::test]
+    async fn form_files_and_options_are_read() {
+        let body = form(&[
+            (r#"form-data; name="options""#, r#"{"filename": "a.pdf"}"#),
+            (
+                r#"form-data; name="files"; filename="index.html""#,
+                "<h1>Hi</h1>",
+            ),
+            (
+                r#"form-data; name="files"; filename="css/site.css""#,
+                "h1{}",
+            ),
+        ]);
+
+        let bundle = from_form(body, &limits(10, 1024)).await.unwrap();
+
+        assert_eq!(bundle.options.filename.as_deref(), Some("a.pdf"));
+        assert_eq!(bundle.files["index.html"], b"<h1>Hi</h1>");
+        assert_eq!(bundle.files["css/site.css"], b"h1{}");
+    }
+
+    #[tokio::test]
+    async fn form_enforces_paths_and_limits() {
+        let traversal = form(&[(r#"form-data; name="f"; filename="../../etc/passwd""#, "x")]);
+        assert!(matches!(
+            from_form(traversal, &limits(10, 1024)).await,
+            Err(RenderError::InvalidBundle(_))
+        ));
+
+        let two_files = form(&[
+            (r#"form-data; name="f"; filename="index.html""#, "x"),
+            (r#"form-data; name="f"; filename="a.css""#, "y"),
+        ]);
+        assert!(matches!(
+            from_form(two_files.clone(), &limits(1, 1024)).await,
+            Err(RenderError::BundleTooLarge(_))
+        ));
+        assert!(matches!(
+            from_form(two_files.clone(), &limits(10, 1)).await,
+            Err(RenderError::BundleTooLarge(_))
+        ));
+        assert!(from_form(two_files, &limits(2, 2)).await.is_ok());
+
+        let unterminated = format!("--{}\r\nContent-Disposition: form-data", BOUNDARY);
+        assert!(matches!(
+            from_form(unterminated.into_bytes(), &limits(10, 1024)).await,
+            Err(RenderError::InvalidBundle(_))
+        ));
+    }
+}
@@ -1,10 +1,12 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
 use dotenv::dotenv;
 use once_cell::sync::Lazy;
 use serde::Deserialize;
 use url::Url;
 
+use crate::url_policy::UrlPolicy;
+
 #[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum AppEnv {
@@ -13,6 +15,29 @@ pub enum AppEnv {
     Production,
 }
 
+/// How renders are separated from each other inside a browser.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PageIsolation {
+    /// Tabs are reused and share cookies, storage and cache
+    #[default]
+    Shared,
+    /// Every render gets a fresh incognito browser context that is disposed afterwards
+    Context,
+}
+
+impl std::str::FromStr for PageIsolation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "shared" => Ok(PageIsolation::Shared),
+            "context" => Ok(PageIsolation::Context),
+            _ => Err(format!("unknown isolation mode: {}", value)),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
 pub struct AppConfig {
     pub env: AppEnv,
@@ -20,6 +45,81 @@ pub struct AppConfig {
     pub port: u16,
     pub ship_key: String,
     pub jwks_issuers: Vec<(String, String)>,
+
+    pub url_policy: UrlPolicy,
+    pub templates_dir: PathBuf,
+    /// Organization fonts available to every render
+    pub fonts_dir: PathBuf,
+    /// Largest font file accepted by `POST /fonts/{family}`
+    pub font_max_bytes: usize,
+    /// Token roles allowed to manage shared resources such as fonts
+    pub admin_roles: Vec<String>,
+
+    pub job_workers: usize,
+    pub job_retention_secs: u64,
+    /// Jobs that may wait for a worker before new ones are rejected with 429
+    pub job_max_pending: usize,
+    /// Total size of finished PDFs kept for download; the oldest are dropped beyond it
+    pub job_max_retained_bytes: usize,
+    pub webhook_secret: Option<String>,
+    pub callback_policy: UrlPolicy,
+
+    pub batch_max_items: usize,
+    pub batch_body_limit_bytes: usize,
+
+    /// Largest bundle upload accepted by `/html2pdf/bundle`
+    pub bundle_max_bytes: usize,
+    /// Most files a bundle may contain
+    pub bundle_max_files: usize,
+    /// Largest total size of a bundle's files once unpacked
+    pub bundle_max_unpacked_bytes: u64,
+
+    /// Chrome processes the pages are spread over
+    pub browser_count: usize,
+    pub browser_isolation: PageIsolation,
+    /// Fresh contexts kept ready per browser in `context` isolation
+    pub browser_prewarmed_contexts: usize,
+    /// Renders that may wait for a free tab before new ones are rejected with 429
+    pub render_queue_max_backlog: usize,
+    /// How long a render waits for a free tab before it is rejected with 503
+    pub render_queue_max_wait_secs: u64,
+    /// Tenants (customer or client ids) whose renders wait in the batch lane
+    pub batch_priority_tenants: Vec<String>,
+
+    /// Deadline of a whole render, from waiting for a tab to the printed document
+    pub render_timeout_secs: u64,
+    /// Deadline for loading the HTML or navigating to the URL
+    pub render_load_timeout_secs: u64,
+    /// Deadline for the `waitFor` conditions, on top of their own timeout
+    pub render_wait_timeout_secs: u64,
+    /// Deadline for printing or capturing the page
+    pub render_print_timeout_secs: u64,
+
+    /// Tabs opened at startup and kept open while the pool is idle
+    pub page_pool_min_size: usize,
+    /// Renders running at the same time
+    pub page_pool_max_size: usize,
+    /// Inactivity after which a browser closes idle tabs beyond the minimum, 0 to keep them
+    pub page_pool_idle_secs: u64,
+    /// Move the number of concurrent renders between the min and max size with demand
+    pub page_pool_adaptive: bool,
+    /// Average wait for a free tab above which the adaptive pool grows
+    pub page_pool_grow_wait_ms: u64,
+    /// Renders after which a pooled tab is replaced, 0 for no limit
+    pub page_max_uses: u32,
+    /// Age after which a pooled tab is replaced, 0 for no limit
+    pub page_max_age_secs: u64,
+    /// JS heap size above which a tab is replaced after its render, 0 for no limit
+    pub page_max_js_heap_mb: u64,
+    /// Interval of the liveness probe sent to Chrome
+    pub browser_health_check_secs: u64,
+    /// Upper bound of the delay between attempts to relaunch a crashed browser
+    pub browser_relaunch_max_backoff_secs: u64,
+
+    /// Directory or file of the PDFium library used for thumbnails; the system library when unset
+    pub pdfium_library_path: Option<PathBuf>,
+    /// Largest image `/html2image` captures, in device pixels; full pages are cut off beyond it
+    pub screenshot_max_pixels: u64,
 }
 
 static CONFIG: Lazy<Arc<AppConfig>> = Lazy::new(|| Arc::new(load_config()));
@@ -30,33 +130,112 @@ fn extract_issuer_from_jwks_url(jwks_url: &str) -> Result<String, Box<dyn std::e
     Ok(base_url)
 }
 
-fn load_config() -> AppConfig {
-    dotenv().ok();
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid {} value", name)),
+        Err(_) => default,
+    }
+}
 
-    let mut config = AppConfig::default();
-    config.port = std::env::var("PORT")
-        .unwrap_or_else(|_| 3000.to_string())
-        .parse()
-        .expect("Invalid PORT value");
+fn env_list(name: &str) -> Option<Vec<String>> {
+    std::env::var(name).ok().map(|value| {
+        value
+            .split(',')
+            .map(|item| item.trim().to_lowercase())
+            .filter(|item| !item.is_empty())
+            .collect()
+    })
+}
 
-    config.ship_key =
-        std::env::var("AUTH_SHIP_TOKEN").expect("AUTH_SHIP_TOKEN environment variable is not set");
+fn load_config() -> AppConfig {
+    dotenv().ok();
 
     let auth0_jwks_uri = std::env::var("AUTH_AUTH0_JWKS_URI")
         .expect("AUTH_AUTH0_JWKS_URI environment variable is not set");
     let auth_eu_keycloak_jwks_uri = std::env::var("AUTH_EU_KEYCLOAK_JWKS_URI")
         .expect("AUTH_EU_KEYCLOAK_JWKS_URI environment variable is not set");
 
-    config.jwks_issuers.push((
-        extract_issuer_from_jwks_url(&auth0_jwks_uri).unwrap(),
-        auth0_jwks_uri,
-    ));
-    config.jwks_issuers.push((
-        extract_issuer_from_jwks_url(&auth_eu_keycloak_jwks_uri).unwrap(),
-        auth_eu_keycloak_jwks_uri,
-    ));
+    AppConfig {
+        env: AppEnv::default(),
+
+        port: std::env::var("PORT")
+            .unwrap_or_else(|_| 3000.to_string())
+            .parse()
+            .expect("Invalid PORT value"),
+        ship_key: std::env::var("AUTH_SHIP_TOKEN")
+            .expect("AUTH_SHIP_TOKEN environment variable is not set"),
+        jwks_issuers: vec![
+            (
+                extract_issuer_from_jwks_url(&auth0_jwks_uri).unwrap(),
+                auth0_jwks_uri,
+            ),
+            (
+                extract_issuer_from_jwks_url(&auth_eu_keycloak_jwks_uri).unwrap(),
+                auth_eu_keycloak_jwks_uri,
+            ),
+        ],
+
+        url_policy: env_policy("URL_ALLOWED_SCHEMES", "URL_ALLOWED_HOSTS"),
+        templates_dir: std::env::var("TEMPLATES_DIR")
+            .unwrap_or_else(|_| "templates".to_string())
+            .into(),
+        fonts_dir: std::env::var("FONTS_DIR")
+            .unwrap_or_else(|_| "fonts".to_string())
+            .into(),
+        font_max_bytes: env_or("FONT_MAX_BYTES", 10 * 1024 * 1024),
+        admin_roles: env_list("ADMIN_ROLES").unwrap_or_else(|| vec!["html2pdf-admin".to_string()]),
+
+        job_workers: env_or("JOB_WORKERS", 4),
+        job_retention_secs: env_or("JOB_RETENTION_SECS", 3600),
+        job_max_pending: env_or("JOB_MAX_PENDING", 100),
+        job_max_retained_bytes: env_or("JOB_MAX_RETAINED_BYTES", 512 * 1024 * 1024),
+        webhook_secret: std::env::var("WEBHOOK_SECRET").ok(),
+        callback_policy: env_policy("CALLBACK_ALLOWED_SCHEMES", "CALLBACK_ALLOWED_HOSTS"),
+
+        batch_max_items: env_or("BATCH_MAX_ITEMS", 1000),
+        batch_body_limit_bytes: env_or("BATCH_BODY_LIMIT_BYTES", 64 * 1024 * 1024),
+
+        bundle_max_bytes: env_or("BUNDLE_MAX_BYTES", 32 * 1024 * 1024),
+        bundle_max_files: env_or("BUNDLE_MAX_FILES", 500),
+        bundle_max_unpacked_bytes: env_or("BUNDLE_MAX_UNPACKED_BYTES", 128 * 1024 * 1024),
+
+        browser_count: env_or("BROWSER_COUNT", 1),
+        browser_isolation: env_or("BROWSER_ISOLATION", PageIsolation::Shared),
+        browser_prewarmed_contexts: env_or("BROWSER_PREWARMED_CONTEXTS", 2),
+        render_queue_max_backlog: env_or("RENDER_QUEUE_MAX_BACKLOG", 100),
+        render_queue_max_wait_secs: env_or("RENDER_QUEUE_MAX_WAIT_SECS", 60),
+        batch_priority_tenants: env_list("BATCH_PRIORITY_TENANTS").unwrap_or_default(),
+
+        render_timeout_secs: env_or("RENDER_TIMEOUT_SECS", 300),
+        render_load_timeout_secs: env_or("RENDER_LOAD_TIMEOUT_SECS", 30),
+        render_wait_timeout_secs: env_or("RENDER_WAIT_TIMEOUT_SECS", 150),
+        render_print_timeout_secs: env_or("RENDER_PRINT_TIMEOUT_SECS", 60),
+
+        page_pool_min_size: env_or("PAGE_POOL_MIN_SIZE", 2),
+        page_pool_max_size: env_or("PAGE_POOL_MAX_SIZE", 10),
+        page_pool_idle_secs: env_or("PAGE_POOL_IDLE_SECS", 300),
+        page_pool_adaptive: env_or("PAGE_POOL_ADAPTIVE", false),
+        page_pool_grow_wait_ms: env_or("PAGE_POOL_GROW_WAIT_MS", 500),
+        page_max_uses: env_or("PAGE_MAX_USES", 100),
+        page_max_age_secs: env_or("PAGE_MAX_AGE_SECS", 1800),
+        page_max_js_heap_mb: env_or("PAGE_MAX_JS_HEAP_MB", 512),
+        browser_health_check_secs: env_or("BROWSER_HEALTH_CHECK_SECS", 10),
+        browser_relaunch_max_backoff_secs: env_or("BROWSER_RELAUNCH_MAX_BACKOFF_SECS", 60),
+
+        pdfium_library_path: std::env::var("PDFIUM_LIBRARY_PATH").ok().map(Into::into),
+        screenshot_max_pixels: env_or("SCREENSHOT_MAX_PIXELS", 40_000_000),
+    }
+}
 
-    config
+/// `UrlPolicy` with the schemes and hosts of the given variables, the defaults where unset.
+fn env_policy(schemes: &str, hosts: &str) -> UrlPolicy {
+    let default = UrlPolicy::default();
+    UrlPolicy {
+        allowed_schemes: env_list(schemes).unwrap_or(default.allowed_schemes),
+        allowed_hosts: env_list(hosts).unwrap_or(default.allowed_hosts),
+    }
 }
 
 pub fn get() -> Arc<AppConfig> {
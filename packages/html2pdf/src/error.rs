@@ -1,28 +1,179 @@
+use std::fmt;
+
 use axum::{
-    http::StatusCode,
+    http::{HeaderValue, StatusCode, header},
     response::{IntoResponse, Response},
 };
 
 pub enum HttpError {
     BadRequest(anyhow::Error),
+    NotFound(anyhow::Error),
+    Conflict(anyhow::Error),
+    Forbidden(anyhow::Error),
     InternalServerError(anyhow::Error),
+    WaitTimeout(anyhow::Error),
+    GatewayTimeout(anyhow::Error),
+    TooManyRequests(anyhow::Error),
+    PayloadTooLarge(anyhow::Error),
+    ServiceUnavailable(anyhow::Error),
+}
+
+impl HttpError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HttpError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            HttpError::NotFound(_) => StatusCode::NOT_FOUND,
+            HttpError::Conflict(_) => StatusCode::CONFLICT,
+            HttpError::Forbidden(_) => StatusCode::FORBIDDEN,
+            HttpError::WaitTimeout(_) | HttpError::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            HttpError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            HttpError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            HttpError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            HttpError::InternalServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Prefix the error message with `context`, keeping the status.
+    pub fn context(self, context: impl fmt::Display) -> Self {
+        let wrap = |err: anyhow::Error| anyhow::anyhow!("{}: {}", context, err);
+        match self {
+            HttpError::BadRequest(err) => HttpError::BadRequest(wrap(err)),
+            HttpError::NotFound(err) => HttpError::NotFound(wrap(err)),
+            HttpError::Conflict(err) => HttpError::Conflict(wrap(err)),
+            HttpError::Forbidden(err) => HttpError::Forbidden(wrap(err)),
+            HttpError::WaitTimeout(err) => HttpError::WaitTimeout(wrap(err)),
+            HttpError::GatewayTimeout(err) => HttpError::GatewayTimeout(wrap(err)),
+            HttpError::TooManyRequests(err) => HttpError::TooManyRequests(wrap(err)),
+            HttpError::PayloadTooLarge(err) => HttpError::PayloadTooLarge(wrap(err)),
+            HttpError::ServiceUnavailable(err) => HttpError::ServiceUnavailable(wrap(err)),
+            HttpError::InternalServerError(err) => HttpError::InternalServerError(wrap(err)),
+        }
+    }
+}
+
+// The message a client gets to see; internal errors are only logged.
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::BadRequest(err) => write!(f, "Bad Request: {}", err),
+            HttpError::NotFound(err) => write!(f, "Not Found: {}", err),
+            HttpError::Conflict(err) => write!(f, "Conflict: {}", err),
+            HttpError::Forbidden(err) => write!(f, "Forbidden: {}", err),
+            HttpError::WaitTimeout(err) => write!(f, "Wait Timeout: {}", err),
+            HttpError::GatewayTimeout(err) => write!(f, "Gateway Timeout: {}", err),
+            HttpError::TooManyRequests(err) => write!(f, "Too Many Requests: {}", err),
+            HttpError::PayloadTooLarge(err) => write!(f, "Payload Too Large: {}", err),
+            HttpError::ServiceUnavailable(err) => write!(f, "Service Unavailable: {}", err),
+            HttpError::InternalServerError(_) => write!(f, "Internal Server Error"),
+        }
+    }
 }
 
 impl IntoResponse for HttpError {
     fn into_response(self) -> Response {
+        if let HttpError::InternalServerError(err) = &self {
+            tracing::error!("Internal Server Error: {}", err);
+        }
+
+        let retry_after = match &self {
+            HttpError::TooManyRequests(err) | HttpError::ServiceUnavailable(err) => err
+                .downcast_ref::<RenderError>()
+                .and_then(RenderError::retry_after_secs),
+            _ => None,
+        };
+
+        let mut response = (self.status(), self.to_string()).into_response();
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
+
+/// Render failures caused by the request rather than by the service. They travel through
+/// `anyhow` like any other error and are mapped to a matching `HttpError` on the way out.
+#[derive(Debug, thiserror::Error)]
+pub enum RenderError {
+    #[error("URL not allowed: {0}")]
+    UrlNotAllowed(String),
+
+    #[error("{condition} not met within {timeout_ms}ms")]
+    WaitTimeout { condition: String, timeout_ms: u64 },
+
+    #[error("invalid wait condition: {0}")]
+    InvalidWaitCondition(String),
+
+    #[error("template not found: {0}")]
+    TemplateNotFound(String),
+
+    #[error("invalid template: {0}")]
+    InvalidTemplate(String),
+
+    #[error("invalid PDF options: {0}")]
+    InvalidPdfOptions(String),
+
+    #[error("conformance failed: {0}")]
+    ConformanceFailed(String),
+
+    #[error("invalid image options: {0}")]
+    InvalidImageOptions(String),
+
+    #[error("invalid resource policy: {0}")]
+    InvalidResourcePolicy(String),
+
+    #[error("invalid bundle: {0}")]
+    InvalidBundle(String),
+
+    #[error("bundle too large: {0}")]
+    BundleTooLarge(String),
+
+    #[error("invalid font: {0}")]
+    InvalidFont(String),
+
+    #[error("browser unavailable: {0}")]
+    BrowserUnavailable(String),
+
+    #[error("{phase} did not finish within {timeout_ms}ms")]
+    RenderTimeout {
+        phase: &'static str,
+        timeout_ms: u128,
+    },
+
+    #[error("render queue is full, {waiting} renders are waiting")]
+    QueueFull {
+        waiting: usize,
+        retry_after_secs: u64,
+    },
+
+    #[error("job queue is full, {pending} jobs are pending")]
+    JobQueueFull {
+        pending: usize,
+        retry_after_secs: u64,
+    },
+
+    #[error("no render slot became free within {waited_ms}ms")]
+    QueueTimeout {
+        waited_ms: u128,
+        retry_after_secs: u64,
+    },
+}
+
+impl RenderError {
+    /// When a client should try again after the service turned a render away.
+    pub fn retry_after_secs(&self) -> Option<u64> {
         match self {
-            HttpError::BadRequest(err) => {
-                (StatusCode::BAD_REQUEST, format!("Bad Request: {}", err)).into_response()
+            RenderError::QueueFull {
+                retry_after_secs, ..
             }
-            HttpError::InternalServerError(err) => {
-                tracing::error!("Internal Server Error: {}", err);
-
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Internal Server Error"),
-                )
-                    .into_response()
+            | RenderError::QueueTimeout {
+                retry_after_secs, ..
             }
+            | RenderError::JobQueueFull {
+                retry_after_secs, ..
+            } => Some(*retry_after_secs),
+            _ => None,
         }
     }
 }
@@ -34,6 +185,29 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self::InternalServerError(err.into())
+        let err = err.into();
+        match err.downcast_ref::<RenderError>() {
+            Some(
+                RenderError::UrlNotAllowed(_)
+                | RenderError::InvalidWaitCondition(_)
+                | RenderError::InvalidTemplate(_)
+                | RenderError::InvalidPdfOptions(_)
+                | RenderError::ConformanceFailed(_)
+                | RenderError::InvalidImageOptions(_)
+                | RenderError::InvalidResourcePolicy(_)
+                | RenderError::InvalidBundle(_)
+                | RenderError::InvalidFont(_),
+            ) => Self::BadRequest(err),
+            Some(RenderError::BundleTooLarge(_)) => Self::PayloadTooLarge(err),
+            Some(RenderError::TemplateNotFound(_)) => Self::NotFound(err),
+            Some(RenderError::WaitTimeout { .. }) => Self::WaitTimeout(err),
+            Some(RenderError::BrowserUnavailable(_)) => Self::ServiceUnavailable(err),
+            Some(RenderError::RenderTimeout { .. }) => Self::GatewayTimeout(err),
+            Some(RenderError::QueueFull { .. } | RenderError::JobQueueFull { .. }) => {
+                Self::TooManyRequests(err)
+            }
+            Some(RenderError::QueueTimeout { .. }) => Self::ServiceUnavailable(err),
+            None => Self::InternalServerError(err),
+        }
     }
 }
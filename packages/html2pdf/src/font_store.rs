@@ -0,0 +1,480 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose};
+use chromiumoxide::Page;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::Mutex};
+use url::Url;
+
+use crate::{error::RenderError, resources::Asset};
+
+/// Origin registered fonts are served from during renders. `.invalid` never resolves, so the
+/// files can only come from the registry.
+const FONT_ORIGIN: &str = "https://fonts.invalid/";
+const DEFAULT_WEIGHT: u16 = 400;
+
+/// Organization fonts kept on disk and made available to every render.
+///
+/// Every face is one file named after its weight and style:
+///
+/// ```text
+/// {dir}/{family}/{weight}-{style}.{ttf|otf|woff|woff2}
+/// ```
+///
+/// Fonts can be dropped onto disk by hand and are picked up at startup, or stored through
+/// [`FontStore::add`]. Renders get an `@font-face` rule per face, with the files served by request
+/// interception.
+pub struct FontStore {
+    dir: PathBuf,
+    faces: RwLock<Arc<FontFaces>>,
+    write_lock: Mutex<()>,
+}
+
+/// The registered faces as seen by a render.
+#[derive(Default)]
+pub struct FontFaces {
+    pub faces: Vec<FontFace>,
+    /// Font files on the font origin
+    pub assets: Arc<[Asset]>,
+    /// `@font-face` rules for every face
+    pub css: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FontFace {
+    pub family: String,
+    pub weight: u16,
+    pub style: FontStyle,
+    pub format: FontFormat,
+    /// File size in bytes
+    pub size: usize,
+}
+
+#[derive(Serialize)]
+pub struct FontFamily {
+    pub family: String,
+    pub faces: Vec<FontFace>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FontStyle {
+    #[default]
+    Normal,
+    Italic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FontFormat {
+    Ttf,
+    Otf,
+    Woff,
+    Woff2,
+}
+
+impl FontStyle {
+    fn as_str(self) -> &'static str {
+        match self {
+            FontStyle::Normal => "normal",
+            FontStyle::Italic => "italic",
+        }
+    }
+}
+
+impl FontFormat {
+    const ALL: [FontFormat; 4] = [
+        FontFormat::Ttf,
+        FontFormat::Otf,
+        FontFormat::Woff,
+        FontFormat::Woff2,
+    ];
+
+    /// Recognize a font file by its signature.
+    fn detect(data: &[u8]) -> Option<Self> {
+        match data.get(..4)? {
+            [0x00, 0x01, 0x00, 0x00] | b"true" => Some(FontFormat::Ttf),
+            b"OTTO" => Some(FontFormat::Otf),
+            b"wOFF" => Some(FontFormat::Woff),
+            b"wOF2" => Some(FontFormat::Woff2),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            FontFormat::Ttf => "ttf",
+            FontFormat::Otf => "otf",
+            FontFormat::Woff => "woff",
+            FontFormat::Woff2 => "woff2",
+        }
+    }
+
+    fn from_extension(extension: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.extension().eq_ignore_ascii_case(extension))
+    }
+
+    fn mime(self) -> &'static str {
+        match self {
+            FontFormat::Ttf => "font/ttf",
+            FontFormat::Otf => "font/otf",
+            FontFormat::Woff => "font/woff",
+            FontFormat::Woff2 => "font/woff2",
+        }
+    }
+
+    /// Format hint of a `src` descriptor.
+    fn css_format(self) -> &'static str {
+        match self {
+            FontFormat::Ttf => "truetype",
+            FontFormat::Otf => "opentype",
+            FontFormat::Woff => "woff",
+            FontFormat::Woff2 => "woff2",
+        }
+    }
+}
+
+impl FontStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            faces: RwLock::new(Arc::default()),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// Read every font on disk, replacing what renders use.
+    pub async fn load(&self) -> Result<()> {
+        let mut files = Vec::new();
+
+        let mut families = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                *self.faces.write().unwrap() = Arc::default();
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(family_entry) = families.next_entry().await? {
+            let Ok(family) = family_entry.file_name().into_string() else {
+                continue;
+            };
+            if !family_entry.file_type().await?.is_dir() || !is_valid_family(&family) {
+                continue;
+            }
+
+            let mut entries = fs::read_dir(family_entry.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                let Some(format) = path
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .and_then(FontFormat::from_extension)
+                else {
+                    continue;
+                };
+                let (weight, style) = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .map_or((DEFAULT_WEIGHT, FontStyle::Normal), parse_face_name);
+
+                let data = fs::read(&path).await?;
+                if FontFormat::detect(&data) != Some(format) {
+                    tracing::warn!(
+                        "Skipping {}, it is no {} font",
+                        path.display(),
+                        format.extension()
+                    );
+                    continue;
+                }
+
+                let file = entry.file_name().to_string_lossy().into_owned();
+                files.push((
+                    FontFace {
+                        family: family.clone(),
+                        weight,
+                        style,
+                        format,
+                        size: data.len(),
+                    },
+                    file,
+                    data,
+                ));
+            }
+        }
+
+        files.sort_by(|(a, _, _), (b, _, _)| {
+            (&a.family, a.weight, a.style.as_str()).cmp(&(&b.family, b.weight, b.style.as_str()))
+        });
+        let faces = FontFaces::build(files);
+        tracing::info!("Loaded {} font faces", faces.faces.len());
+        *self.faces.write().unwrap() = Arc::new(faces);
+
+        Ok(())
+    }
+
+    /// Faces currently available to renders.
+    pub fn faces(&self) -> Arc<FontFaces> {
+        Arc::clone(&self.faces.read().unwrap())
+    }
+
+    /// Registered faces grouped by family.
+    pub fn list(&self) -> Vec<FontFamily> {
+        let mut families: Vec<FontFamily> = Vec::new();
+
+        for face in &self.faces().faces {
+            match families.last_mut() {
+                Some(family) if family.family == face.family => family.faces.push(face.clone()),
+                _ => families.push(FontFamily {
+                    family: face.family.clone(),
+                    faces: vec![face.clone()],
+                }),
+            }
+        }
+
+        families
+    }
+
+    /// Store a face of `family`, replacing one of the same weight and style, and make it
+    /// available to renders.
+    pub async fn add(
+        &self,
+        family: &str,
+        weight: u16,
+        style: FontStyle,
+        data: &[u8],
+    ) -> Result<FontFace> {
+        validate_family(family)?;
+        if !(1..=1000).contains(&weight) {
+            return Err(RenderError::InvalidFont(format!(
+                "weight {} is out of range 1-1000",
+                weight
+            ))
+            .into());
+        }
+        let format = FontFormat::detect(data).ok_or_else(|| {
+            RenderError::InvalidFont("not a TrueType, OpenType, WOFF or WOFF2 font".to_string())
+        })?;
+
+        let _guard = self.write_lock.lock().await;
+
+        let family_dir = self.dir.join(family);
+        fs::create_dir_all(&family_dir).await?;
+
+        let stem = format!("{}-{}", weight, style.as_str());
+        // Write next to the final file so a face never becomes visible half-written
+        let staging = family_dir.join(format!(".staging-{}", stem));
+        fs::write(&staging, data).await?;
+        for other in FontFormat::ALL {
+            let file = family_dir.join(format!("{}.{}", stem, other.extension()));
+            if other != format && fs::try_exists(&file).await? {
+                fs::remove_file(&file).await?;
+            }
+        }
+        fs::rename(
+            &staging,
+            family_dir.join(format!("{}.{}", stem, format.extension())),
+        )
+        .await?;
+
+        tracing::info!("Stored font {} {} {}", family, weight, style.as_str());
+        self.load().await?;
+
+        Ok(FontFace {
+            family: family.to_string(),
+            weight,
+            style,
+            format,
+            size: data.len(),
+        })
+    }
+}
+
+impl FontFaces {
+    fn build(files: Vec<(FontFace, String, Vec<u8>)>) -> Self {
+        let origin = Url::parse(FONT_ORIGIN).expect("valid font origin");
+
+        let mut faces = Vec::new();
+        let mut assets = Vec::new();
+        let mut css = String::new();
+        for (face, file, data) in files {
+            let Ok(url) = origin.join(&format!("{}/{}", face.family, file)) else {
+                continue;
+            };
+
+            css.push_str(&format!(
+                "@font-face {{ font-family: \"{}\"; src: url(\"{}\") format(\"{}\"); font-weight: {}; font-style: {}; font-display: block; }}\n",
+                face.family,
+                url,
+                face.format.css_format(),
+                face.weight,
+                face.style.as_str()
+            ));
+            assets.push(Asset {
+                url: url.to_string(),
+                content_type: Some(face.format.mime().to_string()),
+                data: general_purpose::STANDARD.encode(data),
+            });
+            faces.push(face);
+        }
+
+        Self {
+            faces,
+            assets: assets.into(),
+            css,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.faces.is_empty()
+    }
+
+    /// Add the `@font-face` rules to a loaded page and wait until the fonts it uses are ready.
+    pub async fn inject(&self, page: &Page) -> Result<()> {
+        let js = format!(
+            "(() => {{ const style = document.createElement('style'); style.textContent = {}; (document.head || document.documentElement).appendChild(style); void document.documentElement.offsetHeight; return document.fonts.ready.then(() => true); }})()",
+            serde_json::to_string(&self.css)?
+        );
+        page.evaluate_expression(js).await?;
+        Ok(())
+    }
+}
+
+/// Weight and style from a file name such as `700-italic`; anything unrecognized counts as a
+/// regular face.
+fn parse_face_name(stem: &str) -> (u16, FontStyle) {
+    let (weight, style) = stem.split_once('-').unwrap_or((stem, "normal"));
+    let style = if style.eq_ignore_ascii_case("italic") {
+        FontStyle::Italic
+    } else {
+        FontStyle::Normal
+    };
+
+    (weight.parse().unwrap_or(DEFAULT_WEIGHT), style)
+}
+
+fn is_valid_family(family: &str) -> bool {
+    !family.is_empty()
+        && family.len() <= 128
+        && family.trim() == family
+        && family
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-' || c == '_')
+}
+
+fn validate_family(family: &str) -> Result<(), RenderError> {
+    if is_valid_family(family) {
+        Ok(())
+    } else {
+        Err(RenderError::InvalidFont(format!(
+            "invalid family '{}', use letters, digits, spaces, '-' and '_'",
+            family
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTF: &[u8] = b"\x00\x01\x00\x00 rest of a TrueType font";
+    const WOFF2: &[u8] = b"wOF2 rest of a WOFF2 font";
+
+    #[test]
+    fn formats_are_detected_by_signature() {
+        assert_eq!(FontFormat::detect(TTF), Some(FontFormat::Ttf));
+        assert_eq!(FontFormat::detect(b"true...."), Some(FontFormat::Ttf));
+        assert_eq!(FontFormat::detect(b"OTTO...."), Some(FontFormat::Otf));
+        assert_eq!(FontFormat::detect(b"wOFF...."), Some(FontFormat::Woff));
+        assert_eq!(FontFormat::detect(WOFF2), Some(FontFormat::Woff2));
+        assert_eq!(FontFormat::detect(b"%PDF-1.7"), None);
+        assert_eq!(FontFormat::detect(b"wOF"), None);
+    }
+
+    #[test]
+    fn face_names_default_to_regular() {
+        assert_eq!(parse_face_name("700-italic"), (700, FontStyle::Italic));
+        assert_eq!(parse_face_name("300-ITALIC"), (300, FontStyle::Italic));
+        assert_eq!(parse_face_name("900"), (900, FontStyle::Normal));
+        assert_eq!(parse_face_name("700-oblique"), (700, FontStyle::Normal));
+        assert_eq!(parse_face_name("bold"), (DEFAULT_WEIGHT, FontStyle::Normal));
+    }
+
+    #[test]
+    fn family_names_are_plain() {
+        assert!(is_valid_family("Open Sans"));
+        assert!(is_valid_family("Source_Code-Pro"));
+        assert!(!is_valid_family(""));
+        assert!(!is_valid_family(" Padded"));
+        assert!(!is_valid_family("../etc"));
+        assert!(!is_valid_family("Quote\"d"));
+        assert!(!is_valid_family(&"x".repeat(129)));
+    }
+
+    #[tokio::test]
+    async fn added_faces_replace_other_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FontStore::new(dir.path());
+
+        store
+            .add("Open Sans", 700, FontStyle::Italic, TTF)
+            .await
+            .unwrap();
+        let face = store
+            .add("Open Sans", 700, FontStyle::Italic, WOFF2)
+            .await
+            .unwrap();
+        assert_eq!(face.format, FontFormat::Woff2);
+
+        let family_dir = dir.path().join("Open Sans");
+        assert!(!family_dir.join("700-italic.ttf").exists());
+        assert!(family_dir.join("700-italic.woff2").exists());
+
+        // A fresh store finds the same face on disk
+        let reloaded = FontStore::new(dir.path());
+        reloaded.load().await.unwrap();
+        let faces = reloaded.faces();
+        assert_eq!(faces.faces.len(), 1);
+        assert_eq!(faces.faces[0].size, WOFF2.len());
+
+        let url = "https://fonts.invalid/Open%20Sans/700-italic.woff2";
+        assert_eq!(faces.assets[0].url, url);
+        assert_eq!(faces.assets[0].content_type.as_deref(), Some("font/woff2"));
+        assert_eq!(
+            faces.css,
+            format!(
+                "@font-face {{ font-family: \"Open Sans\"; src: url(\"{}\") format(\"woff2\"); font-weight: 700; font-style: italic; font-display: block; }}\n",
+                url
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_fonts_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FontStore::new(dir.path());
+
+        assert!(
+            store
+                .add("Sans", 400, FontStyle::Normal, b"%PDF")
+                .await
+                .is_err()
+        );
+        assert!(store.add("Sans", 0, FontStyle::Normal, TTF).await.is_err());
+        assert!(
+            store
+                .add("../x", 400, FontStyle::Normal, TTF)
+                .await
+                .is_err()
+        );
+        assert!(store.list().is_empty());
+    }
+}
@@ -0,0 +1,67 @@
+use axum::{
+    Json,
+    extract::{Extension, Path, State},
+    http::StatusCode,
+};
+use base64::{Engine as _, engine::general_purpose};
+use serde::Deserialize;
+
+use crate::{
+    AdminAccess, AppState, cnfg,
+    error::{HttpError, RenderError},
+    font_store::{FontFace, FontFamily, FontStyle},
+};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadFontRequest {
+    /// Base64 encoded TrueType, OpenType, WOFF or WOFF2 file
+    pub data: String,
+    #[serde(default = "default_weight")]
+    pub weight: u16,
+    #[serde(default)]
+    pub style: FontStyle,
+}
+
+fn default_weight() -> u16 {
+    400
+}
+
+pub async fn list_fonts(State(app_state): State<AppState>) -> Json<Vec<FontFamily>> {
+    Json(app_state.font_store.list())
+}
+
+/// Register a face of `family` for all renders. Only callers with one of the `ADMIN_ROLES` may
+/// change the fonts every tenant renders with.
+pub async fn upload_font(
+    State(app_state): State<AppState>,
+    Extension(AdminAccess(is_admin)): Extension<AdminAccess>,
+    Path(family): Path<String>,
+    Json(payload): Json<UploadFontRequest>,
+) -> Result<(StatusCode, Json<FontFace>), HttpError> {
+    if !is_admin {
+        return Err(HttpError::Forbidden(anyhow::anyhow!(
+            "Managing fonts needs an admin role"
+        )));
+    }
+
+    let data = general_purpose::STANDARD
+        .decode(payload.data.trim())
+        .map_err(|e| RenderError::InvalidFont(format!("data is not valid base64: {}", e)))?;
+    let max_bytes = cnfg::get().font_max_bytes;
+    if data.len() > max_bytes {
+        return Err(RenderError::InvalidFont(format!(
+            "font is {} bytes, at most {} are allowed",
+            data.len(),
+            max_bytes
+        ))
+        .into());
+    }
+
+    let face = app_state
+        .font_store
+        .add(&family, payload.weight, payload.style, &data)
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(face)))
+}
@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+
+use chromiumoxide::cdp::browser_protocol::page::PrintToPdfParams;
+use serde::Deserialize;
+
+use crate::{error::RenderError, page_setup::check_printable_area};
+
+/// Margin Chrome prints with when none is given, in inches.
+const DEFAULT_MARGIN_IN: f64 = 0.4;
+/// Smallest top or bottom margin leaving room for a header or footer, in inches.
+const MIN_HEADER_FOOTER_MARGIN_IN: f64 = 0.75;
+
+/// Placeholders Chrome fills in itself, by the class of the element it fills.
+const BUILTIN_PLACEHOLDERS: [(&str, &str); 5] = [
+    ("pageNumber", "pageNumber"),
+    ("totalPages", "totalPages"),
+    ("date", "date"),
+    ("title", "title"),
+    ("url", "url"),
+];
+
+/// Running header and footer printed on every page.
+///
+/// Both are HTML with `{{placeholder}}`s: `pageNumber`, `totalPages`, `date`, `title` and `url`
+/// are filled in by Chrome per page, any other name is looked up in `variables` and inserted as
+/// text. Chrome renders them without the page's styles, so a readable font size and the page's
+/// side margins are applied automatically.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HeaderFooter {
+    pub header: Option<String>,
+    pub footer: Option<String>,
+    /// Values of the caller's own placeholders, e.g. a document number
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+}
+
+impl HeaderFooter {
+    pub fn is_empty(&self) -> bool {
+        self.header.is_none() && self.footer.is_none()
+    }
+
+    /// Reject templates that can never be applied, before anything is rendered.
+    pub fn validate(&self, params: Option<&PrintToPdfParams>) -> Result<(), RenderError> {
+        // Chrome fills these in itself, so a variable of the same name would never be shown
+        if let Some(name) = self.variables.keys().find(|name| {
+            BUILTIN_PLACEHOLDERS
+                .iter()
+                .any(|(placeholder, _)| placeholder == name)
+        }) {
+            return Err(RenderError::InvalidPdfOptions(format!(
+                "'{}' is a built-in header/footer placeholder and cannot be a variable",
+                name
+            )));
+        }
+
+        self.apply(params.cloned()).map(|_| ())
+    }
+
+    /// Print parameters showing the header and footer, with top and bottom margins raised where
+    /// they would clip them.
+    pub fn apply(
+        &self,
+        params: Option<PrintToPdfParams>,
+    ) -> Result<Option<PrintToPdfParams>, RenderError> {
+        if self.is_empty() {
+            return Ok(params);
+        }
+
+        let params = params.unwrap_or_default();
+        self.check_overrides(&params)?;
+
+        let sides = (
+            params.margin_left.unwrap_or(DEFAULT_MARGIN_IN),
+            params.margin_right.unwrap_or(DEFAULT_MARGIN_IN),
+        );
+        let template = |content: &Option<String>, given: &Option<String>| match content {
+            Some(content) => Ok(wrap(&self.substitute(content)?, sides)),
+            None => Ok(given
+                .clone()
+                // Chrome prints its own date, title and URL in place of a missing template
+                .unwrap_or_else(|| "<span></span>".to_string())),
+        };
+        let fit = |margin: Option<f64>, shown: bool| {
+            if shown {
+                Some(margin.unwrap_or(0.0).max(MIN_HEADER_FOOTER_MARGIN_IN))
+            } else {
+                margin
+            }
+        };
+
+        let params = PrintToPdfParams {
+            display_header_footer: Some(true),
+            header_template: Some(template(&self.header, &params.header_template)?),
+            footer_template: Some(template(&self.footer, &params.footer_template)?),
+            margin_top: fit(params.margin_top, self.header.is_some()),
+            margin_bottom: fit(params.margin_bottom, self.footer.is_some()),
+            ..params
+        };
+        // The raised margins may no longer fit on small paper
+        check_printable_area(&params).map_err(|_| {
+            RenderError::InvalidPdfOptions(format!(
+                "margins leave no room on the paper with the {}in needed by a header or footer",
+                MIN_HEADER_FOOTER_MARGIN_IN
+            ))
+        })?;
+
+        Ok(Some(params))
+    }
+
+    /// Templates may be given here or in `printParams`, not in both.
+    fn check_overrides(&self, params: &PrintToPdfParams) -> Result<(), RenderError> {
+        let conflicts = [
+            (
+                self.header.is_some() && params.header_template.is_some(),
+                "header",
+                "headerTemplate",
+            ),
+            (
+                self.footer.is_some() && params.footer_template.is_some(),
+                "footer",
+                "footerTemplate",
+            ),
+        ];
+
+        match conflicts.iter().find(|(conflict, _, _)| *conflict) {
+            Some((_, option, param)) => Err(RenderError::InvalidPdfOptions(format!(
+                "set either {} or printParams.{}, not both",
+                option, param
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace the placeholders of `template`.
+    fn substitute(&self, template: &str) -> Result<String, RenderError> {
+        let mut html = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(start) = rest.find("{{") {
+            html.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let end = after.find("}}").ok_or_else(|| {
+                RenderError::InvalidPdfOptions("unclosed '{{' in header or footer".to_string())
+            })?;
+            let name = after[..end].trim();
+
+            match BUILTIN_PLACEHOLDERS
+                .iter()
+                .find(|(placeholder, _)| *placeholder == name)
+            {
+                Some((_, class)) => html.push_str(&format!("<span class=\"{}\"></span>", class)),
+                None => {
+                    let value = self.variables.get(name).ok_or_else(|| {
+                        RenderError::InvalidPdfOptions(format!(
+                            "no value for header/footer placeholder '{}'",
+                            name
+                        ))
+                    })?;
+                    html.push_str(&escape_html(value));
+                }
+            }
+
+            rest = &after[end + 2..];
+        }
+        html.push_str(rest);
+
+        Ok(html)
+    }
+}
+
+/// Give the template the styles Chrome's header and footer lack: a font size other than zero,
+/// the page's side margins, and printed backgrounds.
+fn wrap(content: &str, (left, right): (f64, f64)) -> String {
+    format!(
+        "<style>#header, #footer {{ padding: 0 !important; }} .html2pdf-hf {{ width: 100%; box-sizing: border-box; padding: 0 {}in 0 {}in; font-family: sans-serif; font-size: 10px; -webkit-print-color-adjust: exact; print-color-adjust: exact; }}</style><div class=\"html2pdf-hf\">{}</div>",
+        right, left, content
+    )
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::page_setup::PageSetup;
+
+    fn header_footer(value: serde_json::Value) -> HeaderFooter {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        let options = header_footer(json!({
+            "header": "",
+            "variables": { "invoice": "<A & B>" }
+        }));
+
+        assert_eq!(
+            options
+                .substitute("{{ pageNumber }}/{{totalPages}}")
+                .unwrap(),
+            "<span class=\"pageNumber\"></span>/<span class=\"totalPages\"></span>"
+        );
+        assert_eq!(
+            options.substitute("Invoice {{invoice}}").unwrap(),
+            "Invoice &lt;A &amp; B&gt;"
+        );
+        assert_eq!(
+            options.substitute("no placeholders").unwrap(),
+            "no placeholders"
+        );
+        assert!(options.substitute("{{customer}}").is_err());
+        assert!(options.substitute("{{invoice").is_err());
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        assert_eq!(
+            escape_html(r#"<a href="x">'&'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+        assert_eq!(escape_html("plain text"), "plain text");
+    }
+
+    #[test]
+    fn margins_are_raised_only_where_shown() {
+        let params = PrintToPdfParams {
+            margin_top: Some(0.2),
+            margin_bottom: Some(1.0),
+            ..Default::default()
+        };
+
+        let header = header_footer(json!({ "header": "{{title}}" }));
+        let raised = header.apply(Some(params.clone())).unwrap().unwrap();
+        assert_eq!(raised.display_header_footer, Some(true));
+        assert_eq!(raised.margin_top, Some(MIN_HEADER_FOOTER_MARGIN_IN));
+        assert_eq!(raised.margin_bottom, Some(1.0));
+        assert_eq!(raised.footer_template.as_deref(), Some("<span></span>"));
+
+        let footer = header_footer(json!({ "footer": "{{pageNumber}}" }));
+        let raised = footer.apply(None).unwrap().unwrap();
+        assert_eq!(raised.margin_top, None);
+        assert_eq!(raised.margin_bottom, Some(MIN_HEADER_FOOTER_MARGIN_IN));
+
+        assert!(HeaderFooter::default().apply(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn raised_margins_must_fit_the_paper() {
+        // 5.75in of margins fit on a 6in label until the footer needs 0.75in of them
+        let setup: PageSetup = serde_json::from_value(json!({
+            "format": "Label4x6",
+            "margin": { "top": "5.5in", "bottom": "0.25in" }
+        }))
+        .unwrap();
+        let params = setup.apply(None).unwrap();
+
+        let footer = header_footer(json!({ "footer": "{{pageNumber}}" }));
+        assert!(footer.validate(params.as_ref()).is_err());
+        let header = header_footer(json!({ "header": "{{pageNumber}}" }));
+        assert!(header.validate(params.as_ref()).is_ok());
+    }
+
+    #[test]
+    fn built_in_placeholders_are_not_variables() {
+        for name in ["title", "date", "url", "pageNumber", "totalPages"] {
+            let options = header_footer(json!({
+                "header": "{{title}}",
+                "variables": { name: "mine" }
+            }));
+            assert!(options.validate(None).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn templates_are_set_in_one_place() {
+        let params = PrintToPdfParams {
+            footer_template: Some("<p>Page</p>".to_string()),
+            ..Default::default()
+        };
+
+        let footer = header_footer(json!({ "footer": "{{pageNumber}}" }));
+        assert!(footer.validate(Some(&params)).is_err());
+
+        // A header alone leaves the footer to printParams
+        let header = header_footer(json!({ "header": "{{pageNumber}}" }));
+        let applied = header.apply(Some(params)).unwrap().unwrap();
+        assert_eq!(applied.footer_template.as_deref(), Some("<p>Page</p>"));
+        assert!(applied.header_template.unwrap().contains("pageNumber"));
+    }
+}
@@ -0,0 +1,96 @@
+use axum::{
+    Json,
+    extract::{Extension, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    browser_pool::PageLoad,
+    cnfg,
+    error::HttpError,
+    html2pdf::{accepts, file_response, resolve_source},
+    render_queue::Priority,
+    resources::ResourcePolicy,
+    screenshot::{ImageFormat, ScreenshotOptions},
+    wait::WaitOptions,
+};
+
+#[derive(Deserialize)]
+pub struct Html2ImageRequest {
+    #[serde(default)]
+    pub blob: String,
+    /// Page to navigate to instead of rendering `blob`
+    pub url: Option<String>,
+    /// Conditions the page has to meet before it is captured
+    #[serde(rename = "waitFor")]
+    pub wait_for: Option<WaitOptions>,
+    /// Which subresources the page may load, and assets served in place of the network
+    #[serde(default)]
+    pub resources: ResourcePolicy,
+    /// Suggested file name, sent back in `Content-Disposition` for raw image responses
+    pub filename: Option<String>,
+    #[serde(flatten)]
+    pub screenshot: ScreenshotOptions,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Html2ImageResponse {
+    pub image_base64: String,
+    pub content_type: &'static str,
+}
+
+pub async fn html2image(
+    State(app_state): State<AppState>,
+    Extension(priority): Extension<Priority>,
+    headers: HeaderMap,
+    Json(payload): Json<Html2ImageRequest>,
+) -> Result<Response, HttpError> {
+    tracing::debug!("Received HTML2IMAGE request");
+
+    let source = resolve_source(&payload.blob, payload.url.as_deref())?;
+    payload
+        .screenshot
+        .validate(cnfg::get().screenshot_max_pixels)?;
+    if let Some(wait_for) = &payload.wait_for {
+        wait_for.validate()?;
+    }
+    payload.resources.validate()?;
+
+    let load = PageLoad {
+        source,
+        wait: payload.wait_for,
+        resources: payload.resources,
+        priority,
+    };
+    let image = app_state
+        .browser_pool
+        .screenshot(&load, &payload.screenshot)
+        .await?;
+
+    let format = payload.screenshot.format;
+    if accepts_image(&headers, format) {
+        let fallback_name = format!("image.{}", format.extension());
+        return Ok(file_response(
+            image,
+            format.mime(),
+            payload.filename.as_deref(),
+            &fallback_name,
+        ));
+    }
+
+    Ok(Json(Html2ImageResponse {
+        image_base64: general_purpose::STANDARD.encode(image),
+        content_type: format.mime(),
+    })
+    .into_response())
+}
+
+/// Whether the client asked for the raw image instead of the JSON envelope.
+fn accepts_image(headers: &HeaderMap, format: ImageFormat) -> bool {
+    accepts(headers, format.mime()) || accepts(headers, "image/*")
+}
@@ -1,38 +1,412 @@
-use axum::{Json, extract::State};
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    body::Body,
+    extract::{Extension, State},
+    http::{HeaderMap, HeaderValue, header},
+    response::{IntoResponse, Response},
+};
 use base64::{Engine as _, engine::general_purpose};
 use chromiumoxide::cdp::browser_protocol::page::PrintToPdfParams;
 use serde::{Deserialize, Serialize};
+use url::Url;
 
-use crate::{AppState, error::HttpError};
+use crate::{
+    AppState,
+    browser_pool::{PageLoad, RenderSource},
+    cnfg,
+    error::{HttpError, RenderError},
+    header_footer::HeaderFooter,
+    outline::OutlineOptions,
+    page_setup::PageSetup,
+    post_process::PostProcess,
+    render_queue::Priority,
+    resources::ResourcePolicy,
+    thumbnails::{Thumbnail, ThumbnailOptions},
+    wait::WaitOptions,
+};
 
 #[derive(Deserialize)]
 pub struct Html2PdfRequest {
+    #[serde(default)]
     pub blob: String,
+    /// Page to navigate to instead of rendering `blob`
+    pub url: Option<String>,
+    #[serde(flatten)]
+    pub options: PdfOptions,
+}
+
+/// Body of `POST /html2pdf`: a render request plus options only a single document supports.
+#[derive(Deserialize)]
+pub struct Html2PdfPayload {
+    #[serde(flatten)]
+    pub request: Html2PdfRequest,
+    /// PNG previews of pages of the generated PDF, returned in the JSON envelope
+    pub thumbnails: Option<ThumbnailOptions>,
+}
+
+/// How a page is turned into a PDF, independent of where its HTML comes from.
+#[derive(Deserialize, Clone, Default)]
+pub struct PdfOptions {
     #[serde(rename = "printParams")]
     pub print_params: Option<PrintToPdfParams>,
+    /// Paper format, orientation and margins with units, translated into `printParams`
+    #[serde(flatten)]
+    pub page_setup: PageSetup,
+    /// Conditions the page has to meet before it is printed
+    #[serde(rename = "waitFor")]
+    pub wait_for: Option<WaitOptions>,
+    /// Suggested file name, sent back in `Content-Disposition` for raw PDF responses
+    pub filename: Option<String>,
+    /// Which subresources the page may load, and assets served in place of the network
+    #[serde(default)]
+    pub resources: ResourcePolicy,
+    /// Running header and footer, replacing Chrome's raw templates in `printParams`
+    #[serde(flatten)]
+    pub header_footer: HeaderFooter,
+    /// Bookmarks and a table of contents from the document's headings
+    pub outline: Option<OutlineOptions>,
+    #[serde(flatten)]
+    pub post_process: PostProcess,
 }
 
 #[derive(Serialize)]
 pub struct Html2PdfResponse {
     #[serde(rename = "pdfBase64")]
     pub pdf_base64: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnails: Option<Vec<Thumbnail>>,
 }
 
 pub async fn html2pdf(
     State(app_state): State<AppState>,
-    Json(payload): Json<Html2PdfRequest>,
-) -> Result<Json<Html2PdfResponse>, HttpError> {
+    Extension(priority): Extension<Priority>,
+    headers: HeaderMap,
+    Json(payload): Json<Html2PdfPayload>,
+) -> Result<Response, HttpError> {
     tracing::debug!("Received HTML2PDF request");
 
-    if payload.blob.is_empty() {
-        return Err(HttpError::BadRequest(anyhow::anyhow!("Empty HTML content")));
+    let Html2PdfPayload {
+        request,
+        thumbnails,
+    } = payload;
+    let source = request.source()?;
+
+    match thumbnails {
+        Some(thumbnails) => {
+            pdf_with_thumbnails(&app_state, source, &request.options, priority, thumbnails).await
+        }
+        None => pdf_reply(&app_state, &headers, source, request.options, priority).await,
+    }
+}
+
+/// Render `source` and rasterize pages of the result. Thumbnails only fit the JSON envelope,
+/// so it is used regardless of what the client accepts.
+async fn pdf_with_thumbnails(
+    app_state: &AppState,
+    source: RenderSource,
+    options: &PdfOptions,
+    priority: Priority,
+    thumbnails: ThumbnailOptions,
+) -> Result<Response, HttpError> {
+    thumbnails.validate()?;
+    if !app_state.thumbnailer.enabled() {
+        return Err(HttpError::BadRequest(anyhow::anyhow!(
+            "Thumbnails are not available on this server"
+        )));
+    }
+
+    let pdf_bytes = render_pdf(app_state, source, options, priority).await?;
+
+    let thumbnailer = Arc::clone(&app_state.thumbnailer);
+    let password = options
+        .post_process
+        .encryption
+        .as_ref()
+        .map(|encryption| encryption.user_password.clone());
+    let (pdf_bytes, thumbnails) = tokio::task::spawn_blocking(move || {
+        let rendered = thumbnailer.render(&pdf_bytes, password.as_deref(), &thumbnails);
+        rendered.map(|thumbnails| (pdf_bytes, thumbnails))
+    })
+    .await??;
+
+    Ok(Json(Html2PdfResponse {
+        pdf_base64: general_purpose::STANDARD.encode(pdf_bytes),
+        thumbnails: Some(thumbnails),
+    })
+    .into_response())
+}
+
+/// Render `source` and answer with either a raw PDF stream or the base64 JSON envelope,
+/// depending on what the client accepts.
+pub async fn pdf_reply(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    source: RenderSource,
+    options: PdfOptions,
+    priority: Priority,
+) -> Result<Response, HttpError> {
+    options.validate()?;
+
+    // Outlines and post-processing need the whole document, so only plain renders are streamed
+    if accepts_pdf(headers) && options.post_process.is_empty() && options.outline.is_none() {
+        let stream = app_state
+            .browser_pool
+            .print_to_pdf_stream(
+                &options.page_load(source, priority),
+                options.print_params()?,
+            )
+            .await?;
+
+        return Ok(pdf_response(
+            Body::from_stream(stream),
+            options.filename.as_deref(),
+        ));
     }
 
+    let pdf_bytes = render_pdf(app_state, source, &options, priority).await?;
+
+    Ok(pdf_bytes_reply(
+        headers,
+        pdf_bytes,
+        options.filename.as_deref(),
+    ))
+}
+
+/// Answer with an already rendered PDF, raw or base64 encoded depending on what the client
+/// accepts.
+pub fn pdf_bytes_reply(
+    headers: &HeaderMap,
+    pdf_bytes: Vec<u8>,
+    filename: Option<&str>,
+) -> Response {
+    if accepts_pdf(headers) {
+        return pdf_response(pdf_bytes, filename);
+    }
+
+    let pdf_base64 = general_purpose::STANDARD.encode(pdf_bytes);
+    Json(Html2PdfResponse {
+        pdf_base64,
+        thumbnails: None,
+    })
+    .into_response()
+}
+
+/// Render `source` into an in-memory PDF and apply the requested post-processing.
+pub async fn render_pdf(
+    app_state: &AppState,
+    source: RenderSource,
+    options: &PdfOptions,
+    priority: Priority,
+) -> Result<Vec<u8>, HttpError> {
+    options.validate()?;
+
     let pdf_bytes = app_state
         .browser_pool
-        .print_to_pdf(&payload.blob, payload.print_params)
+        .print_to_pdf(
+            &options.page_load(source, priority),
+            options.print_params()?,
+            options.outline.as_ref(),
+        )
         .await?;
-    let pdf_base64 = general_purpose::STANDARD.encode(pdf_bytes);
 
-    Ok(Json(Html2PdfResponse { pdf_base64 }))
+    if options.post_process.is_empty() {
+        return Ok(pdf_bytes);
+    }
+
+    let post_process = options.post_process.clone();
+    let pdf_bytes = tokio::task::spawn_blocking(move || post_process.apply(pdf_bytes)).await??;
+
+    Ok(pdf_bytes)
+}
+
+impl PdfOptions {
+    /// Reject options that can never be applied, before anything is rendered.
+    pub fn validate(&self) -> Result<(), RenderError> {
+        if let Some(wait_for) = &self.wait_for {
+            wait_for.validate()?;
+        }
+        self.resources.validate()?;
+        // The header and footer are checked against the margins of the page setup
+        let params = self.page_setup.apply(self.print_params.clone())?;
+        self.header_footer.validate(params.as_ref())?;
+        if let Some(outline) = &self.outline {
+            outline.validate()?;
+        }
+        self.post_process.validate()
+    }
+
+    /// Chrome's print parameters, including the page setup, header and footer.
+    pub fn print_params(&self) -> Result<Option<PrintToPdfParams>, RenderError> {
+        // The header and footer fit themselves into the margins of the page setup
+        let params = self.page_setup.apply(self.print_params.clone())?;
+        self.header_footer.apply(params)
+    }
+
+    pub fn page_load(&self, source: RenderSource, priority: Priority) -> PageLoad {
+        PageLoad {
+            source,
+            wait: self.wait_for.clone(),
+            resources: self.resources.clone(),
+            priority,
+        }
+    }
+}
+
+impl Html2PdfRequest {
+    /// Resolve what to render, checking URLs against the configured allow-list.
+    pub fn source(&self) -> Result<RenderSource, HttpError> {
+        resolve_source(&self.blob, self.url.as_deref())
+    }
+}
+
+/// Turn the `blob`/`url` pair of a request into a [`RenderSource`], checking URLs against the
+/// configured allow-list.
+pub fn resolve_source(blob: &str, url: Option<&str>) -> Result<RenderSource, HttpError> {
+    match (url, blob.is_empty()) {
+        (Some(_), false) => Err(HttpError::BadRequest(anyhow::anyhow!(
+            "Provide either blob or url, not both"
+        ))),
+        (Some(url), true) => {
+            let url = Url::parse(url)
+                .map_err(|e| HttpError::BadRequest(anyhow::anyhow!("Invalid url: {}", e)))?;
+            cnfg::get().url_policy.check(&url)?;
+            Ok(RenderSource::Url(url))
+        }
+        (None, false) => Ok(RenderSource::Html(blob.to_string())),
+        (None, true) => Err(HttpError::BadRequest(anyhow::anyhow!("Empty HTML content"))),
+    }
+}
+
+/// Whether the client asked for a raw `application/pdf` body instead of the JSON envelope.
+pub fn accepts_pdf(headers: &HeaderMap) -> bool {
+    accepts(headers, "application/pdf")
+}
+
+/// Whether any `Accept` header lists `mime`, ignoring media range parameters.
+pub fn accepts(headers: &HeaderMap, mime: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_range| {
+            media_range
+                .split(';')
+                .next()
+                .is_some_and(|range| range.trim().eq_ignore_ascii_case(mime))
+        })
+}
+
+/// Build a raw `application/pdf` response, as an attachment when a file name is given.
+pub fn pdf_response(body: impl Into<Body>, filename: Option<&str>) -> Response {
+    file_response(body, "application/pdf", filename, "document.pdf")
+}
+
+/// Build a raw response of `content_type`, as an attachment when a file name is given.
+/// `fallback_name` is used when nothing of the given file name survives sanitizing.
+pub fn file_response(
+    body: impl Into<Body>,
+    content_type: &'static str,
+    filename: Option<&str>,
+    fallback_name: &str,
+) -> Response {
+    let disposition = match filename {
+        Some(name) => format!(
+            "attachment; filename=\"{}\"",
+            sanitize_filename(name, fallback_name)
+        ),
+        None => "inline".to_string(),
+    };
+
+    let mut response = Response::new(body.into());
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    if let Ok(value) = HeaderValue::from_str(&disposition) {
+        headers.insert(header::CONTENT_DISPOSITION, value);
+    }
+
+    response
+}
+
+/// Keep only characters that are safe inside a quoted `Content-Disposition` file name.
+fn sanitize_filename(name: &str, fallback_name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' | '/' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+
+    if sanitized.trim().is_empty() {
+        fallback_name.to_string()
+    } else {
+        sanitized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Bytes;
+
+    use super::*;
+
+    fn accept(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::ACCEPT, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn pdf_is_accepted_from_any_accept_header() {
+        assert!(accepts_pdf(&accept(&["application/pdf"])));
+        assert!(accepts_pdf(&accept(&["text/html, Application/PDF;q=0.9"])));
+        assert!(accepts_pdf(&accept(&[
+            "application/json",
+            "application/pdf"
+        ])));
+
+        assert!(!accepts_pdf(&HeaderMap::new()));
+        assert!(!accepts_pdf(&accept(&["application/json"])));
+        assert!(!accepts_pdf(&accept(&["application/*", "*/*"])));
+        assert!(!accepts_pdf(&accept(&["application/pdfx"])));
+    }
+
+    #[test]
+    fn file_names_are_safe_to_quote() {
+        assert_eq!(
+            sanitize_filename("report.pdf", "document.pdf"),
+            "report.pdf"
+        );
+        assert_eq!(
+            sanitize_filename("a\"b\\c/d.pdf", "document.pdf"),
+            "a_b_c_d.pdf"
+        );
+        assert_eq!(
+            sanitize_filename("Übersicht\r\n.pdf", "document.pdf"),
+            "_bersicht__.pdf"
+        );
+        assert_eq!(sanitize_filename("  ", "document.pdf"), "document.pdf");
+        assert_eq!(sanitize_filename("", "image.png"), "image.png");
+    }
+
+    #[tokio::test]
+    async fn failed_streams_abort_the_body() {
+        let chunks: Vec<anyhow::Result<Bytes>> = vec![
+            Ok(Bytes::from_static(b"%PDF-1.4")),
+            Err(anyhow::anyhow!("Chrome went away")),
+        ];
+        let response = pdf_response(Body::from_stream(futures::stream::iter(chunks)), None);
+
+        assert!(
+            axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .is_err()
+        );
+    }
 }
@@ -0,0 +1,519 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::{Mutex, mpsc};
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    AppState, Caller,
+    browser_pool::RenderSource,
+    error::{HttpError, RenderError},
+    html2pdf::{PdfOptions, render_pdf},
+    render_queue::Priority,
+};
+
+const WEBHOOK_ATTEMPTS: u32 = 3;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Suggested wait before submitting again to a full queue.
+const QUEUE_FULL_RETRY_AFTER_SECS: u64 = 10;
+
+pub const SIGNATURE_HEADER: &str = "x-html2pdf-signature";
+pub const TIMESTAMP_HEADER: &str = "x-html2pdf-timestamp";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+/// Public view of a job, returned by the API and posted to callback URLs.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobView {
+    pub id: Uuid,
+    pub status: JobStatus,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Time spent waiting for a worker
+    pub queued_ms: Option<i64>,
+    /// Time spent rendering
+    pub render_ms: Option<i64>,
+    pub error: Option<String>,
+    pub pdf_url: Option<String>,
+}
+
+struct Job {
+    /// Caller that submitted the job, the only one who may see it
+    owner: Caller,
+    status: JobStatus,
+    work: Option<(RenderSource, PdfOptions)>,
+    filename: Option<String>,
+    callback_url: Option<Url>,
+    created_at: DateTime<Utc>,
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+    error: Option<String>,
+    pdf: Option<Bytes>,
+}
+
+/// Finished document of a job, ready to be sent to the client.
+pub struct JobPdf {
+    pub bytes: Bytes,
+    pub filename: Option<String>,
+}
+
+/// In-memory job registry plus the channel feeding the render workers.
+///
+/// At most `max_pending` jobs wait for a worker. Finished jobs are kept until `retention` has
+/// passed, then swept; when their PDFs would take more than `max_retained_bytes`, the oldest
+/// are dropped early.
+pub struct JobQueue {
+    jobs: Mutex<HashMap<Uuid, Job>>,
+    sender: mpsc::Sender<Uuid>,
+    receiver: Mutex<mpsc::Receiver<Uuid>>,
+    http_client: reqwest::Client,
+    retention: Duration,
+    max_pending: usize,
+    max_retained_bytes: usize,
+    webhook_secret: Option<String>,
+}
+
+impl JobQueue {
+    pub fn new(
+        retention: Duration,
+        max_pending: usize,
+        max_retained_bytes: usize,
+        webhook_secret: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let max_pending = max_pending.max(1);
+        let (sender, receiver) = mpsc::channel(max_pending);
+
+        // Callbacks go to caller supplied URLs, so never follow redirects somewhere else
+        let http_client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+
+        Ok(Self {
+            jobs: Mutex::new(HashMap::new()),
+            sender,
+            receiver: Mutex::new(receiver),
+            http_client,
+            retention,
+            max_pending,
+            max_retained_bytes,
+            webhook_secret,
+        })
+    }
+
+    pub fn webhooks_enabled(&self) -> bool {
+        self.webhook_secret.is_some()
+    }
+
+    /// Spawn `workers` tasks draining the queue into the browser pool, plus the sweeper that
+    /// drops expired jobs.
+    pub fn start(app_state: &AppState, workers: usize) {
+        for _ in 0..workers.max(1) {
+            let app_state = app_state.clone();
+            tokio::spawn(async move {
+                loop {
+                    let id = app_state.job_queue.receiver.lock().await.recv().await;
+                    match id {
+                        Some(id) => app_state.job_queue.run(&app_state, id).await,
+                        None => break,
+                    }
+                }
+            });
+        }
+
+        let app_state = app_state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                app_state.job_queue.sweep().await;
+            }
+        });
+    }
+
+    /// Queue a render for `owner`, or turn it away when too many jobs are pending.
+    pub async fn submit(
+        &self,
+        owner: Caller,
+        source: RenderSource,
+        options: PdfOptions,
+        callback_url: Option<Url>,
+    ) -> anyhow::Result<JobView> {
+        let id = Uuid::new_v4();
+        let job = Job {
+            owner,
+            status: JobStatus::Queued,
+            filename: options.filename.clone(),
+            work: Some((source, options)),
+            callback_url,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            error: None,
+            pdf: None,
+        };
+        let view = view(id, &job);
+
+        let mut jobs = self.jobs.lock().await;
+        jobs.insert(id, job);
+        if let Err(e) = self.sender.try_send(id) {
+            jobs.remove(&id);
+            return Err(match e {
+                mpsc::error::TrySendError::Full(_) => RenderError::JobQueueFull {
+                    pending: self.max_pending,
+                    retry_after_secs: QUEUE_FULL_RETRY_AFTER_SECS,
+                }
+                .into(),
+                mpsc::error::TrySendError::Closed(_) => anyhow::anyhow!("Job workers stopped"),
+            });
+        }
+
+        Ok(view)
+    }
+
+    /// The job, if it exists and belongs to `caller`.
+    pub async fn get(&self, id: Uuid, caller: &Caller) -> Option<JobView> {
+        let jobs = self.jobs.lock().await;
+        let job = jobs.get(&id).filter(|job| caller.is(&job.owner))?;
+        Some(view(id, job))
+    }
+
+    /// The job's status together with its PDF once it is done, if it belongs to `caller`.
+    pub async fn pdf(&self, id: Uuid, caller: &Caller) -> Option<(JobView, Option<JobPdf>)> {
+        let jobs = self.jobs.lock().await;
+        let job = jobs.get(&id).filter(|job| caller.is(&job.owner))?;
+        let pdf = job.pdf.clone().map(|bytes| JobPdf {
+            bytes,
+            filename: job.filename.clone(),
+        });
+
+        Some((view(id, job), pdf))
+    }
+
+    async fn run(&self, app_state: &AppState, id: Uuid) {
+        let work = {
+            let mut jobs = self.jobs.lock().await;
+            let Some(job) = jobs.get_mut(&id) else {
+                return;
+            };
+            job.status = JobStatus::Running;
+            job.started_at = Some(Utc::now());
+            job.work.take()
+        };
+        let Some((source, options)) = work else {
+            return;
+        };
+
+        tracing::debug!("Rendering job {}", id);
+        // Nobody waits on the response of an async job
+        let result = render_pdf(app_state, source, &options, Priority::Batch).await;
+
+        let (view, callback_url) = {
+            let mut jobs = self.jobs.lock().await;
+            if let Ok(pdf) = &result {
+                drop_oldest_pdfs(&mut jobs, pdf.len(), self.max_retained_bytes);
+            }
+            let Some(job) = jobs.get_mut(&id) else {
+                return;
+            };
+            job.finished_at = Some(Utc::now());
+            match result {
+                Ok(pdf) if pdf.len() > self.max_retained_bytes => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(format!(
+                        "PDF of {} bytes is larger than the {} bytes jobs may keep",
+                        pdf.len(),
+                        self.max_retained_bytes
+                    ));
+                }
+                Ok(pdf) => {
+                    job.status = JobStatus::Done;
+                    job.pdf = Some(Bytes::from(pdf));
+                }
+                Err(err) => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(err.to_string());
+                    if let HttpError::InternalServerError(err) = &err {
+                        tracing::error!("Job {} failed: {}", id, err);
+                    }
+                }
+            }
+            (view(id, job), job.callback_url.clone())
+        };
+
+        // Deliver in the background so retries don't keep the worker from the next job
+        if let Some(callback_url) = callback_url {
+            let job_queue = Arc::clone(&app_state.job_queue);
+            tokio::spawn(async move { job_queue.deliver_webhook(callback_url, &view).await });
+        }
+    }
+
+    async fn deliver_webhook(&self, url: Url, view: &JobView) {
+        let Some(secret) = &self.webhook_secret else {
+            return;
+        };
+        let body = match serde_json::to_vec(view) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!("Failed to serialize webhook for job {}: {}", view.id, e);
+                return;
+            }
+        };
+
+        let timestamp = Utc::now().timestamp().to_string();
+        let signature = sign(secret, &timestamp, &body);
+
+        for attempt in 1..=WEBHOOK_ATTEMPTS {
+            let result = self
+                .http_client
+                .post(url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(TIMESTAMP_HEADER, &timestamp)
+                .header(SIGNATURE_HEADER, &signature)
+                .body(body.clone())
+                .send()
+                .await;
+
+            match result {
+                Ok(response) if response.status().is_success() => return,
+                Ok(response) => tracing::warn!(
+                    "Webhook for job {} got status {} (attempt {})",
+                    view.id,
+                    response.status(),
+                    attempt
+                ),
+                Err(e) => tracing::warn!(
+                    "Webhook for job {} failed: {} (attempt {})",
+                    view.id,
+                    e,
+                    attempt
+                ),
+            }
+
+            if attempt < WEBHOOK_ATTEMPTS {
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+            }
+        }
+
+        tracing::error!("Giving up on webhook for job {}", view.id);
+    }
+
+    async fn sweep(&self) {
+        let now = Utc::now();
+        let retention = chrono::Duration::from_std(self.retention).unwrap_or(chrono::Duration::MAX);
+
+        self.jobs.lock().await.retain(|_, job| {
+            job.finished_at
+                .is_none_or(|finished_at| now - finished_at < retention)
+        });
+    }
+}
+
+/// Drop the jobs with the oldest PDFs until `incoming` more bytes fit into `max_bytes`. Dropped
+/// jobs are gone as if their retention had passed.
+fn drop_oldest_pdfs(jobs: &mut HashMap<Uuid, Job>, incoming: usize, max_bytes: usize) {
+    if incoming > max_bytes {
+        return;
+    }
+
+    let mut retained: Vec<_> = jobs
+        .iter()
+        .filter_map(|(id, job)| Some((job.finished_at?, *id, job.pdf.as_ref()?.len())))
+        .collect();
+    let mut total: usize = retained.iter().map(|(_, _, size)| size).sum();
+    retained.sort_by_key(|(finished_at, _, _)| *finished_at);
+
+    for (_, id, size) in retained {
+        if total + incoming <= max_bytes {
+            break;
+        }
+        tracing::debug!("Dropping job {} to make room for a new PDF", id);
+        jobs.remove(&id);
+        total -= size;
+    }
+}
+
+/// Signature of a webhook: hex encoded HMAC-SHA256 over `"{timestamp}.{body}"`, prefixed
+/// with `sha256=`.
+pub fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn view(id: Uuid, job: &Job) -> JobView {
+    JobView {
+        id,
+        status: job.status,
+        created_at: job.created_at,
+        started_at: job.started_at,
+        finished_at: job.finished_at,
+        queued_ms: job
+            .started_at
+            .map(|started_at| (started_at - job.created_at).num_milliseconds()),
+        render_ms: job
+            .started_at
+            .zip(job.finished_at)
+            .map(|(started_at, finished_at)| (finished_at - started_at).num_milliseconds()),
+        error: job.error.clone(),
+        pdf_url: (job.status == JobStatus::Done).then(|| format!("/jobs/{}/pdf", id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, http::HeaderMap, routing::post};
+    use hmac::{Hmac, Mac};
+
+    use super::*;
+
+    const SECRET: &str = "webhook-test-secret";
+
+    fn queue(max_pending: usize) -> JobQueue {
+        JobQueue::new(
+            Duration::from_secs(60),
+            max_pending,
+            1024,
+            Some(SECRET.to_string()),
+        )
+        .unwrap()
+    }
+
+    fn caller(name: &str) -> Caller {
+        Caller(Some(name.to_string()))
+    }
+
+    async fn submit(queue: &JobQueue, owner: &str) -> anyhow::Result<JobView> {
+        queue
+            .submit(
+                caller(owner),
+                RenderSource::Html("<p>report</p>".to_string()),
+                PdfOptions::default(),
+                None,
+            )
+            .await
+    }
+
+    fn finished_job(minutes_ago: i64, pdf_size: usize) -> Job {
+        let finished_at = Utc::now() - chrono::Duration::minutes(minutes_ago);
+        Job {
+            owner: caller("acme"),
+            status: JobStatus::Done,
+            work: None,
+            filename: None,
+            callback_url: None,
+            created_at: finished_at,
+            started_at: Some(finished_at),
+            finished_at: Some(finished_at),
+            error: None,
+            pdf: Some(Bytes::from(vec![0; pdf_size])),
+        }
+    }
+
+    #[tokio::test]
+    async fn webhook_is_delivered_with_a_valid_signature() {
+        let (tx, mut rx) = mpsc::channel::<(HeaderMap, Bytes)>(1);
+        let app = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: Bytes| async move {
+                tx.send((headers, body)).await.unwrap();
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let queue = queue(1);
+        let job = submit(&queue, "acme").await.unwrap();
+        queue.deliver_webhook(url, &job).await;
+
+        let (headers, body) = rx.recv().await.unwrap();
+        let timestamp = headers[TIMESTAMP_HEADER].to_str().unwrap();
+        let signature = headers[SIGNATURE_HEADER].to_str().unwrap();
+        let signature = hex::decode(signature.strip_prefix("sha256=").unwrap()).unwrap();
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(&body);
+        mac.verify_slice(&signature).unwrap();
+
+        let delivered: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(delivered["id"], job.id.to_string());
+        assert_eq!(delivered["status"], "queued");
+    }
+
+    #[tokio::test]
+    async fn full_queue_rejects_jobs() {
+        let queue = queue(1);
+
+        submit(&queue, "acme").await.unwrap();
+        let rejected = submit(&queue, "acme").await.unwrap_err();
+
+        assert!(matches!(
+            HttpError::from(rejected),
+            HttpError::TooManyRequests(_)
+        ));
+        assert_eq!(queue.jobs.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn jobs_are_only_visible_to_their_owner() {
+        let queue = queue(1);
+        let job = submit(&queue, "acme").await.unwrap();
+
+        assert!(queue.get(job.id, &caller("acme")).await.is_some());
+        assert!(queue.get(job.id, &caller("globex")).await.is_none());
+        assert!(queue.pdf(job.id, &caller("globex")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn callers_without_an_id_do_not_share_jobs() {
+        let queue = queue(1);
+        let id = Uuid::new_v4();
+        let job = Job {
+            owner: Caller(None),
+            ..finished_job(0, 100)
+        };
+        queue.jobs.lock().await.insert(id, job);
+
+        assert!(queue.get(id, &Caller(None)).await.is_none());
+        assert!(queue.pdf(id, &Caller(None)).await.is_none());
+    }
+
+    #[test]
+    fn oldest_pdfs_make_room_for_new_ones() {
+        let (oldest, older, recent) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut jobs = HashMap::from([
+            (oldest, finished_job(30, 400)),
+            (older, finished_job(20, 400)),
+            (recent, finished_job(10, 100)),
+        ]);
+
+        drop_oldest_pdfs(&mut jobs, 300, 1000);
+        assert!(!jobs.contains_key(&oldest));
+        assert!(jobs.contains_key(&older) && jobs.contains_key(&recent));
+
+        // A PDF that can never fit is not worth dropping anything for
+        drop_oldest_pdfs(&mut jobs, 2000, 1000);
+        assert_eq!(jobs.len(), 2);
+    }
+}
@@ -0,0 +1,109 @@
+use axum::{
+    Json,
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::Response,
+};
+use serde::Deserialize;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    AppState, Caller, cnfg,
+    error::HttpError,
+    html2pdf::{Html2PdfRequest, pdf_response},
+    job_queue::{JobStatus, JobView},
+};
+
+#[derive(Deserialize)]
+pub struct CreateJobRequest {
+    #[serde(flatten)]
+    pub request: Html2PdfRequest,
+    /// Receives a signed `JobView` once the job is done or failed
+    #[serde(rename = "callbackUrl")]
+    pub callback_url: Option<String>,
+}
+
+pub async fn create_job(
+    State(app_state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(payload): Json<CreateJobRequest>,
+) -> Result<(StatusCode, Json<JobView>), HttpError> {
+    // A job nobody can be told apart from would be visible to nobody
+    if caller.0.is_none() {
+        return Err(HttpError::Forbidden(anyhow::anyhow!(
+            "Jobs need a token that identifies the caller"
+        )));
+    }
+
+    let source = payload.request.source()?;
+    payload.request.options.validate()?;
+
+    let callback_url = match &payload.callback_url {
+        Some(callback_url) => {
+            if !app_state.job_queue.webhooks_enabled() {
+                return Err(HttpError::BadRequest(anyhow::anyhow!(
+                    "Callbacks are not configured on this server"
+                )));
+            }
+            let url = Url::parse(callback_url).map_err(|e| {
+                HttpError::BadRequest(anyhow::anyhow!("Invalid callbackUrl: {}", e))
+            })?;
+            cnfg::get().callback_policy.check(&url)?;
+            Some(url)
+        }
+        None => None,
+    };
+
+    let job = app_state
+        .job_queue
+        .submit(caller, source, payload.request.options, callback_url)
+        .await?;
+
+    tracing::debug!("Queued job {}", job.id);
+
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+pub async fn get_job(
+    State(app_state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<JobView>, HttpError> {
+    // Jobs of other callers are reported as missing, so their ids can not be probed
+    app_state
+        .job_queue
+        .get(id, &caller)
+        .await
+        .map(Json)
+        .ok_or_else(|| job_not_found(id))
+}
+
+pub async fn get_job_pdf(
+    State(app_state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<Uuid>,
+) -> Result<Response, HttpError> {
+    let (job, pdf) = app_state
+        .job_queue
+        .pdf(id, &caller)
+        .await
+        .ok_or_else(|| job_not_found(id))?;
+
+    match (job.status, pdf) {
+        (JobStatus::Done, Some(pdf)) => Ok(pdf_response(pdf.bytes, pdf.filename.as_deref())),
+        (JobStatus::Failed, _) => Err(HttpError::Conflict(anyhow::anyhow!(
+            "Job {} failed: {}",
+            id,
+            job.error.unwrap_or_default()
+        ))),
+        _ => Err(HttpError::Conflict(anyhow::anyhow!(
+            "Job {} is not done yet",
+            id
+        ))),
+    }
+}
+
+fn job_not_found(id: Uuid) -> HttpError {
+    HttpError::NotFound(anyhow::anyhow!("Job {} not found", id))
+}
@@ -1,28 +1,66 @@
+mod batch;
 mod browser_pool;
+mod browser_shard;
+mod bundle;
 mod cnfg;
 mod error;
+mod font_store;
+mod fonts;
+mod header_footer;
+mod html2image;
 mod html2pdf;
+mod job_queue;
+mod jobs;
+mod merge;
+mod outline;
+mod page_setup;
+mod pdf_tools;
+mod pdfa;
+mod post_process;
+mod render_queue;
+mod resources;
+mod screenshot;
+mod template_helpers;
+mod template_store;
+mod templates;
+mod thumbnails;
+mod url_policy;
+mod wait;
+mod watermark;
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
-use axum::extract::{Request, State};
-use axum::http::Method;
+use axum::extract::{DefaultBodyLimit, Request, State};
+use axum::http::{Method, StatusCode};
 use axum::middleware;
-use axum::response::Response;
+use axum::response::{IntoResponse, Response};
 use axum::{
-    Router,
+    Json, Router,
     routing::{get, post},
 };
 use tower_http::cors::{Any, CorsLayer};
 
-use auth_sdk::{TokenValidationConfig, TokenValidator};
+use auth_sdk::{Claims, TokenValidationConfig, TokenValidationResult, TokenValidator, User};
+use batch::html2pdf_batch;
 use browser_pool::BrowserPool;
+use browser_shard::BrowserState;
+use bundle::html2pdf_bundle;
+use font_store::FontStore;
+use fonts::{list_fonts, upload_font};
+use html2image::html2image;
 use html2pdf::html2pdf;
+use job_queue::JobQueue;
+use jobs::{create_job, get_job, get_job_pdf};
+use merge::html2pdf_merge;
+use render_queue::Priority;
+use template_store::TemplateStore;
+use templates::{create_template, get_template, list_templates, render_template};
+use thumbnails::Thumbnailer;
 
 async fn auth_middleware(
     State(app_state): State<AppState>,
-    request: Request,
+    mut request: Request,
     next: middleware::Next,
 ) -> Response {
     let auth_header = request.headers().get("Authorization");
@@ -46,23 +84,94 @@ async fn auth_middleware(
 
     let token = token.unwrap();
     match token_validator.validate_token(&token).await {
-        Ok(_) => {
-            // Your middleware logic here
+        Ok(TokenValidationResult::Valid { claims }) => {
+            request.extensions_mut().insert(token_priority(&claims));
+            request.extensions_mut().insert(admin_access(&claims));
+            request.extensions_mut().insert(caller(&claims));
             next.run(request).await
         }
-        Err(e) => {
-            return Response::builder()
-                .status(axum::http::StatusCode::UNAUTHORIZED)
-                .body(format!("Token validation failed: {}", e).into())
-                .unwrap();
-        }
+        Ok(TokenValidationResult::Expired) => Response::builder()
+            .status(axum::http::StatusCode::UNAUTHORIZED)
+            .body("Token expired".into())
+            .unwrap(),
+        Ok(TokenValidationResult::Invalid { reason }) => Response::builder()
+            .status(axum::http::StatusCode::UNAUTHORIZED)
+            .body(format!("Invalid token: {}", reason).into())
+            .unwrap(),
+        Ok(TokenValidationResult::UnknownIssuer { issuer }) => Response::builder()
+            .status(axum::http::StatusCode::UNAUTHORIZED)
+            .body(format!("Unknown token issuer: {}", issuer).into())
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(axum::http::StatusCode::UNAUTHORIZED)
+            .body(format!("Token validation failed: {}", e).into())
+            .unwrap(),
+    }
+}
+
+/// Queue lane for the caller's renders; tenants listed in `BATCH_PRIORITY_TENANTS` wait behind
+/// interactive renders.
+fn token_priority(claims: &Claims) -> Priority {
+    let tenants = &cnfg::get().batch_priority_tenants;
+    let is_batch = [&claims.customer_id, &claims.azp]
+        .into_iter()
+        .flatten()
+        .any(|tenant| tenants.contains(&tenant.to_lowercase()));
+
+    if is_batch {
+        Priority::Batch
+    } else {
+        Priority::Interactive
+    }
+}
+
+/// Whether the caller holds one of the `ADMIN_ROLES`, which manage what every tenant renders
+/// with.
+#[derive(Clone, Copy)]
+struct AdminAccess(bool);
+
+fn admin_access(claims: &Claims) -> AdminAccess {
+    let admin_roles = &cnfg::get().admin_roles;
+    let is_admin = User::from_claims(claims)
+        .roles
+        .iter()
+        .any(|role| admin_roles.contains(&role.to_lowercase()));
+
+    AdminAccess(is_admin)
+}
+
+/// Who is calling, by customer id, user id or subject, whichever the token has first. Jobs are
+/// only visible to the caller that created them.
+#[derive(Clone)]
+struct Caller(Option<String>);
+
+impl Caller {
+    /// Whether this is the caller `owner` stands for. A caller without an id is nobody, so it
+    /// never matches, not even another caller without an id.
+    fn is(&self, owner: &Caller) -> bool {
+        matches!((&self.0, &owner.0), (Some(id), Some(owner)) if id == owner)
     }
 }
 
+fn caller(claims: &Claims) -> Caller {
+    // Same precedence as `User::from_claims`, behind the tenant
+    Caller(
+        [&claims.customer_id, &claims.user_id, &claims.sub]
+            .into_iter()
+            .flatten()
+            .find(|id| !id.is_empty())
+            .cloned(),
+    )
+}
+
 #[derive(Clone)]
 struct AppState {
     browser_pool: Arc<BrowserPool>,
     token_validator: Arc<TokenValidator>,
+    template_store: Arc<TemplateStore>,
+    font_store: Arc<FontStore>,
+    job_queue: Arc<JobQueue>,
+    thumbnailer: Arc<Thumbnailer>,
 }
 
 #[tokio::main]
@@ -70,7 +179,10 @@ async fn main() -> Result<()> {
     let config = cnfg::get();
     tracing_subscriber::fmt::init();
 
-    let browser_pool = Arc::new(BrowserPool::new().await?);
+    let font_store = Arc::new(FontStore::new(config.fonts_dir.clone()));
+    font_store.load().await?;
+
+    let browser_pool = BrowserPool::new(Arc::clone(&font_store)).await?;
     let mut token_validator_config =
         TokenValidationConfig::new().with_ship_key(config.ship_key.clone());
 
@@ -81,10 +193,21 @@ async fn main() -> Result<()> {
     let token_validator = TokenValidator::new(token_validator_config);
 
     let app_state = AppState {
-        browser_pool: browser_pool,
+        browser_pool,
         token_validator: Arc::new(token_validator),
+        template_store: Arc::new(TemplateStore::new(config.templates_dir.clone())),
+        font_store,
+        job_queue: Arc::new(JobQueue::new(
+            Duration::from_secs(config.job_retention_secs),
+            config.job_max_pending,
+            config.job_max_retained_bytes,
+            config.webhook_secret.clone(),
+        )?),
+        thumbnailer: Arc::new(Thumbnailer::new(config.pdfium_library_path.as_deref())),
     };
 
+    JobQueue::start(&app_state, config.job_workers);
+
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
@@ -93,16 +216,42 @@ async fn main() -> Result<()> {
 
     let protected_routes = Router::new()
         .route("/html2pdf", post(html2pdf))
+        .route(
+            "/html2pdf/batch",
+            post(html2pdf_batch).layer(DefaultBodyLimit::max(config.batch_body_limit_bytes)),
+        )
+        .route(
+            "/html2pdf/bundle",
+            post(html2pdf_bundle).layer(DefaultBodyLimit::max(config.bundle_max_bytes)),
+        )
+        .route(
+            "/html2pdf/merge",
+            post(html2pdf_merge).layer(DefaultBodyLimit::max(config.batch_body_limit_bytes)),
+        )
+        .route("/html2image", post(html2image))
+        .route("/jobs", post(create_job))
+        .route("/jobs/{id}", get(get_job))
+        .route("/jobs/{id}/pdf", get(get_job_pdf))
+        .route("/templates", get(list_templates))
+        .route("/templates/{name}", get(get_template).post(create_template))
+        .route("/templates/{name}/render", post(render_template))
+        .route("/fonts", get(list_fonts))
+        .route(
+            "/fonts/{family}",
+            // Fonts arrive base64 encoded inside JSON
+            post(upload_font).layer(DefaultBodyLimit::max(config.font_max_bytes * 2)),
+        )
         .layer(middleware::from_fn_with_state(
             app_state.clone(),
             auth_middleware,
         ))
-        .with_state(app_state);
+        .with_state(app_state.clone());
 
     let app = Router::new()
         .merge(protected_routes)
         .route("/healthz", get(healthz))
-        .layer(cors);
+        .layer(cors)
+        .with_state(app_state);
 
     let port = config.port;
     let addr = format!("0.0.0.0:{}", port);
@@ -114,6 +263,26 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn healthz() -> &'static str {
-    "Pong"
+/// Reports the status of every browser; unhealthy only while none of them can render.
+async fn healthz(State(app_state): State<AppState>) -> Response {
+    let browsers = app_state.browser_pool.health();
+    let running = browsers
+        .iter()
+        .filter(|browser| browser.state == BrowserState::Running)
+        .count();
+    let (status, code) = match running {
+        0 => ("unavailable", StatusCode::SERVICE_UNAVAILABLE),
+        running if running < browsers.len() => ("degraded", StatusCode::OK),
+        _ => ("ok", StatusCode::OK),
+    };
+
+    (
+        code,
+        Json(serde_json::json!({
+            "status": status,
+            "browsers": browsers,
+            "queue": app_state.browser_pool.queue_stats(),
+        })),
+    )
+        .into_response()
 }
@@ -0,0 +1,111 @@
+use axum::{
+    Json,
+    extract::{Extension, State},
+    http::HeaderMap,
+    response::Response,
+};
+use futures::StreamExt;
+use serde::Deserialize;
+
+use crate::{
+    AppState, cnfg,
+    error::{HttpError, RenderError},
+    html2pdf::{Html2PdfRequest, pdf_bytes_reply, render_pdf},
+    pdf_tools::{self, Section},
+    post_process::PostProcess,
+    render_queue::Priority,
+};
+
+#[derive(Deserialize)]
+pub struct MergeRequest {
+    /// Rendered and concatenated in this order
+    pub sections: Vec<MergeSection>,
+    pub filename: Option<String>,
+    /// Applied to the merged document
+    #[serde(flatten)]
+    pub post_process: PostProcess,
+}
+
+#[derive(Deserialize)]
+pub struct MergeSection {
+    /// Bookmark title of the section, defaults to `Section {n}`
+    pub title: Option<String>,
+    #[serde(flatten)]
+    pub request: Html2PdfRequest,
+}
+
+pub async fn html2pdf_merge(
+    State(app_state): State<AppState>,
+    Extension(priority): Extension<Priority>,
+    headers: HeaderMap,
+    Json(payload): Json<MergeRequest>,
+) -> Result<Response, HttpError> {
+    tracing::debug!("Received merge of {} sections", payload.sections.len());
+
+    if payload.sections.is_empty() {
+        return Err(HttpError::BadRequest(anyhow::anyhow!(
+            "No sections to merge"
+        )));
+    }
+    let max_items = cnfg::get().batch_max_items;
+    if payload.sections.len() > max_items {
+        return Err(HttpError::BadRequest(anyhow::anyhow!(
+            "Merge has {} sections, at most {} are allowed",
+            payload.sections.len(),
+            max_items
+        )));
+    }
+
+    payload.post_process.validate()?;
+    // An encrypted section could not be read back for merging, and the PDF/A parts of a
+    // section's catalog would be dropped by it
+    if let Some(index) = payload.sections.iter().position(|section| {
+        let post_process = &section.request.options.post_process;
+        post_process.encryption.is_some() || post_process.conformance.is_some()
+    }) {
+        return Err(HttpError::from(RenderError::InvalidPdfOptions(
+            "encryption and conformance are only supported on the merged document".to_string(),
+        ))
+        .context(format!("Section {}", index)));
+    }
+
+    // Sections are rendered concurrently up to the pool's limit, the first failure aborts
+    let concurrency = app_state.browser_pool.concurrency_limit();
+    let sections: Vec<Section> = futures::stream::iter(payload.sections.into_iter().enumerate())
+        .map(|(index, section)| {
+            let app_state = app_state.clone();
+            async move {
+                render_section(&app_state, index, section, priority)
+                    .await
+                    .map_err(|err| err.context(format!("Section {}", index)))
+            }
+        })
+        .buffered(concurrency)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+
+    let post_process = payload.post_process;
+    let pdf = tokio::task::spawn_blocking(move || post_process.apply(pdf_tools::merge(sections)?))
+        .await??;
+
+    Ok(pdf_bytes_reply(&headers, pdf, payload.filename.as_deref()))
+}
+
+async fn render_section(
+    app_state: &AppState,
+    index: usize,
+    section: MergeSection,
+    priority: Priority,
+) -> Result<Section, HttpError> {
+    let source = section.request.source()?;
+    let pdf = render_pdf(app_state, source, &section.request.options, priority).await?;
+
+    Ok(Section {
+        title: section
+            .title
+            .unwrap_or_else(|| format!("Section {}", index + 1)),
+        pdf,
+    })
+}
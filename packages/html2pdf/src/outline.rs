@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chromiumoxide::Page;
+use lopdf::{Bookmark, Object, ObjectId};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::RenderError, pdf_tools};
+
+/// Width of the page number placeholders of the first pass, so filling in the real numbers does
+/// not move anything.
+const PAGE_PLACEHOLDER: &str = "000";
+const DEFAULT_TOC_TITLE: &str = "Contents";
+
+/// Bookmarks and an optional table of contents built from the document's headings.
+///
+/// `h1` to `h{maxLevel}` are collected, together with any element carrying a `data-outline`
+/// attribute, whose value is used as title when it is not empty. `data-outline-level` overrides
+/// the level and `data-outline="false"` leaves a heading out.
+///
+/// The table of contents goes into the element marked `data-toc`, or onto its own page in front
+/// of the document. Its page numbers need a second print, after the first one showed where every
+/// heading ended up.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutlineOptions {
+    #[serde(default = "default_max_level")]
+    pub max_level: u8,
+    /// Render a table of contents page
+    #[serde(default)]
+    pub toc: bool,
+    pub toc_title: Option<String>,
+}
+
+fn default_max_level() -> u8 {
+    6
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PrepareOptions<'a> {
+    max_level: u8,
+    toc: bool,
+    toc_title: &'a str,
+    placeholder: &'a str,
+}
+
+/// A heading of the page, marked with an anchor Chrome turns into a named destination.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Heading {
+    /// Id of the anchor in front of the heading
+    pub id: String,
+    pub level: u8,
+    pub title: String,
+}
+
+/// The headings of a page prepared for printing.
+pub struct PageOutline {
+    pub headings: Vec<Heading>,
+    pub toc: bool,
+}
+
+impl OutlineOptions {
+    pub fn validate(&self) -> Result<(), RenderError> {
+        if !(1..=6).contains(&self.max_level) {
+            return Err(RenderError::InvalidPdfOptions(
+                "outline.maxLevel must be between 1 and 6".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Mark the headings of a loaded page and link to them, from the table of contents or from
+    /// hidden links. Chrome only writes destinations for elements that are linked to.
+    pub async fn prepare(&self, page: &Page) -> Result<PageOutline> {
+        let options = PrepareOptions {
+            max_level: self.max_level,
+            toc: self.toc,
+            toc_title: self.toc_title.as_deref().unwrap_or(DEFAULT_TOC_TITLE),
+            placeholder: PAGE_PLACEHOLDER,
+        };
+        let js = format!("({})({})", PREPARE_JS, serde_json::to_string(&options)?);
+        let headings = page.evaluate_expression(js).await?.into_value()?;
+
+        Ok(PageOutline {
+            headings,
+            toc: self.toc,
+        })
+    }
+}
+
+impl PageOutline {
+    /// Write the pages of the headings into the table of contents.
+    pub async fn fill_toc(&self, page: &Page, pages: &HashMap<String, u32>) -> Result<()> {
+        let js = format!("({})({})", FILL_TOC_JS, serde_json::to_string(pages)?);
+        page.evaluate_expression(js).await?;
+        Ok(())
+    }
+}
+
+/// 1-based page number of every heading found in `pdf`.
+pub fn heading_pages(pdf: &[u8], headings: &[Heading]) -> Result<HashMap<String, u32>> {
+    let document = pdf_tools::load(pdf)?;
+    let page_numbers: HashMap<ObjectId, u32> = document
+        .get_pages()
+        .into_iter()
+        .map(|(number, id)| (id, number))
+        .collect();
+
+    Ok(heading_page_ids(&document, headings)
+        .into_iter()
+        .filter_map(|(id, page_id)| Some((id, *page_numbers.get(&page_id)?)))
+        .collect())
+}
+
+/// Replace the outline of `pdf` by one bookmark per heading, nested by level.
+pub fn add_outline(pdf: Vec<u8>, headings: &[Heading]) -> Result<Vec<u8>> {
+    let mut document = pdf_tools::load(&pdf)?;
+    let page_ids = heading_page_ids(&document, headings);
+
+    // Open bookmarks by level; a heading nests under the closest one of a lower level
+    let mut open: Vec<(u8, u32)> = Vec::new();
+    for heading in headings {
+        let Some(page_id) = page_ids.get(&heading.id) else {
+            continue;
+        };
+        while open
+            .last()
+            .is_some_and(|(level, _)| *level >= heading.level)
+        {
+            open.pop();
+        }
+
+        let parent = open.last().map(|(_, bookmark)| *bookmark);
+        let bookmark = document.add_bookmark(
+            Bookmark::new(heading.title.clone(), [0.0, 0.0, 0.0], 0, *page_id),
+            parent,
+        );
+        open.push((heading.level, bookmark));
+    }
+
+    let Some(outline_id) = document.build_outline() else {
+        return Ok(pdf);
+    };
+    let catalog = document.catalog_mut()?;
+    catalog.set("Outlines", outline_id);
+    catalog.set("PageMode", "UseOutlines");
+
+    pdf_tools::save(document)
+}
+
+/// Page object each heading's destination points to.
+///
+/// Headings Chrome wrote no destination for are left out, so they get no bookmark and no page
+/// number in the table of contents.
+fn heading_page_ids(document: &lopdf::Document, headings: &[Heading]) -> HashMap<String, ObjectId> {
+    let destinations = pdf_tools::named_destinations(document);
+
+    let page_ids: HashMap<_, _> = headings
+        .iter()
+        .filter_map(|heading| {
+            let destination = destinations.get(heading.id.as_bytes())?;
+            match destination.first()? {
+                Object::Reference(page_id) => Some((heading.id.clone(), *page_id)),
+                _ => None,
+            }
+        })
+        .collect();
+
+    if page_ids.len() < headings.len() {
+        tracing::warn!(
+            "Only {} of {} headings have a destination in the printed PDF",
+            page_ids.len(),
+            headings.len()
+        );
+    }
+
+    page_ids
+}
+
+const PREPARE_JS: &str = r#"(options) => {
+    const selector = [];
+    for (let level = 1; level <= options.maxLevel; level++) selector.push('h' + level);
+    selector.push('[data-outline]');
+
+    const headings = [];
+    for (const element of document.querySelectorAll(selector.join(','))) {
+        const marker = element.getAttribute('data-outline');
+        if (marker === 'false') continue;
+        // Hidden elements are not printed, so Chrome writes no destination for them
+        if (element.getClientRects().length === 0) continue;
+
+        const heading = /^H[1-6]$/.test(element.tagName) ? parseInt(element.tagName[1], 10) : 1;
+        const level = parseInt(element.getAttribute('data-outline-level'), 10) || heading;
+        const title = (marker || element.textContent).replace(/\s+/g, ' ').trim();
+        if (!title) continue;
+
+        const anchor = document.createElement('span');
+        anchor.id = 'html2pdf-outline-' + headings.length;
+        anchor.style.cssText = 'display: inline-block; width: 0; height: 0;';
+        element.prepend(anchor);
+        headings.push({ id: anchor.id, level: Math.min(Math.max(level, 1), 6), title });
+    }
+
+    const links = document.createElement(options.toc ? 'nav' : 'div');
+    if (options.toc) {
+        const style = document.createElement('style');
+        style.textContent = '.html2pdf-toc ol { list-style: none; margin: 0; padding: 0; }'
+            + ' .html2pdf-toc-title { font-size: 1.5em; font-weight: bold; margin-bottom: 1em; }'
+            + ' .html2pdf-toc a { display: flex; color: inherit; text-decoration: none; }'
+            + ' .html2pdf-toc-text { flex: 1; }'
+            + ' .html2pdf-toc-page { display: inline-block; min-width: 3ch; text-align: right; }';
+        // First in the head, so the document's own styles win
+        document.head.prepend(style);
+
+        links.className = 'html2pdf-toc';
+        const title = document.createElement('div');
+        title.className = 'html2pdf-toc-title';
+        title.textContent = options.tocTitle;
+        links.append(title);
+    } else {
+        // Laid out but invisible, Chrome skips destinations of links without a box
+        links.style.cssText = 'position: absolute; width: 0; height: 0; overflow: hidden;';
+    }
+
+    const list = document.createElement('ol');
+    for (const heading of headings) {
+        const item = document.createElement('li');
+        item.className = 'html2pdf-toc-level-' + heading.level;
+        item.style.paddingLeft = (heading.level - 1) * 1.5 + 'em';
+        const link = document.createElement('a');
+        link.href = '#' + heading.id;
+        const text = document.createElement('span');
+        text.className = 'html2pdf-toc-text';
+        text.textContent = heading.title;
+        const page = document.createElement('span');
+        page.className = 'html2pdf-toc-page';
+        page.dataset.tocPage = heading.id;
+        page.textContent = options.placeholder;
+        link.append(text, page);
+        item.append(link);
+        list.append(item);
+    }
+    links.append(list);
+
+    const target = document.querySelector('[data-toc]');
+    if (options.toc && target) {
+        target.append(links);
+    } else if (options.toc) {
+        links.style.breakAfter = 'page';
+        document.body.prepend(links);
+    } else {
+        document.body.append(links);
+    }
+
+    return headings;
+}"#;
+
+const FILL_TOC_JS: &str = r#"(pages) => {
+    for (const element of document.querySelectorAll('[data-toc-page]')) {
+        const page = pages[element.dataset.tocPage];
+        element.textContent = page === undefined ? '' : String(page);
+    }
+    return true;
+}"#;
+
+#[cfg(test)]
+mod tests {
+    use lopdf::{Dictionary, Document, dictionary};
+
+    use super::*;
+
+    /// A PDF of `pages` empty pages with a named destination `dest-N` on page N, the way Chrome
+    /// writes them for link targets.
+    fn fixture(pages: u32) -> Vec<u8> {
+        let mut document = Document::with_version("1.7");
+        let pages_id = document.new_object_id();
+
+        let mut kids = Vec::new();
+        let mut dests = Dictionary::new();
+        for number in 1..=pages {
+            let page_id = document.add_object(dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            });
+            kids.push(page_id.into());
+            dests.set(
+                format!("dest-{}", number),
+                vec![
+                    page_id.into(),
+                    "XYZ".into(),
+                    0.into(),
+                    800.into(),
+                    Object::Null,
+                ],
+            );
+        }
+
+        document.objects.insert(
+            pages_id,
+            dictionary! {
+                "Type" => "Pages",
+                "Count" => pages,
+                "Kids" => kids,
+            }
+            .into(),
+        );
+        let dests_id = document.add_object(dests);
+        let catalog_id = document.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+            "Dests" => dests_id,
+        });
+        document.trailer.set("Root", catalog_id);
+
+        pdf_tools::save(document).unwrap()
+    }
+
+    fn heading(page: u32, level: u8, title: &str) -> Heading {
+        Heading {
+            id: format!("dest-{}", page),
+            level,
+            title: title.to_string(),
+        }
+    }
+
+    /// The bookmarks from `first` on, children in brackets after their parent.
+    fn tree(document: &Document, first: Option<ObjectId>) -> String {
+        let mut entries = Vec::new();
+        let mut next = first;
+        while let Some(id) = next {
+            let item = document.get_dictionary(id).unwrap();
+            let title = lopdf::decode_text_string(item.get(b"Title").unwrap()).unwrap();
+            let children = item.get(b"First").and_then(Object::as_reference).ok();
+            entries.push(match children {
+                Some(_) => format!("{} ({})", title, tree(document, children)),
+                None => title,
+            });
+            next = item.get(b"Next").and_then(Object::as_reference).ok();
+        }
+        entries.join(", ")
+    }
+
+    #[test]
+    fn bookmarks_nest_by_level() {
+        let headings = [
+            heading(1, 1, "Report"),
+            heading(2, 2, "Summary"),
+            heading(3, 3, "Revenue"),
+            heading(4, 2, "Details"),
+            heading(5, 1, "Appendix"),
+        ];
+
+        let pdf = add_outline(fixture(5), &headings).unwrap();
+        let document = pdf_tools::load(&pdf).unwrap();
+        let catalog = document.catalog().unwrap();
+        assert_eq!(
+            catalog.get(b"PageMode").unwrap().as_name().unwrap(),
+            b"UseOutlines"
+        );
+
+        let outlines = document
+            .get_dictionary(catalog.get(b"Outlines").unwrap().as_reference().unwrap())
+            .unwrap();
+        let first = outlines.get(b"First").unwrap().as_reference().ok();
+        assert_eq!(
+            tree(&document, first),
+            "Report (Summary (Revenue), Details), Appendix"
+        );
+    }
+
+    #[test]
+    fn bookmarks_point_to_the_heading_pages() {
+        let pdf = fixture(3);
+        let headings = [heading(1, 1, "One"), heading(3, 1, "Three")];
+
+        let pages = heading_pages(&pdf, &headings).unwrap();
+        assert_eq!(pages["dest-1"], 1);
+        assert_eq!(pages["dest-3"], 3);
+    }
+
+    #[test]
+    fn headings_without_destinations_are_left_out() {
+        let headings = [Heading {
+            id: "html2pdf-outline-0".to_string(),
+            level: 1,
+            title: "Lost".to_string(),
+        }];
+
+        // Without any destination the PDF is returned as printed
+        let pdf = fixture(1);
+        assert_eq!(add_outline(pdf.clone(), &headings).unwrap(), pdf);
+        assert!(heading_pages(&pdf, &headings).unwrap().is_empty());
+        assert_eq!(add_outline(pdf.clone(), &[]).unwrap(), pdf);
+
+        let partial = [heading(2, 1, "Found"), headings[0].clone()];
+        let pages = heading_pages(&fixture(2), &partial).unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages["dest-2"], 2);
+        let pdf = add_outline(fixture(2), &partial).unwrap();
+        let document = pdf_tools::load(&pdf).unwrap();
+        let outlines = document
+            .get_dictionary(
+                document
+                    .catalog()
+                    .unwrap()
+                    .get(b"Outlines")
+                    .unwrap()
+                    .as_reference()
+                    .unwrap(),
+            )
+            .unwrap();
+        let first = outlines.get(b"First").unwrap().as_reference().ok();
+        assert_eq!(tree(&document, first), "Found");
+    }
+}
@@ -0,0 +1,363 @@
+use chromiumoxide::cdp::browser_protocol::page::PrintToPdfParams;
+use serde::Deserialize;
+
+use crate::error::RenderError;
+
+/// Paper size Chrome prints on when none is given, in inches.
+const DEFAULT_PAPER_IN: (f64, f64) = (8.5, 11.0);
+
+/// Named paper formats with their portrait width and height, in inches.
+const PAPER_FORMATS: [(&str, f64, f64); 10] = [
+    ("A3", 297.0 / 25.4, 420.0 / 25.4),
+    ("A4", 210.0 / 25.4, 297.0 / 25.4),
+    ("A5", 148.0 / 25.4, 210.0 / 25.4),
+    ("A6", 105.0 / 25.4, 148.0 / 25.4),
+    ("Letter", 8.5, 11.0),
+    ("Legal", 8.5, 14.0),
+    ("Tabloid", 11.0, 17.0),
+    ("Ledger", 17.0, 11.0),
+    // Shipping labels
+    ("Label4x6", 4.0, 6.0),
+    ("Label100x150", 100.0 / 25.4, 150.0 / 25.4),
+];
+
+/// Units a length may be given in, by how many of them make an inch.
+const UNITS: [(&str, f64); 5] = [
+    ("mm", 25.4),
+    ("cm", 2.54),
+    ("in", 1.0),
+    ("px", 96.0),
+    ("pt", 72.0),
+];
+
+/// Paper size and margins in the units people actually use, translated into Chrome's inches.
+///
+/// `format` names a paper size such as `A4` or `Letter`; `width` and `height` give a custom one
+/// instead. Lengths are strings with a unit (`"210mm"`, `"2.5cm"`, `"1in"`, `"96px"`, `"12pt"`)
+/// or plain numbers, which count as pixels.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PageSetup {
+    pub format: Option<String>,
+    pub landscape: Option<bool>,
+    pub width: Option<Length>,
+    pub height: Option<Length>,
+    /// One length for every side, or `top`, `right`, `bottom` and `left` separately
+    pub margin: Option<Margin>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Length {
+    Pixels(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Margin {
+    All(Length),
+    Sides(MarginSides),
+}
+
+/// Margins given side by side. A misspelt side is an error rather than silently Chrome's default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MarginSides {
+    pub top: Option<Length>,
+    pub right: Option<Length>,
+    pub bottom: Option<Length>,
+    pub left: Option<Length>,
+}
+
+/// Margins in inches, `None` where Chrome's default applies.
+#[derive(Default)]
+struct MarginsIn {
+    top: Option<f64>,
+    right: Option<f64>,
+    bottom: Option<f64>,
+    left: Option<f64>,
+}
+
+impl PageSetup {
+    pub fn is_empty(&self) -> bool {
+        self.format.is_none()
+            && self.landscape.is_none()
+            && self.width.is_none()
+            && self.height.is_none()
+            && self.margin.is_none()
+    }
+
+    /// Print parameters with the paper size, orientation and margins set.
+    pub fn apply(
+        &self,
+        params: Option<PrintToPdfParams>,
+    ) -> Result<Option<PrintToPdfParams>, RenderError> {
+        if self.is_empty() {
+            return Ok(params);
+        }
+
+        let params = params.unwrap_or_default();
+        self.check_overrides(&params)?;
+
+        let size = self.paper_size()?;
+        let margins = self.margins()?;
+
+        let params = PrintToPdfParams {
+            landscape: self.landscape.or(params.landscape),
+            paper_width: size.map(|(width, _)| width).or(params.paper_width),
+            paper_height: size.map(|(_, height)| height).or(params.paper_height),
+            margin_top: margins.top.or(params.margin_top),
+            margin_right: margins.right.or(params.margin_right),
+            margin_bottom: margins.bottom.or(params.margin_bottom),
+            margin_left: margins.left.or(params.margin_left),
+            ..params
+        };
+        check_printable_area(&params)?;
+
+        Ok(Some(params))
+    }
+
+    /// Settings may be given here or in `printParams`, not in both.
+    fn check_overrides(&self, params: &PrintToPdfParams) -> Result<(), RenderError> {
+        let sets_size = self.format.is_some() || self.width.is_some() || self.height.is_some();
+        let conflicts = [
+            (
+                self.landscape.is_some() && params.landscape.is_some(),
+                "landscape",
+                "landscape",
+            ),
+            (
+                sets_size && params.paper_width.is_some(),
+                "format/width",
+                "paperWidth",
+            ),
+            (
+                sets_size && params.paper_height.is_some(),
+                "format/height",
+                "paperHeight",
+            ),
+            (
+                self.margin.is_some()
+                    && (params.margin_top.is_some()
+                        || params.margin_right.is_some()
+                        || params.margin_bottom.is_some()
+                        || params.margin_left.is_some()),
+                "margin",
+                "margin*",
+            ),
+        ];
+
+        match conflicts.iter().find(|(conflict, _, _)| *conflict) {
+            Some((_, option, param)) => Err(invalid(format!(
+                "set either {} or printParams.{}, not both",
+                option, param
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Portrait paper size in inches, if one is set.
+    fn paper_size(&self) -> Result<Option<(f64, f64)>, RenderError> {
+        match (&self.format, &self.width, &self.height) {
+            (None, None, None) => Ok(None),
+            (Some(format), None, None) => PAPER_FORMATS
+                .iter()
+                .find(|(name, _, _)| name.eq_ignore_ascii_case(format.trim()))
+                .map(|(_, width, height)| Some((*width, *height)))
+                .ok_or_else(|| {
+                    let names: Vec<_> = PAPER_FORMATS.iter().map(|(name, _, _)| *name).collect();
+                    invalid(format!(
+                        "unknown paper format '{}', use one of {}",
+                        format,
+                        names.join(", ")
+                    ))
+                }),
+            (Some(_), _, _) => Err(invalid(
+                "set either format or width and height, not both".to_string(),
+            )),
+            (None, Some(width), Some(height)) => {
+                let width = width.to_inches("width")?;
+                let height = height.to_inches("height")?;
+                if width <= 0.0 || height <= 0.0 {
+                    return Err(invalid("width and height must be positive".to_string()));
+                }
+                Ok(Some((width, height)))
+            }
+            (None, _, _) => Err(invalid(
+                "width and height must be given together".to_string(),
+            )),
+        }
+    }
+
+    fn margins(&self) -> Result<MarginsIn, RenderError> {
+        let side = |length: Option<&Length>, name: &str| {
+            length
+                .map(|length| {
+                    let inches = length.to_inches(name)?;
+                    if inches < 0.0 {
+                        return Err(invalid(format!("{} must not be negative", name)));
+                    }
+                    Ok(inches)
+                })
+                .transpose()
+        };
+
+        match &self.margin {
+            None => Ok(MarginsIn::default()),
+            Some(Margin::All(length)) => {
+                let all = side(Some(length), "margin")?;
+                Ok(MarginsIn {
+                    top: all,
+                    right: all,
+                    bottom: all,
+                    left: all,
+                })
+            }
+            Some(Margin::Sides(MarginSides {
+                top: None,
+                right: None,
+                bottom: None,
+                left: None,
+            })) => Err(invalid(
+                "margin needs at least one of top, right, bottom and left".to_string(),
+            )),
+            Some(Margin::Sides(MarginSides {
+                top,
+                right,
+                bottom,
+                left,
+            })) => Ok(MarginsIn {
+                top: side(top.as_ref(), "margin.top")?,
+                right: side(right.as_ref(), "margin.right")?,
+                bottom: side(bottom.as_ref(), "margin.bottom")?,
+                left: side(left.as_ref(), "margin.left")?,
+            }),
+        }
+    }
+}
+
+impl Length {
+    fn to_inches(&self, name: &str) -> Result<f64, RenderError> {
+        let inches = match self {
+            Length::Pixels(pixels) => pixels / 96.0,
+            Length::Text(text) => parse_length(text).ok_or_else(|| {
+                invalid(format!(
+                    "{} '{}' is no length, use a number with mm, cm, in, px or pt",
+                    name, text
+                ))
+            })?,
+        };
+
+        if inches.is_finite() {
+            Ok(inches)
+        } else {
+            Err(invalid(format!("{} is out of range", name)))
+        }
+    }
+}
+
+/// Margins have to leave some of the paper to print on.
+pub fn check_printable_area(params: &PrintToPdfParams) -> Result<(), RenderError> {
+    let width = params.paper_width.unwrap_or(DEFAULT_PAPER_IN.0);
+    let height = params.paper_height.unwrap_or(DEFAULT_PAPER_IN.1);
+    let (width, height) = if params.landscape.unwrap_or(false) {
+        (height, width)
+    } else {
+        (width, height)
+    };
+
+    let used = |a: Option<f64>, b: Option<f64>| a.unwrap_or(0.0) + b.unwrap_or(0.0);
+    if used(params.margin_left, params.margin_right) >= width
+        || used(params.margin_top, params.margin_bottom) >= height
+    {
+        return Err(invalid("margins leave no room on the paper".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Inches of a length such as `25.4mm`. A zero needs no unit.
+fn parse_length(text: &str) -> Option<f64> {
+    let text = text.trim();
+    let split = |unit: &str| {
+        let at = text.len().checked_sub(unit.len())?;
+        let (value, suffix) = (text.get(..at)?, text.get(at..)?);
+        suffix.eq_ignore_ascii_case(unit).then_some(value)
+    };
+    if let Some((value, per_inch)) = UNITS
+        .iter()
+        .find_map(|(unit, per_inch)| Some((split(unit)?, per_inch)))
+    {
+        let value: f64 = value.trim_end().parse().ok()?;
+        return Some(value / per_inch);
+    }
+
+    text.parse::<f64>().ok().filter(|value| *value == 0.0)
+}
+
+fn invalid(message: String) -> RenderError {
+    RenderError::InvalidPdfOptions(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn setup(value: serde_json::Value) -> PageSetup {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn lengths_take_any_unit_in_any_case() {
+        assert_eq!(parse_length("25.4mm"), Some(1.0));
+        assert_eq!(parse_length("2.54CM"), Some(1.0));
+        assert_eq!(parse_length(" 1 in "), Some(1.0));
+        assert_eq!(parse_length("96Px"), Some(1.0));
+        assert_eq!(parse_length("72pt"), Some(1.0));
+        assert_eq!(parse_length("0"), Some(0.0));
+    }
+
+    #[test]
+    fn lengths_need_a_unit_and_a_number() {
+        assert_eq!(parse_length("12"), None);
+        assert_eq!(parse_length("mm"), None);
+        assert_eq!(parse_length("12em"), None);
+        assert_eq!(parse_length(""), None);
+
+        let nan = Length::Text("NaNmm".to_string());
+        assert!(nan.to_inches("margin").is_err());
+        let infinite = Length::Text("infin".to_string());
+        assert!(infinite.to_inches("margin").is_err());
+    }
+
+    #[test]
+    fn margin_sides_are_checked() {
+        let sides = setup(json!({ "margin": { "top": "1cm", "left": 48 } }));
+        let params = sides.apply(None).unwrap().unwrap();
+        assert_eq!(params.margin_top, Some(1.0 / 2.54));
+        assert_eq!(params.margin_left, Some(0.5));
+        assert_eq!(params.margin_right, None);
+
+        let misspelt = serde_json::from_value::<PageSetup>(json!({ "margin": { "tpo": "1cm" } }));
+        assert!(misspelt.is_err());
+        assert!(setup(json!({ "margin": {} })).apply(None).is_err());
+    }
+
+    #[test]
+    fn landscape_margins_fit_the_turned_paper() {
+        // 9in of side margins only fit across Letter turned sideways
+        let margin = json!({ "top": "1in", "bottom": "1in", "left": "4.5in", "right": "4.5in" });
+        let portrait = setup(json!({ "format": "Letter", "margin": margin }));
+        assert!(portrait.apply(None).is_err());
+        let landscape = setup(json!({ "format": "Letter", "landscape": true, "margin": margin }));
+        assert!(landscape.apply(None).is_ok());
+
+        let margin = json!({ "top": "5in", "bottom": "4in" });
+        let portrait = setup(json!({ "format": "Letter", "margin": margin }));
+        assert!(portrait.apply(None).is_ok());
+        let landscape = setup(json!({ "format": "Letter", "landscape": true, "margin": margin }));
+        assert!(landscape.apply(None).is_err());
+    }
+}
@@ -0,0 +1,511 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Result;
+use lopdf::{Bookmark, Dictionary, Document, Object, ObjectId, dictionary};
+
+use crate::error::RenderError;
+
+/// Page attributes a page may inherit from its ancestors in the page tree.
+const INHERITABLE_PAGE_KEYS: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+/// A rendered document that is to become part of a merged PDF.
+pub struct Section {
+    pub title: String,
+    pub pdf: Vec<u8>,
+}
+
+pub fn load(pdf: &[u8]) -> Result<Document> {
+    Ok(Document::load_mem(pdf)?)
+}
+
+pub fn save(mut document: Document) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    document.save_to(&mut out)?;
+    Ok(out)
+}
+
+/// Concatenate `sections` into a single PDF with one top-level bookmark per section.
+///
+/// Links inside a section keep working because named destinations are resolved into explicit
+/// page references before the section's catalog is dropped. Outlines of the sections are
+/// replaced by the merged outline.
+pub fn merge(sections: Vec<Section>) -> Result<Vec<u8>> {
+    let mut merged = Document::with_version("1.7");
+    let pages_id = merged.new_object_id();
+
+    let mut next_id = merged.max_id + 1;
+    let mut kids = Vec::new();
+
+    for section in sections {
+        let mut document = load(&section.pdf)?;
+        inline_named_destinations(&mut document);
+        flatten_inherited_page_attributes(&mut document)?;
+
+        document.renumber_objects_with(next_id);
+        next_id = document.max_id + 1;
+
+        let page_ids: Vec<ObjectId> = document.get_pages().into_values().collect();
+        if let Some(first_page) = page_ids.first() {
+            merged.add_bookmark(
+                Bookmark::new(section.title, [0.0, 0.0, 0.0], 0, *first_page),
+                None,
+            );
+        }
+
+        for (id, object) in document.objects {
+            match object.type_name().unwrap_or_default() {
+                b"Catalog" | b"Pages" | b"Outlines" => {}
+                b"Page" => {
+                    let mut page = object.as_dict()?.clone();
+                    page.set("Parent", pages_id);
+                    merged.objects.insert(id, Object::Dictionary(page));
+                }
+                _ => {
+                    merged.objects.insert(id, object);
+                }
+            }
+        }
+
+        kids.extend(page_ids.into_iter().map(Object::Reference));
+    }
+
+    if kids.is_empty() {
+        anyhow::bail!("Merged document has no pages");
+    }
+
+    merged.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Count" => kids.len() as i64,
+            "Kids" => kids,
+        }),
+    );
+    merged.max_id = next_id;
+
+    let catalog_id = merged.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    merged.trailer.set("Root", catalog_id);
+
+    if let Some(outline_id) = merged.build_outline() {
+        let catalog = merged.get_dictionary_mut(catalog_id)?;
+        catalog.set("Outlines", outline_id);
+        catalog.set("PageMode", "UseOutlines");
+    }
+
+    // Drop the sections' outline items and anything else only the old catalogs referenced
+    merged.prune_objects();
+    merged.renumber_objects();
+
+    save(merged)
+}
+
+/// Named destinations keyed by name, each resolved to its explicit destination array.
+pub fn named_destinations(document: &Document) -> HashMap<Vec<u8>, Vec<Object>> {
+    let mut destinations = HashMap::new();
+
+    let Ok(catalog) = document.catalog() else {
+        return destinations;
+    };
+
+    // PDF 1.1 style `/Dests` dictionary
+    if let Ok(dests) = catalog
+        .get(b"Dests")
+        .and_then(|dests| document.dereference(dests))
+        .and_then(|(_, dests)| dests.as_dict())
+    {
+        for (name, value) in dests.iter() {
+            if let Some(destination) = destination_array(document, value) {
+                destinations.insert(name.clone(), destination);
+            }
+        }
+    }
+
+    // PDF 1.2 style name tree under `/Names /Dests`
+    if let Ok(tree) = catalog
+        .get(b"Names")
+        .and_then(|names| document.dereference(names))
+        .and_then(|(_, names)| names.as_dict())
+        .and_then(|names| names.get(b"Dests"))
+        .and_then(|tree| document.dereference(tree))
+        .and_then(|(_, tree)| tree.as_dict())
+    {
+        collect_name_tree(document, tree, &mut destinations, 0);
+    }
+
+    destinations
+}
+
+/// Replace named destinations in link annotations by the destinations they point to.
+pub fn inline_named_destinations(document: &mut Document) {
+    let destinations = named_destinations(document);
+    if destinations.is_empty() {
+        return;
+    }
+
+    let lookup = |object: &Object| -> Option<Vec<Object>> {
+        let name = match object {
+            Object::Name(name) => name.as_slice(),
+            Object::String(name, _) => name.as_slice(),
+            _ => return None,
+        };
+        destinations.get(name).cloned()
+    };
+
+    for object in document.objects.values_mut() {
+        let Ok(dict) = object.as_dict_mut() else {
+            continue;
+        };
+        if !matches!(dict.get(b"Subtype"), Ok(Object::Name(subtype)) if subtype == b"Link") {
+            continue;
+        }
+
+        if let Some(destination) = dict.get(b"Dest").ok().and_then(lookup) {
+            dict.set("Dest", destination);
+        }
+        if let Ok(action) = dict.get_mut(b"A").and_then(Object::as_dict_mut)
+            && let Some(destination) = action.get(b"D").ok().and_then(lookup)
+        {
+            action.set("D", destination);
+        }
+    }
+}
+
+/// Look up a page attribute, following the page tree up for inheritable ones.
+pub fn page_attribute<'a>(
+    document: &'a Document,
+    page_id: ObjectId,
+    key: &[u8],
+) -> Option<&'a Object> {
+    let mut node = Some(page_id);
+    // Bounded, to guard against cyclic page trees
+    for _ in 0..64 {
+        let dict = document.get_dictionary(node?).ok()?;
+        if let Ok(value) = dict.get(key) {
+            return Some(value);
+        }
+        node = dict.get(b"Parent").and_then(Object::as_reference).ok();
+    }
+
+    None
+}
+
+/// Copy inheritable attributes from the page tree onto every page, so pages can be moved to
+/// another page tree without losing their resources or size.
+fn flatten_inherited_page_attributes(document: &mut Document) -> Result<()> {
+    let mut inherited: BTreeMap<ObjectId, Vec<(&[u8], Object)>> = BTreeMap::new();
+
+    for page_id in document.page_iter() {
+        let page = document.get_dictionary(page_id)?;
+        let found: Vec<_> = INHERITABLE_PAGE_KEYS
+            .iter()
+            .filter(|key| !page.has(key))
+            .filter_map(|key| Some((*key, page_attribute(document, page_id, key)?.clone())))
+            .collect();
+
+        if !found.is_empty() {
+            inherited.insert(page_id, found);
+        }
+    }
+
+    for (page_id, values) in inherited {
+        let page = document.get_dictionary_mut(page_id)?;
+        for (key, value) in values {
+            page.set(key, value);
+        }
+    }
+
+    Ok(())
+}
+
+/// Selection of pages such as `1`, `2-4` or `1,3,5-`, as inclusive 1-based ranges; `None` as
+/// end means "to the last page". `all` and the default select every page.
+#[derive(Debug, Clone, Default)]
+pub struct PageRange(Vec<(u32, Option<u32>)>);
+
+impl PageRange {
+    pub fn parse(spec: &str) -> Result<Self, RenderError> {
+        let invalid = || RenderError::InvalidPdfOptions(format!("invalid page range: {}", spec));
+        let number = |value: &str| match value.trim().parse::<u32>() {
+            Ok(number) if number > 0 => Ok(number),
+            _ => Err(invalid()),
+        };
+
+        if spec.trim().eq_ignore_ascii_case("all") {
+            return Ok(Self::default());
+        }
+
+        let mut ranges = Vec::new();
+        for part in spec.split(',').map(str::trim) {
+            let range = match part.split_once('-') {
+                Some((start, "")) => (number(start)?, None),
+                Some((start, end)) => {
+                    let (start, end) = (number(start)?, number(end)?);
+                    if end < start {
+                        return Err(invalid());
+                    }
+                    (start, Some(end))
+                }
+                None => {
+                    let page = number(part)?;
+                    (page, Some(page))
+                }
+            };
+            ranges.push(range);
+        }
+
+        Ok(Self(ranges))
+    }
+
+    pub fn contains(&self, page: u32, total: u32) -> bool {
+        self.0.is_empty()
+            || self
+                .0
+                .iter()
+                .any(|&(start, end)| page >= start && page <= end.unwrap_or(total))
+    }
+}
+
+fn collect_name_tree(
+    document: &Document,
+    node: &Dictionary,
+    destinations: &mut HashMap<Vec<u8>, Vec<Object>>,
+    depth: usize,
+) {
+    if depth > 32 {
+        return;
+    }
+
+    if let Ok(names) = node.get(b"Names").and_then(Object::as_array) {
+        for pair in names.chunks_exact(2) {
+            if let (Ok(name), Some(destination)) =
+                (pair[0].as_str(), destination_array(document, &pair[1]))
+            {
+                destinations.insert(name.to_vec(), destination);
+            }
+        }
+    }
+
+    if let Ok(kids) = node.get(b"Kids").and_then(Object::as_array) {
+        for kid in kids {
+            if let Ok(kid) = kid
+                .as_reference()
+                .and_then(|id| document.get_dictionary(id))
+            {
+                collect_name_tree(document, kid, destinations, depth + 1);
+            }
+        }
+    }
+}
+
+/// A destination value is either the array itself or a dictionary holding it under `/D`.
+fn destination_array(document: &Document, value: &Object) -> Option<Vec<Object>> {
+    let (_, value) = document.dereference(value).ok()?;
+    match value {
+        Object::Array(array) => Some(array.clone()),
+        Object::Dictionary(dict) => {
+            let (_, destination) = document.dereference(dict.get(b"D").ok()?).ok()?;
+            destination.as_array().ok().cloned()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A PDF of `pages` pages whose MediaBox and Resources live on the page tree, with a link
+    /// on the first page to the named destination `last` on the last page.
+    fn section(pages: u32, width: i64) -> Vec<u8> {
+        let mut document = Document::with_version("1.7");
+        let pages_id = document.new_object_id();
+        let font_id = document.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Helvetica",
+        });
+
+        let page_ids: Vec<ObjectId> = (0..pages)
+            .map(|_| {
+                document.add_object(dictionary! {
+                    "Type" => "Page",
+                    "Parent" => pages_id,
+                })
+            })
+            .collect();
+        let last = *page_ids.last().unwrap();
+        let link_id = document.add_object(dictionary! {
+            "Type" => "Annot",
+            "Subtype" => "Link",
+            "Rect" => vec![0.into(), 0.into(), 10.into(), 10.into()],
+            "Dest" => Object::string_literal("last"),
+        });
+        document
+            .get_dictionary_mut(page_ids[0])
+            .unwrap()
+            .set("Annots", vec![link_id.into()]);
+
+        document.objects.insert(
+            pages_id,
+            dictionary! {
+                "Type" => "Pages",
+                "Count" => pages,
+                "Kids" => page_ids.iter().map(|id| Object::Reference(*id)).collect::<Vec<_>>(),
+                "MediaBox" => vec![0.into(), 0.into(), width.into(), 842.into()],
+                "Resources" => dictionary! { "Font" => dictionary! { "F1" => font_id } },
+            }
+            .into(),
+        );
+        let names_id = document.add_object(dictionary! {
+            "Dests" => dictionary! {
+                "Names" => vec![
+                    Object::string_literal("last"),
+                    vec![last.into(), "Fit".into()].into(),
+                ],
+            },
+        });
+        let catalog_id = document.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+            "Names" => names_id,
+        });
+        document.trailer.set("Root", catalog_id);
+
+        save(document).unwrap()
+    }
+
+    /// Title and target page number of each top-level bookmark.
+    fn bookmarks(document: &Document) -> Vec<(String, u32)> {
+        let pages: HashMap<ObjectId, u32> = document
+            .get_pages()
+            .into_iter()
+            .map(|(number, id)| (id, number))
+            .collect();
+        let outlines = document
+            .catalog()
+            .and_then(|catalog| catalog.get(b"Outlines"))
+            .and_then(Object::as_reference)
+            .and_then(|id| document.get_dictionary(id))
+            .unwrap();
+
+        let mut bookmarks = Vec::new();
+        let mut next = outlines.get(b"First").and_then(Object::as_reference).ok();
+        while let Some(id) = next {
+            let item = document.get_dictionary(id).unwrap();
+            let title = lopdf::decode_text_string(item.get(b"Title").unwrap()).unwrap();
+            let target = item
+                .get(b"A")
+                .and_then(|action| document.dereference(action))
+                .and_then(|(_, action)| action.as_dict())
+                .and_then(|action| action.get(b"D"))
+                .and_then(Object::as_array)
+                .and_then(|destination| destination[0].as_reference())
+                .unwrap();
+            bookmarks.push((title, pages[&target]));
+            next = item.get(b"Next").and_then(Object::as_reference).ok();
+        }
+        bookmarks
+    }
+
+    fn merged() -> Document {
+        let pdf = merge(vec![
+            Section {
+                title: "Cover".to_string(),
+                pdf: section(2, 595),
+            },
+            Section {
+                title: "Appendix".to_string(),
+                pdf: section(3, 842),
+            },
+        ])
+        .unwrap();
+        load(&pdf).unwrap()
+    }
+
+    #[test]
+    fn sections_are_concatenated_with_a_bookmark_each() {
+        let document = merged();
+
+        assert_eq!(document.get_pages().len(), 5);
+        assert_eq!(
+            bookmarks(&document),
+            vec![("Cover".to_string(), 1), ("Appendix".to_string(), 3)]
+        );
+    }
+
+    #[test]
+    fn pages_keep_their_inherited_attributes() {
+        let document = merged();
+        let pages = document.get_pages();
+
+        for (number, width) in [(1, 595), (2, 595), (3, 842), (5, 842)] {
+            let page = document.get_dictionary(pages[&number]).unwrap();
+            let media_box = page.get(b"MediaBox").and_then(Object::as_array).unwrap();
+            assert_eq!(media_box[2].as_i64().unwrap(), width, "page {}", number);
+
+            let font = page
+                .get(b"Resources")
+                .and_then(Object::as_dict)
+                .and_then(|resources| resources.get(b"Font"))
+                .and_then(Object::as_dict)
+                .and_then(|fonts| fonts.get(b"F1"))
+                .and_then(Object::as_reference)
+                .and_then(|id| document.get_dictionary(id))
+                .unwrap();
+            assert_eq!(
+                font.get(b"BaseFont").unwrap().as_name().unwrap(),
+                b"Helvetica"
+            );
+        }
+    }
+
+    fn selected(spec: &str, total: u32) -> Vec<u32> {
+        let range = PageRange::parse(spec).unwrap();
+        (1..=total)
+            .filter(|page| range.contains(*page, total))
+            .collect()
+    }
+
+    #[test]
+    fn page_ranges_select_pages() {
+        assert_eq!(selected("1", 5), vec![1]);
+        assert_eq!(selected("2-4", 5), vec![2, 3, 4]);
+        assert_eq!(selected("1, 3,5-", 7), vec![1, 3, 5, 6, 7]);
+        assert_eq!(selected("4-4", 5), vec![4]);
+        assert_eq!(selected("7", 5), Vec::<u32>::new());
+        assert_eq!(selected("all", 3), vec![1, 2, 3]);
+        assert_eq!(selected(" ALL ", 3), vec![1, 2, 3]);
+        assert_eq!(
+            (1..=3)
+                .filter(|page| PageRange::default().contains(*page, 3))
+                .count(),
+            3
+        );
+
+        for invalid in ["", "0", "3-1", "a", "1,,2", "-2", "1-b", "all,1"] {
+            assert!(PageRange::parse(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn named_links_point_to_the_merged_pages() {
+        let document = merged();
+        let pages = document.get_pages();
+
+        // The link on the first page of each section leads to the last page of that section
+        for (first, last) in [(1, 2), (3, 5)] {
+            let page = document.get_dictionary(pages[&first]).unwrap();
+            let link = page
+                .get(b"Annots")
+                .and_then(Object::as_array)
+                .and_then(|annots| annots[0].as_reference())
+                .and_then(|id| document.get_dictionary(id))
+                .unwrap();
+            let destination = link.get(b"Dest").and_then(Object::as_array).unwrap();
+            assert_eq!(destination[0].as_reference().unwrap(), pages[&last]);
+        }
+    }
+}
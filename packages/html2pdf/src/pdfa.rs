@@ -0,0 +1,568 @@
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream, StringFormat, dictionary};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::error::RenderError;
+
+const OUTPUT_CONDITION: &str = "sRGB IEC61966-2.1";
+
+/// Annotation flags, ISO 32000-1 table 165.
+const ANNOTATION_INVISIBLE: i64 = 1;
+const ANNOTATION_HIDDEN: i64 = 1 << 1;
+const ANNOTATION_PRINT: i64 = 1 << 2;
+const ANNOTATION_NO_VIEW: i64 = 1 << 5;
+const ANNOTATION_TOGGLE_NO_VIEW: i64 = 1 << 8;
+
+/// Archival profile the output has to conform to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Conformance {
+    #[serde(rename = "PDF/A-2b", alias = "pdf/a-2b")]
+    PdfA2b,
+    #[serde(rename = "PDF/A-3b", alias = "pdf/a-3b")]
+    PdfA3b,
+}
+
+impl Conformance {
+    fn part(self) -> u8 {
+        match self {
+            Conformance::PdfA2b => 2,
+            Conformance::PdfA3b => 3,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Conformance::PdfA2b => "PDF/A-2b",
+            Conformance::PdfA3b => "PDF/A-3b",
+        }
+    }
+
+    /// Error for a violated clause of ISO 19005-2/3, which share their numbering.
+    pub fn violation(self, clause: &str, message: impl std::fmt::Display) -> RenderError {
+        RenderError::ConformanceFailed(format!("{} {}: {}", self.name(), clause, message))
+    }
+}
+
+/// Turn `document` into a PDF/A document.
+///
+/// Adds what Chrome leaves out (sRGB output intent, XMP metadata matching the document
+/// information, a file identifier), fixes annotation flags and rejects whatever can not be
+/// fixed, such as fonts that are not embedded.
+pub fn convert(document: &mut Document, conformance: Conformance) -> Result<()> {
+    check_document(document, conformance)?;
+    check_fonts(document, conformance)?;
+    fix_annotations(document, conformance)?;
+
+    if document.version.as_str() > "1.7" {
+        document.version = "1.7".to_string();
+    }
+
+    let mut icc = Stream::new(dictionary! { "N" => 3 }, SRGB_PROFILE.clone());
+    // Compression only fails for streams that opt out of it
+    let _ = icc.compress();
+    let icc_id = document.add_object(icc);
+    let output_intent_id = document.add_object(dictionary! {
+        "Type" => "OutputIntent",
+        "S" => "GTS_PDFA1",
+        "OutputConditionIdentifier" => Object::string_literal(OUTPUT_CONDITION),
+        "Info" => Object::string_literal(OUTPUT_CONDITION),
+        "RegistryName" => Object::string_literal("http://www.color.org"),
+        "DestOutputProfile" => icc_id,
+    });
+
+    let info = update_info(document)?;
+    let metadata_id = document.add_object(
+        Stream::new(
+            dictionary! { "Type" => "Metadata", "Subtype" => "XML" },
+            xmp(&info, conformance).into_bytes(),
+        )
+        .with_compression(false),
+    );
+
+    let catalog = document.catalog_mut()?;
+    catalog.set("OutputIntents", vec![Object::Reference(output_intent_id)]);
+    catalog.set("Metadata", metadata_id);
+
+    if document.trailer.get(b"ID").is_err() {
+        let id = Object::String(
+            rand::random::<[u8; 16]>().to_vec(),
+            StringFormat::Hexadecimal,
+        );
+        document.trailer.set("ID", vec![id.clone(), id]);
+    }
+
+    Ok(())
+}
+
+fn check_document(document: &Document, conformance: Conformance) -> Result<(), RenderError> {
+    if document.trailer.has(b"Encrypt") {
+        return Err(conformance.violation("6.1.3", "the document must not be encrypted"));
+    }
+
+    let catalog = document
+        .catalog()
+        .map_err(|e| conformance.violation("6.1", e))?;
+    let names = catalog
+        .get(b"Names")
+        .and_then(|names| document.dereference(names))
+        .and_then(|(_, names)| names.as_dict());
+    if let Ok(names) = names {
+        if names.has(b"JavaScript") {
+            return Err(conformance.violation("6.6.1", "JavaScript is not permitted"));
+        }
+        if conformance == Conformance::PdfA2b && names.has(b"EmbeddedFiles") {
+            return Err(conformance.violation("6.8", "embedded files are not permitted"));
+        }
+    }
+
+    for object in document.objects.values() {
+        let Ok(dict) = object.as_dict() else {
+            continue;
+        };
+        if let Ok(Object::Name(action)) = dict.get(b"S")
+            && matches!(
+                action.as_slice(),
+                b"JavaScript" | b"Launch" | b"Sound" | b"Movie" | b"ResetForm" | b"ImportData"
+            )
+        {
+            return Err(conformance.violation(
+                "6.6.1",
+                format!(
+                    "{} actions are not permitted",
+                    String::from_utf8_lossy(action)
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_fonts(document: &Document, conformance: Conformance) -> Result<(), RenderError> {
+    for object in document.objects.values() {
+        let Ok(font) = object.as_dict() else {
+            continue;
+        };
+        if !matches!(font.get(b"Type"), Ok(Object::Name(name)) if name == b"Font") {
+            continue;
+        }
+
+        let descriptor_font = match font.get(b"Subtype") {
+            // Glyphs of Type 3 fonts are content streams inside the font itself
+            Ok(Object::Name(subtype)) if subtype == b"Type3" => continue,
+            // Composite fonts are embedded through their descendant
+            Ok(Object::Name(subtype)) if subtype == b"Type0" => font
+                .get(b"DescendantFonts")
+                .and_then(|fonts| document.dereference(fonts))
+                .and_then(|(_, fonts)| fonts.as_array())
+                .ok()
+                .and_then(|fonts| fonts.first())
+                .and_then(|descendant| document.dereference(descendant).ok())
+                .and_then(|(_, descendant)| descendant.as_dict().ok()),
+            _ => Some(font),
+        };
+
+        let embedded = descriptor_font
+            .and_then(|font| font.get(b"FontDescriptor").ok())
+            .and_then(|descriptor| document.dereference(descriptor).ok())
+            .and_then(|(_, descriptor)| descriptor.as_dict().ok())
+            .is_some_and(|descriptor| {
+                [&b"FontFile"[..], b"FontFile2", b"FontFile3"]
+                    .iter()
+                    .any(|key| descriptor.has(key))
+            });
+
+        if !embedded {
+            let name = font
+                .get(b"BaseFont")
+                .and_then(Object::as_name)
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .unwrap_or_else(|_| "unnamed font".to_string());
+            return Err(
+                conformance.violation("6.2.11.4.1", format!("font {} is not embedded", name))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Annotations have to be printed as they are shown; all but links need an appearance.
+fn fix_annotations(document: &mut Document, conformance: Conformance) -> Result<()> {
+    let annotation_ids: BTreeSet<ObjectId> = document
+        .page_iter()
+        .filter_map(|page_id| document.get_dictionary(page_id).ok())
+        .filter_map(|page| page.get(b"Annots").ok())
+        .filter_map(|annots| document.dereference(annots).ok())
+        .filter_map(|(_, annots)| annots.as_array().ok())
+        .flatten()
+        .filter_map(|annot| annot.as_reference().ok())
+        .collect();
+
+    for id in annotation_ids {
+        let Ok(annotation) = document.get_dictionary_mut(id) else {
+            continue;
+        };
+
+        let subtype = annotation
+            .get(b"Subtype")
+            .and_then(Object::as_name)
+            .unwrap_or_default()
+            .to_vec();
+        if !matches!(subtype.as_slice(), b"Link" | b"Popup") && !annotation.has(b"AP") {
+            return Err(conformance
+                .violation(
+                    "6.3.3",
+                    format!(
+                        "{} annotation has no appearance",
+                        String::from_utf8_lossy(&subtype)
+                    ),
+                )
+                .into());
+        }
+
+        let flags = annotation
+            .get(b"F")
+            .and_then(Object::as_i64)
+            .unwrap_or_default();
+        let cleared = ANNOTATION_INVISIBLE
+            | ANNOTATION_HIDDEN
+            | ANNOTATION_NO_VIEW
+            | ANNOTATION_TOGGLE_NO_VIEW;
+        annotation.set("F", (flags & !cleared) | ANNOTATION_PRINT);
+    }
+
+    Ok(())
+}
+
+/// Document information as plain strings, with fresh dates; written back to the trailer's
+/// information dictionary so it matches the XMP packet.
+struct Info {
+    entries: Vec<(&'static str, String)>,
+    date: DateTime<Utc>,
+}
+
+impl Info {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+const INFO_KEYS: [&str; 6] = [
+    "Title", "Author", "Subject", "Keywords", "Creator", "Producer",
+];
+
+fn update_info(document: &mut Document) -> Result<Info> {
+    let existing = match document.trailer.get(b"Info") {
+        Ok(info) => document.dereference(info)?.1.as_dict()?.clone(),
+        Err(_) => Dictionary::new(),
+    };
+
+    let entries: Vec<(&'static str, String)> = INFO_KEYS
+        .iter()
+        .filter_map(|&key| {
+            let value = lopdf::decode_text_string(existing.get(key.as_bytes()).ok()?).ok()?;
+            Some((key, value))
+        })
+        .collect();
+    let date = Utc::now();
+
+    // Only keys that have an XMP counterpart are kept
+    let mut info = Dictionary::new();
+    for (key, value) in &entries {
+        info.set(*key, lopdf::text_string(value));
+    }
+    let pdf_date = date.format("D:%Y%m%d%H%M%S+00'00'").to_string();
+    info.set("CreationDate", Object::string_literal(pdf_date.clone()));
+    info.set("ModDate", Object::string_literal(pdf_date));
+
+    let info_id = document.add_object(info);
+    document.trailer.set("Info", info_id);
+
+    Ok(Info { entries, date })
+}
+
+fn xmp(info: &Info, conformance: Conformance) -> String {
+    let mut properties = String::new();
+    let mut property = |xml: String| {
+        properties.push_str("      ");
+        properties.push_str(&xml);
+        properties.push('\n');
+    };
+
+    property(format!("<pdfaid:part>{}</pdfaid:part>", conformance.part()));
+    property("<pdfaid:conformance>B</pdfaid:conformance>".to_string());
+    if let Some(title) = info.get("Title") {
+        property(format!(
+            "<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>",
+            escape_xml(title)
+        ));
+    }
+    if let Some(author) = info.get("Author") {
+        property(format!(
+            "<dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>",
+            escape_xml(author)
+        ));
+    }
+    if let Some(subject) = info.get("Subject") {
+        property(format!(
+            "<dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description>",
+            escape_xml(subject)
+        ));
+    }
+    if let Some(keywords) = info.get("Keywords") {
+        property(format!(
+            "<pdf:Keywords>{}</pdf:Keywords>",
+            escape_xml(keywords)
+        ));
+    }
+    if let Some(producer) = info.get("Producer") {
+        property(format!(
+            "<pdf:Producer>{}</pdf:Producer>",
+            escape_xml(producer)
+        ));
+    }
+    if let Some(creator) = info.get("Creator") {
+        property(format!(
+            "<xmp:CreatorTool>{}</xmp:CreatorTool>",
+            escape_xml(creator)
+        ));
+    }
+    let date = info.date.format("%Y-%m-%dT%H:%M:%S+00:00");
+    property(format!("<xmp:CreateDate>{}</xmp:CreateDate>", date));
+    property(format!("<xmp:ModifyDate>{}</xmp:ModifyDate>", date));
+
+    format!(
+        r#"<?xpacket begin="{bom}" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+  <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+    <rdf:Description rdf:about=""
+        xmlns:pdfaid="http://www.aiim.org/pdfa/ns/id/"
+        xmlns:dc="http://purl.org/dc/elements/1.1/"
+        xmlns:pdf="http://ns.adobe.com/pdf/1.3/"
+        xmlns:xmp="http://ns.adobe.com/xap/1.0/">
+{properties}    </rdf:Description>
+  </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#,
+        bom = '\u{feff}',
+    )
+}
+
+fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            // Control characters are not allowed in XML 1.0
+            c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// ICC v2 display profile for sRGB: D50 adapted primaries and the sRGB transfer curve.
+static SRGB_PROFILE: Lazy<Vec<u8>> = Lazy::new(srgb_profile);
+
+fn srgb_profile() -> Vec<u8> {
+    fn xyz(values: [f64; 3]) -> Vec<u8> {
+        let mut data = b"XYZ \0\0\0\0".to_vec();
+        for value in values {
+            data.extend_from_slice(&((value * 65536.0).round() as i32).to_be_bytes());
+        }
+        data
+    }
+
+    let mut description = b"desc\0\0\0\0".to_vec();
+    description.extend_from_slice(&(OUTPUT_CONDITION.len() as u32 + 1).to_be_bytes());
+    description.extend_from_slice(OUTPUT_CONDITION.as_bytes());
+    description.push(0);
+    // Empty Unicode and ScriptCode descriptions
+    description.extend_from_slice(&[0; 8]);
+    description.extend_from_slice(&[0; 3]);
+    description.extend_from_slice(&[0; 67]);
+
+    let mut copyright = b"text\0\0\0\0".to_vec();
+    copyright.extend_from_slice(b"No copyright, use freely\0");
+
+    let mut curve = b"curv\0\0\0\0".to_vec();
+    let points = 1024u32;
+    curve.extend_from_slice(&points.to_be_bytes());
+    for point in 0..points {
+        let encoded = point as f64 / (points - 1) as f64;
+        let linear = if encoded <= 0.04045 {
+            encoded / 12.92
+        } else {
+            ((encoded + 0.055) / 1.055).powf(2.4)
+        };
+        curve.extend_from_slice(&((linear * 65535.0).round() as u16).to_be_bytes());
+    }
+
+    let tags: [(&[u8; 4], Vec<u8>); 9] = [
+        (b"desc", description),
+        (b"cprt", copyright),
+        (b"wtpt", xyz([0.9505, 1.0, 1.0891])),
+        (b"rXYZ", xyz([0.4361, 0.2225, 0.0139])),
+        (b"gXYZ", xyz([0.3851, 0.7169, 0.0971])),
+        (b"bXYZ", xyz([0.1431, 0.0606, 0.7141])),
+        (b"rTRC", curve.clone()),
+        (b"gTRC", curve.clone()),
+        (b"bTRC", curve),
+    ];
+
+    let header_len = 128;
+    let table_len = 4 + tags.len() * 12;
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data = Vec::new();
+    for (signature, tag) in &tags {
+        let offset = header_len + table_len + data.len();
+        table.extend_from_slice(*signature);
+        table.extend_from_slice(&(offset as u32).to_be_bytes());
+        table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+        data.extend_from_slice(tag);
+        // Tag data starts on a 4 byte boundary
+        data.resize(data.len().next_multiple_of(4), 0);
+    }
+
+    let size = header_len + table_len + data.len();
+    let mut profile = Vec::with_capacity(size);
+    profile.extend_from_slice(&(size as u32).to_be_bytes());
+    profile.extend_from_slice(&[0; 4]); // preferred CMM
+    profile.extend_from_slice(&[2, 0x10, 0, 0]); // version 2.1
+    profile.extend_from_slice(b"mntrRGB XYZ ");
+    for part in [2024u16, 1, 1, 0, 0, 0] {
+        profile.extend_from_slice(&part.to_be_bytes());
+    }
+    profile.extend_from_slice(b"acsp");
+    profile.extend_from_slice(&[0; 24]); // platform, flags, manufacturer, model, attributes
+    profile.extend_from_slice(&[0; 4]); // perceptual rendering intent
+    profile.extend_from_slice(&xyz([0.9642, 1.0, 0.8249])[8..]); // D50 illuminant
+    profile.extend_from_slice(&[0; 48]); // creator and reserved bytes
+    profile.extend_from_slice(&table);
+    profile.extend_from_slice(&data);
+
+    profile
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        pdf_tools,
+        post_process::{PdfEncryption, PdfPermissions, PostProcess},
+    };
+
+    use super::*;
+
+    fn document() -> Document {
+        let mut document = Document::with_version("1.7");
+        let pages_id = document.new_object_id();
+        let page_id = document.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+        });
+        document.objects.insert(
+            pages_id,
+            dictionary! { "Type" => "Pages", "Count" => 1, "Kids" => vec![page_id.into()] }.into(),
+        );
+        let catalog_id = document.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        document.trailer.set("Root", catalog_id);
+        let info_id = document.add_object(dictionary! {
+            "Title" => Object::string_literal("Annual <report> & accounts"),
+        });
+        document.trailer.set("Info", info_id);
+        document
+    }
+
+    fn stream<'a>(document: &'a Document, object: &'a Object) -> &'a Stream {
+        document.dereference(object).unwrap().1.as_stream().unwrap()
+    }
+
+    #[test]
+    fn conversion_adds_output_intent_metadata_and_id() {
+        let mut document = document();
+        convert(&mut document, Conformance::PdfA3b).unwrap();
+        let document = pdf_tools::load(&pdf_tools::save(document).unwrap()).unwrap();
+        let catalog = document.catalog().unwrap();
+
+        let intents = catalog.get(b"OutputIntents").unwrap().as_array().unwrap();
+        let (_, intent) = document.dereference(&intents[0]).unwrap();
+        let intent = intent.as_dict().unwrap();
+        assert_eq!(intent.get(b"S").unwrap().as_name().unwrap(), b"GTS_PDFA1");
+        let profile = stream(&document, intent.get(b"DestOutputProfile").unwrap());
+        assert_eq!(profile.dict.get(b"N").unwrap().as_i64().unwrap(), 3);
+        let profile = profile.decompressed_content().unwrap();
+        assert_eq!(&profile[36..40], b"acsp");
+
+        let metadata = stream(&document, catalog.get(b"Metadata").unwrap());
+        assert_eq!(
+            metadata.dict.get(b"Subtype").unwrap().as_name().unwrap(),
+            b"XML"
+        );
+        let xmp = String::from_utf8(metadata.content.clone()).unwrap();
+        assert!(xmp.contains("<pdfaid:part>3</pdfaid:part>"));
+        assert!(xmp.contains("<pdfaid:conformance>B</pdfaid:conformance>"));
+        assert!(xmp.contains("Annual &lt;report&gt; &amp; accounts"));
+
+        let id = document.trailer.get(b"ID").unwrap().as_array().unwrap();
+        assert_eq!(id.len(), 2);
+        assert_eq!(id[0].as_str().unwrap().len(), 16);
+    }
+
+    #[test]
+    fn fonts_that_are_not_embedded_are_rejected() {
+        let mut document = document();
+        document.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Helvetica",
+        });
+
+        let error = convert(&mut document, Conformance::PdfA2b).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(RenderError::ConformanceFailed(_))
+        ));
+    }
+
+    #[test]
+    fn encryption_is_rejected() {
+        let post_process = PostProcess {
+            encryption: Some(PdfEncryption {
+                owner_password: "owner".to_string(),
+                user_password: String::new(),
+                permissions: PdfPermissions::default(),
+            }),
+            conformance: Some(Conformance::PdfA2b),
+            ..PostProcess::default()
+        };
+        assert!(matches!(
+            post_process.validate(),
+            Err(RenderError::ConformanceFailed(_))
+        ));
+
+        // Documents that arrive encrypted are refused as well
+        let mut document = document();
+        let encrypt_id = document.add_object(dictionary! { "Filter" => "Standard" });
+        document.trailer.set("Encrypt", encrypt_id);
+        let error = convert(&mut document, Conformance::PdfA2b).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(RenderError::ConformanceFailed(_))
+        ));
+    }
+}
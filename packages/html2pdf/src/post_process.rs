@@ -0,0 +1,327 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::Result;
+use lopdf::{
+    Document, EncryptionState, EncryptionVersion, Object, Permissions,
+    encryption::crypt_filters::{Aes256CryptFilter, CryptFilter},
+    text_string,
+};
+use serde::Deserialize;
+
+use crate::{
+    error::RenderError,
+    pdf_tools,
+    pdfa::{self, Conformance},
+    watermark::{self, Watermark},
+};
+
+/// Changes applied to the PDF after Chrome printed it.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PostProcess {
+    /// Document information shown by PDF viewers, replacing Chrome's defaults
+    pub metadata: Option<PdfMetadata>,
+    /// Password protection and permissions
+    pub encryption: Option<PdfEncryption>,
+    /// Text or image overlays, drawn in order
+    #[serde(default)]
+    pub watermarks: Vec<Watermark>,
+    /// Archival profile, `PDF/A-2b` or `PDF/A-3b`
+    pub conformance: Option<Conformance>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PdfMetadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub keywords: Option<String>,
+    pub creator: Option<String>,
+}
+
+/// AES-256 encryption. Permissions are enforced by viewers for anyone opening the document
+/// with the user password; the owner password lifts them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PdfEncryption {
+    pub owner_password: String,
+    /// Needed to open the document, empty lets anyone open it
+    #[serde(default)]
+    pub user_password: String,
+    #[serde(default)]
+    pub permissions: PdfPermissions,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct PdfPermissions {
+    pub print: bool,
+    pub copy: bool,
+    pub modify: bool,
+}
+
+impl Default for PdfPermissions {
+    fn default() -> Self {
+        Self {
+            print: true,
+            copy: true,
+            modify: true,
+        }
+    }
+}
+
+impl PostProcess {
+    pub fn is_empty(&self) -> bool {
+        self.metadata.is_none()
+            && self.encryption.is_none()
+            && self.watermarks.is_empty()
+            && self.conformance.is_none()
+    }
+
+    /// Reject options that can never be applied, before anything is rendered.
+    pub fn validate(&self) -> Result<(), RenderError> {
+        for watermark in &self.watermarks {
+            watermark.validate()?;
+        }
+        if let Some(encryption) = &self.encryption
+            && encryption.owner_password.is_empty()
+        {
+            return Err(RenderError::InvalidPdfOptions(
+                "encryption.ownerPassword must not be empty".to_string(),
+            ));
+        }
+        if let Some(conformance) = self.conformance {
+            if self.encryption.is_some() {
+                return Err(conformance.violation("6.1.3", "the document must not be encrypted"));
+            }
+            if self
+                .watermarks
+                .iter()
+                .any(|watermark| watermark.text.is_some())
+            {
+                return Err(RenderError::InvalidPdfOptions(
+                    "text watermarks use a font that is not embedded, which PDF/A forbids"
+                        .to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn apply(&self, pdf: Vec<u8>) -> Result<Vec<u8>> {
+        if self.is_empty() {
+            return Ok(pdf);
+        }
+
+        let mut document = pdf_tools::load(&pdf)?;
+
+        if let Some(metadata) = &self.metadata {
+            set_metadata(&mut document, metadata)?;
+        }
+
+        if !self.watermarks.is_empty() {
+            watermark::apply(&mut document, &self.watermarks)?;
+        }
+
+        if let Some(conformance) = self.conformance {
+            pdfa::convert(&mut document, conformance)?;
+        }
+
+        // Encryption goes last, it has to cover everything written before
+        if let Some(encryption) = &self.encryption {
+            encrypt(&mut document, encryption)?;
+        }
+
+        pdf_tools::save(document)
+    }
+}
+
+impl PdfPermissions {
+    fn to_lopdf(self) -> Permissions {
+        // Assistive technology may always extract content
+        let mut permissions = Permissions::COPYABLE_FOR_ACCESSIBILITY;
+        if self.print {
+            permissions |= Permissions::PRINTABLE | Permissions::PRINTABLE_IN_HIGH_QUALITY;
+        }
+        if self.copy {
+            permissions |= Permissions::COPYABLE;
+        }
+        if self.modify {
+            permissions |= Permissions::MODIFIABLE
+                | Permissions::ANNOTABLE
+                | Permissions::FILLABLE
+                | Permissions::ASSEMBLABLE;
+        }
+        permissions
+    }
+}
+
+fn set_metadata(document: &mut Document, metadata: &PdfMetadata) -> Result<()> {
+    let info_id = match document.trailer.get(b"Info") {
+        Ok(Object::Reference(id)) => *id,
+        // A direct dictionary keeps its entries, it only moves into an object of its own
+        existing => {
+            let info = match existing {
+                Ok(Object::Dictionary(info)) => info.clone(),
+                _ => lopdf::Dictionary::new(),
+            };
+            let id = document.add_object(info);
+            document.trailer.set("Info", id);
+            id
+        }
+    };
+    let info = document.get_dictionary_mut(info_id)?;
+
+    let fields = [
+        ("Title", &metadata.title),
+        ("Author", &metadata.author),
+        ("Subject", &metadata.subject),
+        ("Keywords", &metadata.keywords),
+        ("Creator", &metadata.creator),
+    ];
+    for (key, value) in fields {
+        if let Some(value) = value {
+            info.set(key, text_string(value));
+        }
+    }
+
+    Ok(())
+}
+
+fn encrypt(document: &mut Document, encryption: &PdfEncryption) -> Result<()> {
+    let file_encryption_key: [u8; 32] = rand::random();
+    let crypt_filter: Arc<dyn CryptFilter> = Arc::new(Aes256CryptFilter);
+
+    let state = EncryptionState::try_from(EncryptionVersion::V5 {
+        encrypt_metadata: true,
+        crypt_filters: BTreeMap::from([(b"StdCF".to_vec(), crypt_filter)]),
+        file_encryption_key: &file_encryption_key,
+        stream_filter: b"StdCF".to_vec(),
+        string_filter: b"StdCF".to_vec(),
+        owner_password: &encryption.owner_password,
+        user_password: &encryption.user_password,
+        permissions: encryption.permissions.to_lopdf(),
+    })?;
+
+    document.encrypt(&state)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use lopdf::{Stream, dictionary};
+
+    use super::*;
+
+    fn pdf(info: Option<Object>) -> Vec<u8> {
+        let mut document = Document::with_version("1.7");
+        let pages_id = document.new_object_id();
+        let content_id = document.add_object(Stream::new(
+            dictionary! {},
+            b"BT /F1 12 Tf 72 720 Td (Quarterly report) Tj ET".to_vec(),
+        ));
+        let page_id = document.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            "Contents" => content_id,
+        });
+        document.objects.insert(
+            pages_id,
+            dictionary! {
+                "Type" => "Pages",
+                "Count" => 1,
+                "Kids" => vec![page_id.into()],
+            }
+            .into(),
+        );
+        let catalog_id = document.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        document.trailer.set("Root", catalog_id);
+        if let Some(info) = info {
+            document.trailer.set("Info", info);
+        }
+
+        pdf_tools::save(document).unwrap()
+    }
+
+    fn metadata() -> PdfMetadata {
+        PdfMetadata {
+            title: Some("Quarterly report".to_string()),
+            author: Some("Jürgen Müller".to_string()),
+            ..PdfMetadata::default()
+        }
+    }
+
+    fn info_field(document: &Document, key: &[u8]) -> String {
+        let (_, info) = document
+            .dereference(document.trailer.get(b"Info").unwrap())
+            .unwrap();
+        lopdf::decode_text_string(info.as_dict().unwrap().get(key).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn metadata_is_written_to_the_info_dictionary() {
+        let post_process = PostProcess {
+            metadata: Some(metadata()),
+            ..PostProcess::default()
+        };
+
+        let document = pdf_tools::load(&post_process.apply(pdf(None)).unwrap()).unwrap();
+        assert_eq!(info_field(&document, b"Title"), "Quarterly report");
+        assert_eq!(info_field(&document, b"Author"), "Jürgen Müller");
+    }
+
+    #[test]
+    fn direct_info_dictionaries_keep_their_entries() {
+        let info = dictionary! { "Producer" => Object::string_literal("Skia/PDF") };
+        let post_process = PostProcess {
+            metadata: Some(metadata()),
+            ..PostProcess::default()
+        };
+
+        let pdf = post_process.apply(pdf(Some(info.into()))).unwrap();
+        let document = pdf_tools::load(&pdf).unwrap();
+        assert_eq!(info_field(&document, b"Title"), "Quarterly report");
+        assert_eq!(info_field(&document, b"Producer"), "Skia/PDF");
+    }
+
+    #[test]
+    fn encrypted_documents_open_with_the_user_password() {
+        let post_process = PostProcess {
+            metadata: Some(metadata()),
+            encryption: Some(PdfEncryption {
+                owner_password: "owner".to_string(),
+                user_password: "reader".to_string(),
+                permissions: PdfPermissions {
+                    print: false,
+                    ..PdfPermissions::default()
+                },
+            }),
+            ..PostProcess::default()
+        };
+        let pdf = post_process.apply(pdf(None)).unwrap();
+
+        let mut document = Document::load_mem(&pdf).unwrap();
+        let encrypt = document.get_encrypted().unwrap();
+        assert_eq!(encrypt.get(b"V").unwrap().as_i64().unwrap(), 5);
+        assert_eq!(encrypt.get(b"R").unwrap().as_i64().unwrap(), 6);
+        let permissions = encrypt.get(b"P").unwrap().as_i64().unwrap();
+        assert_eq!(permissions & Permissions::PRINTABLE.bits() as i64, 0);
+        assert!(document.decrypt("wrong").is_err());
+
+        let document = Document::load_mem_with_password(&pdf, "reader").unwrap();
+        assert_eq!(info_field(&document, b"Title"), "Quarterly report");
+        let page_id = document.page_iter().next().unwrap();
+        let content = document.get_page_content(page_id).unwrap();
+        assert!(
+            content
+                .windows(16)
+                .any(|window| window == b"Quarterly report")
+        );
+    }
+}
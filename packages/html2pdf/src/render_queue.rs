@@ -0,0 +1,375 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::error::RenderError;
+
+/// Weight of the latest render in the moving average of render durations.
+const HOLD_TIME_SMOOTHING: f64 = 0.2;
+/// Render duration assumed before the first render finished.
+const INITIAL_HOLD_TIME: Duration = Duration::from_secs(2);
+const MAX_RETRY_AFTER_SECS: u64 = 120;
+
+/// Which lane a render waits in. Interactive renders are always served before batch ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Someone is waiting for the response
+    #[default]
+    Interactive,
+    /// Bulk work such as async jobs, or tenants configured as batch
+    Batch,
+}
+
+impl Priority {
+    fn lane(self) -> usize {
+        match self {
+            Priority::Interactive => 0,
+            Priority::Batch => 1,
+        }
+    }
+}
+
+/// Queue occupancy as reported by `/healthz`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueStats {
+    pub capacity: usize,
+    pub available: usize,
+    pub waiting_interactive: usize,
+    pub waiting_batch: usize,
+    pub max_backlog: usize,
+}
+
+/// How long renders waited for a slot since the stats were last taken.
+#[derive(Debug, Clone, Default)]
+pub struct WaitStats {
+    /// Renders that got a slot or gave up waiting for one
+    pub renders: usize,
+    /// Renders among them that had to wait
+    pub waited: usize,
+    pub total_wait: Duration,
+    /// Most slots in use at the same time
+    pub peak_in_use: usize,
+}
+
+impl WaitStats {
+    /// Average wait over all renders, including those that got a slot right away.
+    pub fn average_wait(&self) -> Duration {
+        match self.renders {
+            0 => Duration::ZERO,
+            renders => self.total_wait / renders as u32,
+        }
+    }
+}
+
+struct QueueState {
+    capacity: usize,
+    in_use: usize,
+    /// Waiters per lane, in arrival order
+    waiting: [VecDeque<oneshot::Sender<RenderPermit>>; 2],
+    avg_hold: Duration,
+    wait_stats: WaitStats,
+}
+
+impl QueueState {
+    fn waiting(&self) -> usize {
+        self.waiting.iter().map(VecDeque::len).sum()
+    }
+
+    fn available(&self) -> usize {
+        self.capacity.saturating_sub(self.in_use)
+    }
+
+    fn grant(&mut self) {
+        self.in_use += 1;
+        self.wait_stats.peak_in_use = self.wait_stats.peak_in_use.max(self.in_use);
+    }
+
+    fn record_wait(&mut self, waited: Duration) {
+        self.wait_stats.renders += 1;
+        if !waited.is_zero() {
+            self.wait_stats.waited += 1;
+            self.wait_stats.total_wait += waited;
+        }
+    }
+}
+
+/// Admission control in front of the browser pool: at most `capacity` renders run at once, at
+/// most `max_backlog` wait for a slot, and none waits longer than `max_wait`.
+///
+/// Rejected renders carry a `Retry-After` estimate derived from how long renders currently take.
+/// The capacity may be changed while renders are running; when it shrinks, slots are withdrawn
+/// as their renders finish.
+pub struct RenderQueue {
+    state: Mutex<QueueState>,
+    max_backlog: usize,
+    max_wait: Duration,
+}
+
+/// A render slot; the next waiter gets it when the permit is dropped.
+pub struct RenderPermit {
+    queue: Option<Arc<RenderQueue>>,
+    granted_at: Instant,
+}
+
+impl RenderPermit {
+    fn new(queue: &Arc<RenderQueue>) -> Self {
+        Self {
+            queue: Some(Arc::clone(queue)),
+            granted_at: Instant::now(),
+        }
+    }
+
+    /// Give up the permit without releasing its slot, for a slot that is handed on instead.
+    fn defuse(mut self) {
+        self.queue = None;
+    }
+}
+
+impl Drop for RenderPermit {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            queue.release(self.granted_at.elapsed());
+        }
+    }
+}
+
+impl RenderQueue {
+    pub fn new(capacity: usize, max_backlog: usize, max_wait: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(QueueState {
+                capacity,
+                in_use: 0,
+                waiting: [VecDeque::new(), VecDeque::new()],
+                avg_hold: INITIAL_HOLD_TIME,
+                wait_stats: WaitStats::default(),
+            }),
+            max_backlog,
+            max_wait,
+        })
+    }
+
+    /// Wait for a render slot, or fail fast when the backlog is full.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        priority: Priority,
+    ) -> Result<RenderPermit, RenderError> {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            // Waiters that gave up no longer count against the backlog
+            for lane in &mut state.waiting {
+                lane.retain(|waiter| !waiter.is_closed());
+            }
+
+            if state.available() > 0 {
+                state.grant();
+                state.record_wait(Duration::ZERO);
+                return Ok(RenderPermit::new(self));
+            }
+
+            let waiting = state.waiting();
+            if waiting >= self.max_backlog {
+                return Err(RenderError::QueueFull {
+                    waiting,
+                    retry_after_secs: self.retry_after(&state, waiting),
+                });
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            state.waiting[priority.lane()].push_back(sender);
+            receiver
+        };
+
+        let queued_at = Instant::now();
+        let result = tokio::time::timeout(self.max_wait, receiver).await;
+        let mut state = self.state.lock().unwrap();
+        state.record_wait(queued_at.elapsed());
+
+        match result {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => Err(RenderError::QueueTimeout {
+                waited_ms: self.max_wait.as_millis(),
+                retry_after_secs: self.retry_after(&state, state.waiting()),
+            }),
+        }
+    }
+
+    fn release(self: &Arc<Self>, held: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.avg_hold =
+            state.avg_hold.mul_f64(1.0 - HOLD_TIME_SMOOTHING) + held.mul_f64(HOLD_TIME_SMOOTHING);
+        state.in_use -= 1;
+
+        self.dispatch(&mut state);
+    }
+
+    /// Change how many renders may run at once.
+    pub fn resize(self: &Arc<Self>, capacity: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.capacity = capacity;
+
+        self.dispatch(&mut state);
+    }
+
+    /// Hand free slots to the longest waiting renders of the highest priority.
+    fn dispatch(self: &Arc<Self>, state: &mut QueueState) {
+        while state.available() > 0 {
+            let next = match state.waiting[0].pop_front() {
+                Some(waiter) => Some(waiter),
+                None => state.waiting[1].pop_front(),
+            };
+            let Some(waiter) = next else {
+                return;
+            };
+            // A waiter that timed out or was cancelled returns the permit; try the next one
+            match waiter.send(RenderPermit::new(self)) {
+                Ok(()) => state.grant(),
+                Err(permit) => permit.defuse(),
+            }
+        }
+    }
+
+    /// Wait statistics gathered since the last call, for sizing the pool.
+    pub fn take_wait_stats(&self) -> WaitStats {
+        let mut state = self.state.lock().unwrap();
+        let peak_in_use = state.in_use;
+        std::mem::replace(
+            &mut state.wait_stats,
+            WaitStats {
+                peak_in_use,
+                ..WaitStats::default()
+            },
+        )
+    }
+
+    /// Seconds until a slot is likely to be free for a render queued behind `waiting` others.
+    fn retry_after(&self, state: &QueueState, waiting: usize) -> u64 {
+        let rounds = (waiting + 1).div_ceil(state.capacity.max(1)) as u32;
+        (state.avg_hold * rounds)
+            .as_secs()
+            .clamp(1, MAX_RETRY_AFTER_SECS)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.state.lock().unwrap().capacity
+    }
+
+    pub fn available(&self) -> usize {
+        self.state.lock().unwrap().available()
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        QueueStats {
+            capacity: state.capacity,
+            available: state.available(),
+            waiting_interactive: state.waiting[0].len(),
+            waiting_batch: state.waiting[1].len(),
+            max_backlog: self.max_backlog,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    /// Queue a render on a task of its own and return once it waits in its lane.
+    async fn queued(
+        queue: &Arc<RenderQueue>,
+        priority: Priority,
+    ) -> JoinHandle<Result<RenderPermit, RenderError>> {
+        let waiting = |queue: &RenderQueue| {
+            let stats = queue.stats();
+            stats.waiting_interactive + stats.waiting_batch
+        };
+        let before = waiting(queue);
+
+        let task = tokio::spawn({
+            let queue = Arc::clone(queue);
+            async move { queue.acquire(priority).await }
+        });
+        while waiting(queue) == before {
+            tokio::task::yield_now().await;
+        }
+        task
+    }
+
+    #[tokio::test]
+    async fn interactive_renders_are_served_first() {
+        let queue = RenderQueue::new(1, 10, Duration::from_secs(10));
+        let running = queue.acquire(Priority::Interactive).await.unwrap();
+
+        let batch = queued(&queue, Priority::Batch).await;
+        let interactive = queued(&queue, Priority::Interactive).await;
+
+        drop(running);
+        let permit = interactive.await.unwrap().unwrap();
+        assert_eq!(queue.stats().waiting_batch, 1);
+        assert!(!batch.is_finished());
+
+        drop(permit);
+        assert!(batch.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn full_backlogs_reject_right_away() {
+        let queue = RenderQueue::new(1, 1, Duration::from_secs(10));
+        let _running = queue.acquire(Priority::Interactive).await.unwrap();
+        let _waiting = queued(&queue, Priority::Batch).await;
+
+        let rejected = queue.acquire(Priority::Interactive).await;
+        assert!(matches!(
+            rejected,
+            Err(RenderError::QueueFull { waiting: 1, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn waits_are_bounded() {
+        let queue = RenderQueue::new(1, 10, Duration::from_millis(20));
+        let running = queue.acquire(Priority::Interactive).await.unwrap();
+
+        let timed_out = queue.acquire(Priority::Interactive).await;
+        assert!(matches!(
+            timed_out,
+            Err(RenderError::QueueTimeout { waited_ms: 20, .. })
+        ));
+
+        // The waiter that gave up does not keep the slot once it is free again
+        drop(running);
+        assert_eq!(queue.available(), 1);
+        assert!(queue.acquire(Priority::Batch).await.is_ok());
+        assert_eq!(queue.available(), 1);
+    }
+
+    #[tokio::test]
+    async fn shrinking_withdraws_slots_as_renders_finish() {
+        let queue = RenderQueue::new(2, 10, Duration::from_secs(10));
+        let first = queue.acquire(Priority::Interactive).await.unwrap();
+        let second = queue.acquire(Priority::Interactive).await.unwrap();
+
+        queue.resize(1);
+        assert_eq!(queue.capacity(), 1);
+        assert_eq!(queue.available(), 0);
+
+        drop(first);
+        assert_eq!(queue.available(), 0);
+        drop(second);
+        assert_eq!(queue.available(), 1);
+
+        // Growing again hands the new slot to a waiter straight away
+        let _running = queue.acquire(Priority::Interactive).await.unwrap();
+        let waiter = queued(&queue, Priority::Batch).await;
+        queue.resize(2);
+        assert!(waiter.await.unwrap().is_ok());
+    }
+}
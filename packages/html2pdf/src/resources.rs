@@ -0,0 +1,515 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose};
+use chromiumoxide::{
+    Page,
+    cdp::browser_protocol::{
+        fetch::{
+            ContinueRequestParams, DisableParams, EnableParams, EventRequestPaused,
+            FailRequestParams, FulfillRequestParams, HeaderEntry, RequestPattern,
+        },
+        network::{ErrorReason, ResourceType},
+    },
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::{error::RenderError, url_policy::UrlPolicy};
+
+/// Which subresources a page may fetch while it is rendered.
+///
+/// Requests for attached assets are answered from the request body and never reach the network.
+/// Everything else is checked against `blockTypes` first and `network` second. Every request of a
+/// `url` render, including redirect hops and navigations the page starts itself, also has to
+/// pass the service's URL allow-list, which alone governs its main document.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourcePolicy {
+    #[serde(default)]
+    pub network: NetworkAccess,
+    /// Hosts reachable with `allowList`, written like `URL_ALLOWED_HOSTS` entries
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    /// Kinds of resources that are never loaded
+    #[serde(default)]
+    pub block_types: Vec<BlockedType>,
+    /// Files served in place of the network
+    #[serde(default)]
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NetworkAccess {
+    #[default]
+    All,
+    /// Only attached assets are available
+    None,
+    /// Only `allowedHosts` over http(s), plus attached assets
+    AllowList,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockedType {
+    Image,
+    Media,
+    Font,
+    Script,
+    Stylesheet,
+    /// XHR, fetch, event sources and websockets
+    Fetch,
+}
+
+/// A file the page may reference by `url`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Asset {
+    /// Absolute URL the page requests the asset by
+    pub url: String,
+    /// Sent as `Content-Type`; guessed from the URL when omitted
+    pub content_type: Option<String>,
+    /// Base64 encoded file content
+    pub data: String,
+}
+
+/// What happens to a paused request.
+#[derive(Debug)]
+enum Verdict<'a> {
+    Fulfill(&'a Asset),
+    Continue,
+    Block,
+}
+
+impl BlockedType {
+    fn matches(self, resource_type: &ResourceType) -> bool {
+        match self {
+            BlockedType::Image => *resource_type == ResourceType::Image,
+            BlockedType::Media => {
+                matches!(resource_type, ResourceType::Media | ResourceType::TextTrack)
+            }
+            BlockedType::Font => *resource_type == ResourceType::Font,
+            BlockedType::Script => *resource_type == ResourceType::Script,
+            BlockedType::Stylesheet => *resource_type == ResourceType::Stylesheet,
+            BlockedType::Fetch => matches!(
+                resource_type,
+                ResourceType::Xhr
+                    | ResourceType::Fetch
+                    | ResourceType::EventSource
+                    | ResourceType::WebSocket
+            ),
+        }
+    }
+}
+
+impl ResourcePolicy {
+    /// Whether the page may load anything the way it would without a policy.
+    pub fn is_unrestricted(&self) -> bool {
+        self.network == NetworkAccess::All && self.block_types.is_empty() && self.assets.is_empty()
+    }
+
+    /// Reject policies that can never be applied, before anything is rendered.
+    pub fn validate(&self) -> Result<(), RenderError> {
+        let invalid = |message: String| RenderError::InvalidResourcePolicy(message);
+
+        if self.network == NetworkAccess::AllowList && self.allowed_hosts.is_empty() {
+            return Err(invalid(
+                "network allowList needs at least one allowed host".to_string(),
+            ));
+        }
+        for asset in &self.assets {
+            Url::parse(&asset.url)
+                .map_err(|e| invalid(format!("asset url '{}' is invalid: {}", asset.url, e)))?;
+            general_purpose::STANDARD
+                .decode(asset.data.trim())
+                .map_err(|e| {
+                    invalid(format!("asset '{}' is not valid base64: {}", asset.url, e))
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Decide on a request for `url`. `navigation` is the allow-list every request of a `url`
+    /// render has to pass; without one the main document is treated like any other request.
+    fn verdict<'a>(
+        &'a self,
+        shared: &'a [Asset],
+        navigation: Option<&UrlPolicy>,
+        url: &str,
+        resource_type: &ResourceType,
+        is_main_document: bool,
+    ) -> Verdict<'a> {
+        if let Some(asset) = find_asset(&self.assets, url).or_else(|| find_asset(shared, url)) {
+            return Verdict::Fulfill(asset);
+        }
+
+        if let Some(navigation) = navigation {
+            match Url::parse(url) {
+                Ok(url) if navigation.check(&url).is_ok() => {}
+                _ => return Verdict::Block,
+            }
+            if is_main_document {
+                return Verdict::Continue;
+            }
+        }
+
+        if self
+            .block_types
+            .iter()
+            .any(|blocked| blocked.matches(resource_type))
+        {
+            return Verdict::Block;
+        }
+
+        match self.network {
+            NetworkAccess::All => Verdict::Continue,
+            NetworkAccess::None => Verdict::Block,
+            NetworkAccess::AllowList => {
+                let policy = UrlPolicy {
+                    allowed_schemes: vec!["http".to_string(), "https".to_string()],
+                    allowed_hosts: self.allowed_hosts.clone(),
+                };
+                match Url::parse(url) {
+                    Ok(url) if policy.check(&url).is_ok() => Verdict::Continue,
+                    _ => Verdict::Block,
+                }
+            }
+        }
+    }
+}
+
+/// Answers the requests a page makes according to a [`ResourcePolicy`], until stopped.
+///
+/// Shared assets, such as registered fonts, are served to every page on top of the policy's own.
+/// With an unrestricted policy and no navigation allow-list only requests for them are paused.
+pub struct Interception {
+    task: JoinHandle<()>,
+}
+
+impl Interception {
+    /// Pause requests of `page` and decide on them, or do nothing when there is nothing to
+    /// enforce or serve. `navigation` restricts where anything may be loaded from.
+    pub async fn start(
+        page: &Page,
+        policy: &ResourcePolicy,
+        shared: &Arc<[Asset]>,
+        navigation: Option<&UrlPolicy>,
+    ) -> Result<Option<Self>> {
+        let url_patterns = if !policy.is_unrestricted() || navigation.is_some() {
+            vec!["*".to_string()]
+        } else if !shared.is_empty() {
+            shared.iter().map(|asset| asset.url.clone()).collect()
+        } else {
+            return Ok(None);
+        };
+
+        let mut paused = page.event_listener::<EventRequestPaused>().await?;
+        page.execute(EnableParams {
+            patterns: Some(
+                url_patterns
+                    .into_iter()
+                    .map(|url_pattern| RequestPattern {
+                        url_pattern: Some(url_pattern),
+                        ..RequestPattern::default()
+                    })
+                    .collect(),
+            ),
+            handle_auth_requests: None,
+        })
+        .await?;
+        let main_frame = page.mainframe().await?;
+
+        let page = page.clone();
+        let policy = policy.clone();
+        let shared = Arc::clone(shared);
+        let navigation = navigation.cloned();
+        let task = tokio::spawn(async move {
+            while let Some(event) = paused.next().await {
+                let is_main_document = event.resource_type == ResourceType::Document
+                    && main_frame.as_ref() == Some(&event.frame_id);
+                let verdict = policy.verdict(
+                    &shared,
+                    navigation.as_ref(),
+                    &event.request.url,
+                    &event.resource_type,
+                    is_main_document,
+                );
+                let answered = match verdict {
+                    Verdict::Fulfill(asset) => page.execute(fulfill(&event, asset)).await.map(drop),
+                    Verdict::Continue => page
+                        .execute(ContinueRequestParams::new(event.request_id.clone()))
+                        .await
+                        .map(drop),
+                    Verdict::Block => {
+                        tracing::debug!("Blocked {} by resource policy", event.request.url);
+                        page.execute(FailRequestParams::new(
+                            event.request_id.clone(),
+                            ErrorReason::BlockedByClient,
+                        ))
+                        .await
+                        .map(drop)
+                    }
+                };
+                // The request may have been cancelled by the page in the meantime
+                if let Err(e) = answered {
+                    tracing::debug!("Failed to answer {}: {}", event.request.url, e);
+                }
+            }
+        });
+
+        Ok(Some(Self { task }))
+    }
+
+    /// Let the page fetch freely again, before it goes back to the pool.
+    pub async fn stop(self, page: &Page) -> Result<()> {
+        self.task.abort();
+        page.execute(DisableParams::default()).await?;
+        Ok(())
+    }
+}
+
+impl Drop for Interception {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn find_asset<'a>(assets: &'a [Asset], url: &str) -> Option<&'a Asset> {
+    let mut url = Url::parse(url).ok()?;
+    url.set_fragment(None);
+    assets.iter().find(|asset| {
+        Url::parse(&asset.url).is_ok_and(|mut asset_url| {
+            asset_url.set_fragment(None);
+            asset_url == url
+        })
+    })
+}
+
+fn fulfill(event: &EventRequestPaused, asset: &Asset) -> FulfillRequestParams {
+    let content_type = asset
+        .content_type
+        .clone()
+        .unwrap_or_else(|| guess_content_type(&asset.url).to_string());
+
+    FulfillRequestParams {
+        response_headers: Some(vec![
+            HeaderEntry::new("Content-Type", content_type),
+            // Fonts and scripts of another origin are only used with CORS headers
+            HeaderEntry::new("Access-Control-Allow-Origin", "*"),
+        ]),
+        body: Some(asset.data.trim().to_string().into()),
+        ..FulfillRequestParams::new(event.request_id.clone(), 200)
+    }
+}
+
+fn guess_content_type(url: &str) -> &'static str {
+    let path = Url::parse(url)
+        .map(|url| url.path().to_lowercase())
+        .unwrap_or_default();
+    let extension = path.rsplit_once('.').map_or("", |(_, extension)| extension);
+
+    match extension {
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asset(url: &str) -> Asset {
+        Asset {
+            url: url.to_string(),
+            content_type: None,
+            data: String::new(),
+        }
+    }
+
+    fn policy(network: NetworkAccess, allowed_hosts: &[&str]) -> ResourcePolicy {
+        ResourcePolicy {
+            network,
+            allowed_hosts: allowed_hosts.iter().map(|host| host.to_string()).collect(),
+            ..ResourcePolicy::default()
+        }
+    }
+
+    fn subresource<'a>(policy: &'a ResourcePolicy, url: &str, kind: ResourceType) -> Verdict<'a> {
+        policy.verdict(&[], None, url, &kind, false)
+    }
+
+    #[test]
+    fn find_asset_ignores_fragments() {
+        let assets = [asset("https://bundle.invalid/icons.svg#logo")];
+
+        assert!(find_asset(&assets, "https://bundle.invalid/icons.svg").is_some());
+        assert!(find_asset(&assets, "https://bundle.invalid/icons.svg#menu").is_some());
+        assert!(find_asset(&assets, "https://bundle.invalid/icons.svg?v=2").is_none());
+        assert!(find_asset(&assets, "https://bundle.invalid/other.svg").is_none());
+        assert!(find_asset(&assets, "not a url").is_none());
+    }
+
+    #[test]
+    fn assets_are_served_without_network() {
+        let mut policy = policy(NetworkAccess::None, &[]);
+        policy
+            .assets
+            .push(asset("https://bundle.invalid/style.css"));
+        let shared = [asset("https://fonts.invalid/Inter/400-normal.woff2")];
+
+        let own = policy.verdict(
+            &shared,
+            None,
+            "https://bundle.invalid/style.css",
+            &ResourceType::Stylesheet,
+            false,
+        );
+        assert!(matches!(own, Verdict::Fulfill(asset) if asset.url.ends_with("style.css")));
+
+        let font = policy.verdict(
+            &shared,
+            None,
+            "https://fonts.invalid/Inter/400-normal.woff2",
+            &ResourceType::Font,
+            false,
+        );
+        assert!(matches!(font, Verdict::Fulfill(_)));
+
+        let other = subresource(
+            &policy,
+            "https://cdn.example.com/app.js",
+            ResourceType::Script,
+        );
+        assert!(matches!(other, Verdict::Block));
+    }
+
+    #[test]
+    fn block_types_apply_before_network() {
+        let mut policy = policy(NetworkAccess::AllowList, &["cdn.example.com"]);
+        policy.block_types = vec![BlockedType::Script, BlockedType::Fetch];
+
+        let script = subresource(
+            &policy,
+            "https://cdn.example.com/app.js",
+            ResourceType::Script,
+        );
+        assert!(matches!(script, Verdict::Block));
+        let xhr = subresource(&policy, "https://cdn.example.com/api", ResourceType::Xhr);
+        assert!(matches!(xhr, Verdict::Block));
+        let image = subresource(
+            &policy,
+            "https://cdn.example.com/logo.png",
+            ResourceType::Image,
+        );
+        assert!(matches!(image, Verdict::Continue));
+    }
+
+    #[test]
+    fn allow_list_matches_wildcards_and_ports() {
+        let policy = policy(
+            NetworkAccess::AllowList,
+            &["*.example.com", "localhost:8080"],
+        );
+        let verdict = |url| subresource(&policy, url, ResourceType::Image);
+
+        assert!(matches!(
+            verdict("https://cdn.example.com/a.png"),
+            Verdict::Continue
+        ));
+        assert!(matches!(
+            verdict("http://a.b.example.com/a.png"),
+            Verdict::Continue
+        ));
+        assert!(matches!(
+            verdict("https://example.com/a.png"),
+            Verdict::Block
+        ));
+        assert!(matches!(
+            verdict("https://badexample.com/a.png"),
+            Verdict::Block
+        ));
+        assert!(matches!(
+            verdict("http://localhost:8080/a.png"),
+            Verdict::Continue
+        ));
+        assert!(matches!(
+            verdict("http://localhost:9090/a.png"),
+            Verdict::Block
+        ));
+        assert!(matches!(
+            verdict("ftp://cdn.example.com/a.png"),
+            Verdict::Block
+        ));
+    }
+
+    #[test]
+    fn main_document_of_url_renders_needs_the_navigation_allow_list() {
+        let navigation = UrlPolicy {
+            allowed_schemes: vec!["https".to_string()],
+            allowed_hosts: vec!["reports.example.com".to_string()],
+        };
+        // The resource policy alone would let everything through
+        let policy = ResourcePolicy::default();
+        let document =
+            |url| policy.verdict(&[], Some(&navigation), url, &ResourceType::Document, true);
+
+        assert!(matches!(
+            document("https://reports.example.com/q3"),
+            Verdict::Continue
+        ));
+        // Redirect hops and navigations the page starts are paused like the first request
+        assert!(matches!(
+            document("http://169.254.169.254/latest/meta-data"),
+            Verdict::Block
+        ));
+        assert!(matches!(
+            document("http://reports.example.com/q3"),
+            Verdict::Block
+        ));
+    }
+
+    #[test]
+    fn subresources_of_url_renders_need_the_navigation_allow_list() {
+        let navigation = UrlPolicy {
+            allowed_schemes: vec!["https".to_string()],
+            allowed_hosts: vec!["*.example.com".to_string()],
+        };
+        let policy = ResourcePolicy {
+            block_types: vec![BlockedType::Media],
+            ..ResourcePolicy::default()
+        };
+        let verdict = |url, kind| policy.verdict(&[], Some(&navigation), url, &kind, false);
+
+        let image = verdict("https://cdn.example.com/logo.png", ResourceType::Image);
+        assert!(matches!(image, Verdict::Continue));
+        let internal = verdict("http://10.0.0.1/admin", ResourceType::Image);
+        assert!(matches!(internal, Verdict::Block));
+        // The resource policy still applies to allowed hosts
+        let video = verdict("https://cdn.example.com/intro.mp4", ResourceType::Media);
+        assert!(matches!(video, Verdict::Block));
+    }
+
+    #[test]
+    fn main_document_without_navigation_follows_the_resource_policy() {
+        let policy = policy(NetworkAccess::None, &[]);
+
+        let document = policy.verdict(&[], None, "http://10.0.0.1/", &ResourceType::Document, true);
+        assert!(matches!(document, Verdict::Block));
+    }
+}
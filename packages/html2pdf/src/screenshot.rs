@@ -0,0 +1,329 @@
+use chromiumoxide::cdp::browser_protocol::{
+    emulation::SetDeviceMetricsOverrideParams,
+    page::{CaptureScreenshotFormat, CaptureScreenshotParams, Viewport},
+};
+use serde::Deserialize;
+
+use crate::error::RenderError;
+
+const DEFAULT_VIEWPORT: ViewportSize = ViewportSize {
+    width: 1280,
+    height: 800,
+};
+const MAX_VIEWPORT_SIDE: u32 = 10_000;
+const MAX_DEVICE_SCALE_FACTOR: f64 = 4.0;
+/// Longest side of a captured image in device pixels, beyond which Chrome's bitmaps fail.
+const MAX_IMAGE_SIDE: f64 = 16_384.0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ImageFormat {
+    pub fn mime(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Webp => "webp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ViewportSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Region of the page in CSS pixels, relative to the top left corner of the document.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Clip {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// How a page is captured as an image.
+///
+/// Without `fullPage` or `clip` only the viewport is captured. The image is
+/// `deviceScaleFactor` times the captured size in CSS pixels. Images beyond the configured
+/// maximum are rejected, except full pages, whose size is only known once laid out; those are
+/// cut off to fit.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScreenshotOptions {
+    #[serde(default)]
+    pub format: ImageFormat,
+    /// 0-100, for `jpeg` and `webp` only
+    pub quality: Option<u8>,
+    #[serde(default)]
+    pub full_page: bool,
+    pub clip: Option<Clip>,
+    /// Size of the browser window the page is laid out in, defaults to 1280x800
+    pub viewport: Option<ViewportSize>,
+    pub device_scale_factor: Option<f64>,
+}
+
+impl ScreenshotOptions {
+    /// Check the options, with `max_pixels` the largest image that may be captured.
+    pub fn validate(&self, max_pixels: u64) -> Result<(), RenderError> {
+        let invalid = |message: &str| Err(RenderError::InvalidImageOptions(message.to_string()));
+
+        if self.quality.is_some() && self.format == ImageFormat::Png {
+            return invalid("quality is only supported for jpeg and webp");
+        }
+        if self.quality.is_some_and(|quality| quality > 100) {
+            return invalid("quality must be between 0 and 100");
+        }
+        if self.full_page && self.clip.is_some() {
+            return invalid("provide either fullPage or clip, not both");
+        }
+        if let Some(clip) = self.clip {
+            let values = [clip.x, clip.y, clip.width, clip.height];
+            if values
+                .iter()
+                .any(|value| !value.is_finite() || *value < 0.0)
+                || clip.width == 0.0
+                || clip.height == 0.0
+            {
+                return invalid("clip needs a non-negative position and a positive size");
+            }
+        }
+        let viewport = self.viewport();
+        if !(1..=MAX_VIEWPORT_SIDE).contains(&viewport.width)
+            || !(1..=MAX_VIEWPORT_SIDE).contains(&viewport.height)
+        {
+            return invalid("viewport sides must be between 1 and 10000 pixels");
+        }
+        if !(self.device_scale_factor() > 0.0
+            && self.device_scale_factor() <= MAX_DEVICE_SCALE_FACTOR)
+        {
+            return invalid("deviceScaleFactor must be greater than 0 and at most 4");
+        }
+
+        let captured = match self.clip {
+            Some(clip) => Some((clip.width, clip.height)),
+            None if !self.full_page => Some((viewport.width as f64, viewport.height as f64)),
+            None => None,
+        };
+        if let Some((width, height)) = captured {
+            let scale = self.device_scale_factor();
+            let (width, height) = ((width * scale).ceil(), (height * scale).ceil());
+            if width > MAX_IMAGE_SIDE || height > MAX_IMAGE_SIDE {
+                return Err(RenderError::InvalidImageOptions(format!(
+                    "image would be {}x{} pixels, sides may be at most {} pixels",
+                    width, height, MAX_IMAGE_SIDE
+                )));
+            }
+            if width * height > max_pixels as f64 {
+                return Err(RenderError::InvalidImageOptions(format!(
+                    "image would be {}x{} pixels, at most {} pixels are allowed",
+                    width, height, max_pixels
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Largest part of a `width` x `height` CSS pixel document that fits the image limits,
+    /// keeping the full width where possible and cutting off the bottom.
+    fn fit_full_page(&self, (width, height): (f64, f64), max_pixels: u64) -> (f64, f64) {
+        let scale = self.device_scale_factor();
+        let max_side = MAX_IMAGE_SIDE / scale;
+
+        let width = width.min(max_side);
+        let max_height = max_pixels as f64 / (width.max(1.0) * scale * scale);
+        let height = height.min(max_side).min(max_height);
+
+        (width, height)
+    }
+
+    fn viewport(&self) -> ViewportSize {
+        self.viewport.unwrap_or(DEFAULT_VIEWPORT)
+    }
+
+    fn device_scale_factor(&self) -> f64 {
+        self.device_scale_factor.unwrap_or(1.0)
+    }
+
+    /// Emulated window the page is laid out in before capturing.
+    pub fn device_metrics(&self) -> SetDeviceMetricsOverrideParams {
+        let viewport = self.viewport();
+        SetDeviceMetricsOverrideParams::new(
+            viewport.width as i64,
+            viewport.height as i64,
+            self.device_scale_factor(),
+            false,
+        )
+    }
+
+    /// Capture parameters; `content_size` is the size of the laid out document in CSS pixels,
+    /// used for full page captures, which are cut off to stay within `max_pixels`.
+    pub fn capture_params(
+        &self,
+        content_size: (f64, f64),
+        max_pixels: u64,
+    ) -> CaptureScreenshotParams {
+        let clip = match (self.full_page, self.clip) {
+            (true, _) => {
+                let (width, height) = self.fit_full_page(content_size, max_pixels);
+                if (width, height) != content_size {
+                    tracing::debug!(
+                        "Cutting full page capture of {}x{} down to {}x{}",
+                        content_size.0,
+                        content_size.1,
+                        width,
+                        height
+                    );
+                }
+                Some(Viewport {
+                    x: 0.0,
+                    y: 0.0,
+                    width,
+                    height,
+                    scale: 1.0,
+                })
+            }
+            (false, Some(clip)) => Some(Viewport {
+                x: clip.x,
+                y: clip.y,
+                width: clip.width,
+                height: clip.height,
+                scale: 1.0,
+            }),
+            (false, None) => None,
+        };
+
+        CaptureScreenshotParams {
+            format: Some(match self.format {
+                ImageFormat::Png => CaptureScreenshotFormat::Png,
+                ImageFormat::Jpeg => CaptureScreenshotFormat::Jpeg,
+                ImageFormat::Webp => CaptureScreenshotFormat::Webp,
+            }),
+            quality: self.quality.map(i64::from),
+            // Clips may reach beyond the viewport
+            capture_beyond_viewport: Some(clip.is_some()),
+            clip,
+            from_surface: Some(true),
+            optimize_for_speed: None,
+        }
+    }
+
+    pub fn needs_content_size(&self) -> bool {
+        self.full_page
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_PIXELS: u64 = 40_000_000;
+
+    fn options(json: serde_json::Value) -> ScreenshotOptions {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn rejected(json: serde_json::Value) -> bool {
+        matches!(
+            options(json).validate(MAX_PIXELS),
+            Err(RenderError::InvalidImageOptions(_))
+        )
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(options(serde_json::json!({})).validate(MAX_PIXELS).is_ok());
+        assert!(
+            options(serde_json::json!({"fullPage": true, "deviceScaleFactor": 4}))
+                .validate(MAX_PIXELS)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn invalid_combinations_are_rejected() {
+        assert!(rejected(serde_json::json!({"quality": 80})));
+        assert!(rejected(
+            serde_json::json!({"format": "jpeg", "quality": 101})
+        ));
+        assert!(rejected(serde_json::json!({
+            "fullPage": true,
+            "clip": {"x": 0, "y": 0, "width": 10, "height": 10}
+        })));
+        assert!(rejected(serde_json::json!({
+            "clip": {"x": -1, "y": 0, "width": 10, "height": 10}
+        })));
+        assert!(rejected(serde_json::json!({
+            "clip": {"x": 0, "y": 0, "width": 0, "height": 10}
+        })));
+        assert!(rejected(
+            serde_json::json!({"viewport": {"width": 0, "height": 800}})
+        ));
+        assert!(rejected(
+            serde_json::json!({"viewport": {"width": 10001, "height": 800}})
+        ));
+        assert!(rejected(serde_json::json!({"deviceScaleFactor": 0})));
+        assert!(rejected(serde_json::json!({"deviceScaleFactor": 4.5})));
+    }
+
+    #[test]
+    fn oversized_images_are_rejected() {
+        // Within the viewport limit, but 40000 pixels wide once scaled
+        assert!(rejected(serde_json::json!({
+            "viewport": {"width": 10000, "height": 800},
+            "deviceScaleFactor": 4
+        })));
+        assert!(rejected(serde_json::json!({
+            "clip": {"x": 0, "y": 0, "width": 1000, "height": 500000}
+        })));
+        // Both sides fit, the area does not
+        assert!(rejected(serde_json::json!({
+            "clip": {"x": 0, "y": 0, "width": 8000, "height": 8000}
+        })));
+        assert!(
+            options(serde_json::json!({
+                "clip": {"x": 0, "y": 0, "width": 4000, "height": 8000}
+            }))
+            .validate(MAX_PIXELS)
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn full_pages_are_cut_off_to_fit() {
+        let options = options(serde_json::json!({"fullPage": true, "deviceScaleFactor": 4}));
+
+        let (width, height) = options.fit_full_page((1280.0, 500_000.0), MAX_PIXELS);
+        assert_eq!(width, 1280.0);
+        assert!(height * 4.0 <= MAX_IMAGE_SIDE);
+        assert!(width * height * 16.0 <= MAX_PIXELS as f64);
+
+        let (width, height) = options.fit_full_page((50_000.0, 100.0), MAX_PIXELS);
+        assert_eq!((width, height), (MAX_IMAGE_SIDE / 4.0, 100.0));
+
+        // Small documents are captured as they are
+        assert_eq!(
+            options.fit_full_page((1280.0, 1500.0), MAX_PIXELS),
+            (1280.0, 1500.0)
+        );
+
+        let clip = options.capture_params((1280.0, 500_000.0), MAX_PIXELS).clip;
+        assert!(clip.is_some_and(|clip| clip.height * 4.0 <= MAX_IMAGE_SIDE));
+    }
+}
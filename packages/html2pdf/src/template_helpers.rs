@@ -0,0 +1,177 @@
+use std::fmt::Write as _;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use handlebars::{Handlebars, handlebars_helper};
+use serde_json::Value;
+
+handlebars_helper!(format_date: |value: Json, { format: str = "%Y-%m-%d" }| {
+    render_date(value, format)
+});
+
+handlebars_helper!(format_number: |value: f64, { decimals: u64 = 2, thousands: str = ",", decimal: str = "." }| {
+    render_number(value, decimals as usize, thousands, decimal)
+});
+
+handlebars_helper!(format_currency: |value: f64, currency: str, { decimals: u64 = 2, thousands: str = ",", decimal: str = "." }| {
+    // The sign goes in front of the symbol, and only if the rounded number is not zero
+    let number = render_number(value, decimals as usize, thousands, decimal);
+    let (sign, digits) = match number.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", number.as_str()),
+    };
+    format!("{}{}{}", sign, currency_symbol(currency), digits)
+});
+
+/// Register the formatting helpers available to every template:
+///
+/// * `{{formatDate value format="%d.%m.%Y"}}` for RFC 3339 strings, `YYYY-MM-DD` dates and
+///   unix timestamps (seconds)
+/// * `{{formatNumber value decimals=2 thousands="," decimal="."}}`
+/// * `{{formatCurrency value "EUR" decimals=2 thousands="," decimal="."}}`
+pub fn register(registry: &mut Handlebars) {
+    registry.register_helper("formatDate", Box::new(format_date));
+    registry.register_helper("formatNumber", Box::new(format_number));
+    registry.register_helper("formatCurrency", Box::new(format_currency));
+}
+
+fn render_date(value: &Value, format: &str) -> String {
+    let parsed = match value {
+        Value::Number(n) => n
+            .as_i64()
+            .and_then(|secs| DateTime::<Utc>::from_timestamp(secs, 0))
+            .map(|dt| dt.naive_utc()),
+        Value::String(s) => DateTime::parse_from_rfc3339(s)
+            .map(|dt| dt.naive_local())
+            .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S"))
+            .or_else(|_| {
+                NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| d.and_time(Default::default()))
+            })
+            .ok(),
+        _ => None,
+    };
+
+    let Some(parsed) = parsed else {
+        // Leave values we cannot interpret as they are rather than failing the whole render
+        return match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+    };
+
+    // Writing instead of `to_string` so that a bad format string cannot panic
+    let mut out = String::new();
+    match write!(out, "{}", parsed.format(format)) {
+        Ok(()) => out,
+        Err(_) => parsed.format("%Y-%m-%d").to_string(),
+    }
+}
+
+fn render_number(value: f64, decimals: usize, thousands: &str, decimal: &str) -> String {
+    let formatted = format!("{:.*}", decimals, value.abs());
+    let (integer, fraction) = match formatted.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (formatted.as_str(), None),
+    };
+
+    let mut grouped = String::new();
+    for (i, digit) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            grouped.push_str(thousands);
+        }
+        grouped.push(digit);
+    }
+
+    let sign = if value < 0.0 && formatted.chars().any(|c| c.is_ascii_digit() && c != '0') {
+        "-"
+    } else {
+        ""
+    };
+
+    match fraction {
+        Some(fraction) => format!("{}{}{}{}", sign, grouped, decimal, fraction),
+        None => format!("{}{}", sign, grouped),
+    }
+}
+
+fn currency_symbol(currency: &str) -> String {
+    match currency.to_ascii_uppercase().as_str() {
+        "EUR" => "€".to_string(),
+        "USD" => "$".to_string(),
+        "GBP" => "£".to_string(),
+        "JPY" | "CNY" => "¥".to_string(),
+        "INR" => "₹".to_string(),
+        other => format!("{} ", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn render(template: &str, data: Value) -> String {
+        let mut registry = Handlebars::new();
+        register(&mut registry);
+        registry.render_template(template, &data).unwrap()
+    }
+
+    #[test]
+    fn dates_from_strings_and_timestamps() {
+        let date = |value| {
+            render(
+                r#"{{formatDate value format="%d.%m.%Y"}}"#,
+                json!({ "value": value }),
+            )
+        };
+
+        assert_eq!(date(json!("2024-03-01")), "01.03.2024");
+        assert_eq!(date(json!("2024-03-01T23:30:00+02:00")), "01.03.2024");
+        assert_eq!(date(json!("2024-03-01T08:00:00")), "01.03.2024");
+        assert_eq!(date(json!(1_709_251_200)), "01.03.2024");
+        assert_eq!(
+            render("{{formatDate value}}", json!({ "value": "2024-03-01" })),
+            "2024-03-01"
+        );
+        // Values that are no date are left alone
+        assert_eq!(date(json!("soon")), "soon");
+    }
+
+    #[test]
+    fn numbers_are_grouped_and_rounded() {
+        let number = |template, value| render(template, json!({ "value": value }));
+
+        assert_eq!(
+            number("{{formatNumber value}}", json!(1234567.891)),
+            "1,234,567.89"
+        );
+        assert_eq!(
+            number(
+                r#"{{formatNumber value decimals=1 thousands="." decimal=","}}"#,
+                json!(-1234.56)
+            ),
+            "-1.234,6"
+        );
+        assert_eq!(
+            number("{{formatNumber value decimals=0}}", json!(999.5)),
+            "1,000"
+        );
+        assert_eq!(number("{{formatNumber value}}", json!(-0.001)), "0.00");
+    }
+
+    #[test]
+    fn currencies_put_the_sign_before_the_symbol() {
+        let currency = |value, code| {
+            render(
+                "{{formatCurrency value code}}",
+                json!({ "value": value, "code": code }),
+            )
+        };
+
+        assert_eq!(currency(json!(1234.5), "EUR"), "€1,234.50");
+        assert_eq!(currency(json!(-1234.5), "usd"), "-$1,234.50");
+        assert_eq!(currency(json!(12), "CHF"), "CHF 12.00");
+        // Rounds to zero, so there is nothing negative left to show
+        assert_eq!(currency(json!(-0.001), "USD"), "$0.00");
+    }
+}
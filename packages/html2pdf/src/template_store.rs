@@ -0,0 +1,355 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use handlebars::Handlebars;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::{
+    fs,
+    sync::{Mutex, RwLock},
+};
+
+use crate::{error::RenderError, template_helpers};
+
+const TEMPLATE_FILE: &str = "template.hbs";
+const PARTIALS_DIR: &str = "partials";
+const PARTIAL_EXTENSION: &str = "hbs";
+
+/// Versioned Handlebars templates kept on disk.
+///
+/// Every version is an immutable directory holding the template together with its own partials,
+/// so rendering an old version always produces the same HTML:
+///
+/// ```text
+/// {dir}/{name}/{version}/template.hbs
+/// {dir}/{name}/{version}/partials/{partial}.hbs
+/// ```
+///
+/// Versions can be dropped onto disk by hand or created through [`TemplateStore::create_version`].
+pub struct TemplateStore {
+    dir: PathBuf,
+    compiled: RwLock<HashMap<(String, u32), Arc<Handlebars<'static>>>>,
+    write_lock: Mutex<()>,
+}
+
+#[derive(Serialize)]
+pub struct TemplateInfo {
+    pub name: String,
+    pub versions: Vec<u32>,
+    pub latest: Option<u32>,
+}
+
+impl TemplateStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            compiled: RwLock::new(HashMap::new()),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    pub async fn list(&self) -> Result<Vec<TemplateInfo>> {
+        let mut templates = Vec::new();
+
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(templates),
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if entry.file_type().await?.is_dir() && is_valid_name(&name) {
+                templates.push(self.info(&name).await?);
+            }
+        }
+
+        templates.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(templates)
+    }
+
+    pub async fn info(&self, name: &str) -> Result<TemplateInfo> {
+        let versions = self.versions(name).await?;
+
+        Ok(TemplateInfo {
+            name: name.to_string(),
+            latest: versions.last().copied(),
+            versions,
+        })
+    }
+
+    /// Store a new version of `name` and return its number. The template and its partials are
+    /// compiled first, so broken templates are rejected instead of stored.
+    pub async fn create_version(
+        &self,
+        name: &str,
+        source: &str,
+        partials: &BTreeMap<String, String>,
+    ) -> Result<u32> {
+        validate_name(name)?;
+        for partial in partials.keys() {
+            validate_name(partial)?;
+        }
+        compile(source, partials)?;
+
+        let _guard = self.write_lock.lock().await;
+
+        let template_dir = self.dir.join(name);
+        fs::create_dir_all(&template_dir).await?;
+
+        let version = self.versions(name).await?.last().map_or(1, |v| v + 1);
+
+        // Write into a scratch directory first so a version never becomes visible half-written
+        let staging_dir = template_dir.join(format!(".staging-{}", version));
+        if fs::try_exists(&staging_dir).await? {
+            fs::remove_dir_all(&staging_dir).await?;
+        }
+        fs::create_dir_all(staging_dir.join(PARTIALS_DIR)).await?;
+        fs::write(staging_dir.join(TEMPLATE_FILE), source).await?;
+        for (partial, partial_source) in partials {
+            let file = format!("{}.{}", partial, PARTIAL_EXTENSION);
+            fs::write(staging_dir.join(PARTIALS_DIR).join(file), partial_source).await?;
+        }
+        fs::rename(&staging_dir, template_dir.join(version.to_string())).await?;
+
+        tracing::info!("Stored template {} version {}", name, version);
+
+        Ok(version)
+    }
+
+    /// Render `name` with `data`, using `version` or the latest one. Returns the version that
+    /// was used together with the HTML.
+    pub async fn render(
+        &self,
+        name: &str,
+        version: Option<u32>,
+        data: &Value,
+    ) -> Result<(u32, String)> {
+        validate_name(name)?;
+
+        let version = match version {
+            Some(version) => version,
+            None => self
+                .versions(name)
+                .await?
+                .last()
+                .copied()
+                .ok_or_else(|| RenderError::TemplateNotFound(name.to_string()))?,
+        };
+
+        let registry = self.compiled(name, version).await?;
+        let html = registry
+            .render(TEMPLATE_FILE, data)
+            .map_err(|e| RenderError::InvalidTemplate(e.to_string()))?;
+
+        Ok((version, html))
+    }
+
+    async fn versions(&self, name: &str) -> Result<Vec<u32>> {
+        validate_name(name)?;
+
+        let mut versions = Vec::new();
+        let mut entries = match fs::read_dir(self.dir.join(name)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(versions),
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(version) = entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+                versions.push(version);
+            }
+        }
+
+        versions.sort_unstable();
+        Ok(versions)
+    }
+
+    async fn compiled(&self, name: &str, version: u32) -> Result<Arc<Handlebars<'static>>> {
+        let key = (name.to_string(), version);
+
+        if let Some(registry) = self.compiled.read().await.get(&key) {
+            return Ok(Arc::clone(registry));
+        }
+
+        let version_dir = self.dir.join(name).join(version.to_string());
+        let source = match fs::read_to_string(version_dir.join(TEMPLATE_FILE)).await {
+            Ok(source) => source,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(
+                    RenderError::TemplateNotFound(format!("{} version {}", name, version)).into(),
+                );
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let partials = read_partials(&version_dir.join(PARTIALS_DIR)).await?;
+
+        // Versions are immutable, so a compiled registry never goes stale
+        let registry = Arc::new(compile(&source, &partials)?);
+        self.compiled
+            .write()
+            .await
+            .insert(key, Arc::clone(&registry));
+
+        Ok(registry)
+    }
+}
+
+fn compile(source: &str, partials: &BTreeMap<String, String>) -> Result<Handlebars<'static>> {
+    let mut registry = Handlebars::new();
+    template_helpers::register(&mut registry);
+
+    for (name, partial) in partials {
+        registry
+            .register_partial(name, partial)
+            .map_err(|e| RenderError::InvalidTemplate(format!("partial {}: {}", name, e)))?;
+    }
+    registry
+        .register_template_string(TEMPLATE_FILE, source)
+        .map_err(|e| RenderError::InvalidTemplate(e.to_string()))?;
+
+    Ok(registry)
+}
+
+async fn read_partials(dir: &Path) -> Result<BTreeMap<String, String>> {
+    let mut partials = BTreeMap::new();
+
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(partials),
+        Err(e) => return Err(e.into()),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(PARTIAL_EXTENSION) {
+            continue;
+        }
+        if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+            partials.insert(name.to_string(), fs::read_to_string(&path).await?);
+        }
+    }
+
+    Ok(partials)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 128
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn validate_name(name: &str) -> Result<(), RenderError> {
+    if is_valid_name(name) {
+        Ok(())
+    } else {
+        Err(RenderError::InvalidTemplate(format!(
+            "invalid name '{}', use letters, digits, '-' and '_'",
+            name
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn partials(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(name, source)| (name.to_string(), source.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn versions_are_numbered_and_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TemplateStore::new(dir.path());
+
+        let header = partials(&[("header", "<h1>{{title}}</h1>")]);
+        let first = store
+            .create_version("invoice", "{{> header}}v1", &header)
+            .await
+            .unwrap();
+        let second = store
+            .create_version("invoice", "{{> header}}v2", &header)
+            .await
+            .unwrap();
+        assert_eq!((first, second), (1, 2));
+
+        let data = json!({ "title": "Invoice" });
+        let (version, html) = store.render("invoice", None, &data).await.unwrap();
+        assert_eq!((version, html.as_str()), (2, "<h1>Invoice</h1>v2"));
+        let (version, html) = store.render("invoice", Some(1), &data).await.unwrap();
+        assert_eq!((version, html.as_str()), (1, "<h1>Invoice</h1>v1"));
+
+        let info = store.info("invoice").await.unwrap();
+        assert_eq!(info.versions, vec![1, 2]);
+        assert_eq!(info.latest, Some(2));
+    }
+
+    #[tokio::test]
+    async fn staging_directories_are_not_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TemplateStore::new(dir.path());
+
+        // Left behind by an interrupted write
+        std::fs::create_dir_all(dir.path().join("report/.staging-1")).unwrap();
+        assert!(store.info("report").await.unwrap().versions.is_empty());
+
+        let version = store
+            .create_version("report", "ok", &BTreeMap::new())
+            .await
+            .unwrap();
+        assert_eq!(version, 1);
+        assert!(!dir.path().join("report/.staging-1").exists());
+        assert!(dir.path().join("report/1/template.hbs").exists());
+
+        let listed: Vec<_> = store
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|info| info.name)
+            .collect();
+        assert_eq!(listed, vec!["report"]);
+    }
+
+    #[tokio::test]
+    async fn broken_templates_and_bad_names_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TemplateStore::new(dir.path());
+        let none = BTreeMap::new();
+
+        assert!(
+            store
+                .create_version("broken", "{{#if}}", &none)
+                .await
+                .is_err()
+        );
+        assert!(store.create_version("../escape", "x", &none).await.is_err());
+        assert!(
+            store
+                .create_version("report", "x", &partials(&[("../up", "x")]))
+                .await
+                .is_err()
+        );
+        assert!(store.list().await.unwrap().is_empty());
+
+        let missing = store.render("missing", None, &json!({})).await.unwrap_err();
+        assert!(matches!(
+            missing.downcast_ref(),
+            Some(RenderError::TemplateNotFound(_))
+        ));
+    }
+}
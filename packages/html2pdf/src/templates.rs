@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    Json,
+    extract::{Extension, Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    AdminAccess, AppState,
+    browser_pool::RenderSource,
+    error::HttpError,
+    html2pdf::{PdfOptions, pdf_reply},
+    render_queue::Priority,
+    template_store::TemplateInfo,
+};
+
+/// Response header telling callers which template version produced the document.
+const TEMPLATE_VERSION_HEADER: &str = "x-template-version";
+
+#[derive(Deserialize)]
+pub struct CreateTemplateRequest {
+    pub source: String,
+    #[serde(default)]
+    pub partials: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+pub struct CreateTemplateResponse {
+    pub name: String,
+    pub version: u32,
+}
+
+#[derive(Deserialize)]
+pub struct RenderTemplateRequest {
+    #[serde(default)]
+    pub data: Value,
+    /// Version to render, the latest one when omitted
+    pub version: Option<u32>,
+    #[serde(flatten)]
+    pub options: PdfOptions,
+}
+
+pub async fn list_templates(
+    State(app_state): State<AppState>,
+) -> Result<Json<Vec<TemplateInfo>>, HttpError> {
+    Ok(Json(app_state.template_store.list().await?))
+}
+
+pub async fn get_template(
+    State(app_state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<TemplateInfo>, HttpError> {
+    let info = app_state.template_store.info(&name).await?;
+
+    if info.versions.is_empty() {
+        return Err(HttpError::NotFound(anyhow::anyhow!(
+            "Template {} not found",
+            name
+        )));
+    }
+
+    Ok(Json(info))
+}
+
+/// Publish a new version of template `name`. Templates are shared by every tenant, so only
+/// callers with one of the `ADMIN_ROLES` may change them.
+pub async fn create_template(
+    State(app_state): State<AppState>,
+    Extension(AdminAccess(is_admin)): Extension<AdminAccess>,
+    Path(name): Path<String>,
+    Json(payload): Json<CreateTemplateRequest>,
+) -> Result<(StatusCode, Json<CreateTemplateResponse>), HttpError> {
+    if !is_admin {
+        return Err(HttpError::Forbidden(anyhow::anyhow!(
+            "Registering templates needs an admin role"
+        )));
+    }
+
+    let version = app_state
+        .template_store
+        .create_version(&name, &payload.source, &payload.partials)
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateTemplateResponse { name, version }),
+    ))
+}
+
+pub async fn render_template(
+    State(app_state): State<AppState>,
+    Extension(priority): Extension<Priority>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<RenderTemplateRequest>,
+) -> Result<Response, HttpError> {
+    tracing::debug!("Received template render request for {}", name);
+
+    let (version, html) = app_state
+        .template_store
+        .render(&name, payload.version, &payload.data)
+        .await?;
+
+    let mut response = pdf_reply(
+        &app_state,
+        &headers,
+        RenderSource::Html(html),
+        payload.options,
+        priority,
+    )
+    .await?;
+    response
+        .headers_mut()
+        .insert(TEMPLATE_VERSION_HEADER, HeaderValue::from(version));
+
+    Ok(response)
+}
@@ -0,0 +1,172 @@
+use std::{io::Cursor, path::Path};
+
+use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose};
+use pdfium_render::prelude::{PdfRenderConfig, Pdfium};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::RenderError, pdf_tools::PageRange};
+
+const DEFAULT_WIDTH: u32 = 200;
+const MAX_WIDTH: u32 = 2000;
+/// Tall pages are scaled down to this height, so a thumbnail is never larger than
+/// `MAX_WIDTH` x `MAX_HEIGHT` pixels.
+const MAX_HEIGHT: u32 = 4000;
+/// Upper bound on thumbnails per document, so "all" stays cheap for long documents.
+const MAX_THUMBNAILS: usize = 100;
+
+/// Which pages of the generated PDF to rasterize, and how wide.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThumbnailOptions {
+    /// Page selection like `1`, `1-3,5` or `all`; the first page when omitted
+    pub pages: Option<String>,
+    /// Width of each thumbnail in pixels, the height follows from the page's aspect ratio.
+    /// Pages taller than twice their width come out narrower.
+    pub width: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Thumbnail {
+    /// 1-based page number
+    pub page: u32,
+    pub width: u32,
+    pub height: u32,
+    pub png_base64: String,
+}
+
+impl ThumbnailOptions {
+    pub fn validate(&self) -> Result<(), RenderError> {
+        if !(1..=MAX_WIDTH).contains(&self.width()) {
+            return Err(RenderError::InvalidPdfOptions(format!(
+                "thumbnail width must be between 1 and {}",
+                MAX_WIDTH
+            )));
+        }
+        self.page_range()?;
+
+        Ok(())
+    }
+
+    fn width(&self) -> u32 {
+        self.width.unwrap_or(DEFAULT_WIDTH)
+    }
+
+    fn page_range(&self) -> Result<PageRange, RenderError> {
+        PageRange::parse(self.pages.as_deref().unwrap_or("1"))
+    }
+}
+
+/// Rasterizes PDF pages with PDFium. Without a usable PDFium library thumbnails are simply
+/// unavailable; the rest of the service works as before.
+pub struct Thumbnailer {
+    pdfium: Option<Pdfium>,
+}
+
+impl Thumbnailer {
+    pub fn new(library_path: Option<&Path>) -> Self {
+        let bindings = match library_path {
+            Some(path) if path.is_dir() => {
+                Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path(path))
+            }
+            Some(path) => Pdfium::bind_to_library(path),
+            None => Pdfium::bind_to_system_library(),
+        };
+
+        match bindings {
+            Ok(bindings) => Self {
+                pdfium: Some(Pdfium::new(bindings)),
+            },
+            Err(e) => {
+                tracing::warn!("PDFium is not available, thumbnails are disabled: {}", e);
+                Self { pdfium: None }
+            }
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.pdfium.is_some()
+    }
+
+    /// Render the selected pages of `pdf` to PNG. Blocking, run it off the async runtime.
+    ///
+    /// `password` is the user password of encrypted documents.
+    pub fn render(
+        &self,
+        pdf: &[u8],
+        password: Option<&str>,
+        options: &ThumbnailOptions,
+    ) -> Result<Vec<Thumbnail>> {
+        let pdfium = self
+            .pdfium
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("PDFium is not available"))?;
+        let page_range = options.page_range()?;
+        let config = PdfRenderConfig::new()
+            .set_target_width(options.width() as i32)
+            .set_maximum_height(MAX_HEIGHT as i32);
+
+        let document = pdfium.load_pdf_from_byte_slice(pdf, password)?;
+        let pages = document.pages();
+        let total = pages.len() as u32;
+
+        let mut thumbnails = Vec::new();
+        for (index, page) in pages.iter().enumerate() {
+            let number = index as u32 + 1;
+            if !page_range.contains(number, total) {
+                continue;
+            }
+            if thumbnails.len() == MAX_THUMBNAILS {
+                break;
+            }
+
+            let image = page.render_with_config(&config)?.as_image();
+            let mut png = Cursor::new(Vec::new());
+            image.write_to(&mut png, image::ImageFormat::Png)?;
+
+            thumbnails.push(Thumbnail {
+                page: number,
+                width: image.width(),
+                height: image.height(),
+                png_base64: general_purpose::STANDARD.encode(png.into_inner()),
+            });
+        }
+
+        Ok(thumbnails)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(pages: Option<&str>, width: Option<u32>) -> ThumbnailOptions {
+        ThumbnailOptions {
+            pages: pages.map(str::to_string),
+            width,
+        }
+    }
+
+    #[test]
+    fn width_is_bounded() {
+        assert!(options(None, None).validate().is_ok());
+        assert!(options(None, Some(1)).validate().is_ok());
+        assert!(options(None, Some(MAX_WIDTH)).validate().is_ok());
+        assert!(options(None, Some(0)).validate().is_err());
+        assert!(options(None, Some(MAX_WIDTH + 1)).validate().is_err());
+    }
+
+    #[test]
+    fn first_page_unless_pages_are_given() {
+        let first = options(None, None).page_range().unwrap();
+        assert!(first.contains(1, 5) && !first.contains(2, 5));
+
+        let all = options(Some("all"), None).page_range().unwrap();
+        assert!((1..=5).all(|page| all.contains(page, 5)));
+
+        assert!(options(Some("2-"), None).validate().is_ok());
+        assert!(options(Some("0"), None).validate().is_err());
+        assert!(options(Some("first"), None).validate().is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use serde::Deserialize;
+use url::Url;
+
+use crate::error::RenderError;
+
+/// Allow-list deciding which URLs the service may navigate to when rendering a `url` source.
+///
+/// Hosts are matched exactly, or by suffix when written as `*.example.com`. An entry may pin a
+/// port (`localhost:8080`); without one any port is accepted. An empty host list disables URL
+/// rendering altogether.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct UrlPolicy {
+    pub allowed_schemes: Vec<String>,
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for UrlPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: vec!["https".to_string()],
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+impl UrlPolicy {
+    pub fn check(&self, url: &Url) -> Result<(), RenderError> {
+        if !self
+            .allowed_schemes
+            .iter()
+            .any(|scheme| scheme.eq_ignore_ascii_case(url.scheme()))
+        {
+            return Err(RenderError::UrlNotAllowed(format!(
+                "scheme '{}' is not allowed",
+                url.scheme()
+            )));
+        }
+
+        let Some(host) = url.host_str() else {
+            return Err(RenderError::UrlNotAllowed("URL has no host".to_string()));
+        };
+
+        let port = url.port_or_known_default();
+        if self
+            .allowed_hosts
+            .iter()
+            .any(|entry| host_matches(entry, host, port))
+        {
+            Ok(())
+        } else {
+            Err(RenderError::UrlNotAllowed(format!(
+                "host '{}' is not allowed",
+                host
+            )))
+        }
+    }
+}
+
+fn host_matches(entry: &str, host: &str, port: Option<u16>) -> bool {
+    let (pattern, entry_port) = split_port(entry);
+
+    if entry_port.is_some() && entry_port != port {
+        return false;
+    }
+
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .strip_suffix(suffix)
+            .is_some_and(|prefix| prefix.ends_with('.')),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+fn split_port(entry: &str) -> (&str, Option<u16>) {
+    let separator = if entry.starts_with('[') {
+        entry.rfind("]:").map(|i| i + 1)
+    } else {
+        entry.rfind(':')
+    };
+
+    match separator.map(|i| (&entry[..i], entry[i + 1..].parse().ok())) {
+        Some((host, Some(port))) => (host, Some(port)),
+        _ => (entry, None),
+    }
+}
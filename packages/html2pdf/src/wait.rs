@@ -0,0 +1,264 @@
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::Result;
+use chromiumoxide::{
+    Page,
+    cdp::browser_protocol::network::{
+        EventLoadingFailed, EventLoadingFinished, EventRequestWillBeSent,
+    },
+    error::CdpError,
+    listeners::EventStream,
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::time::{Instant, sleep, timeout_at};
+
+use crate::error::RenderError;
+
+const DEFAULT_TIMEOUT_MS: u64 = 30_000;
+const MAX_TIMEOUT_MS: u64 = 120_000;
+const MAX_DELAY_MS: u64 = 30_000;
+
+/// How long the network must stay quiet before it counts as idle.
+const NETWORK_IDLE_WINDOW: Duration = Duration::from_millis(500);
+/// How often selector and expression conditions are re-evaluated.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Conditions a page has to meet before it is printed.
+///
+/// Conditions are checked in the order network idle, fonts, selector, expression, and all of
+/// them share a single `timeoutMs` budget. `delayMs` is applied afterwards and is not counted
+/// against the timeout.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WaitOptions {
+    #[serde(default)]
+    pub network_idle: bool,
+    #[serde(default)]
+    pub fonts: bool,
+    /// CSS selector that has to match at least one element
+    pub selector: Option<String>,
+    /// JS expression that has to become truthy, e.g. `window.__READY__ === true`
+    pub expression: Option<String>,
+    pub delay_ms: Option<u64>,
+    pub timeout_ms: Option<u64>,
+}
+
+impl WaitOptions {
+    /// Reject conditions that are out of bounds, before anything is rendered.
+    pub fn validate(&self) -> Result<(), RenderError> {
+        match self.delay_ms {
+            Some(delay_ms) if delay_ms > MAX_DELAY_MS => Err(RenderError::InvalidWaitCondition(
+                format!("delayMs must not exceed {}", MAX_DELAY_MS),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn timeout_ms(&self) -> u64 {
+        self.timeout_ms
+            .unwrap_or(DEFAULT_TIMEOUT_MS)
+            .min(MAX_TIMEOUT_MS)
+    }
+}
+
+/// Waits for the conditions of a [`WaitOptions`]. Created before the page starts loading so
+/// that network activity of the load itself is observed.
+pub struct ReadyWaiter<'a> {
+    options: Option<&'a WaitOptions>,
+    network: Option<NetworkIdle>,
+}
+
+impl<'a> ReadyWaiter<'a> {
+    pub async fn prepare(page: &Page, options: Option<&'a WaitOptions>) -> Result<Self> {
+        let network = match options {
+            Some(options) if options.network_idle => Some(NetworkIdle::listen(page).await?),
+            _ => None,
+        };
+
+        Ok(Self { options, network })
+    }
+
+    pub async fn wait(self, page: &Page) -> Result<()> {
+        let Some(options) = self.options else {
+            return Ok(());
+        };
+
+        let timeout_ms = options.timeout_ms();
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+
+        if let Some(network) = self.network {
+            within(deadline, timeout_ms, "network idle", network.wait()).await?;
+        }
+
+        if options.fonts {
+            within(deadline, timeout_ms, "document.fonts.ready", async {
+                page.evaluate_expression("document.fonts.ready.then(() => true)")
+                    .await?;
+                Ok(())
+            })
+            .await?;
+        }
+
+        if let Some(selector) = &options.selector {
+            let js = format!(
+                "document.querySelector({}) !== null",
+                serde_json::to_string(selector)?
+            );
+            let condition = format!("selector {}", selector);
+            within(
+                deadline,
+                timeout_ms,
+                &condition,
+                poll(page, &js, &condition),
+            )
+            .await?;
+        }
+
+        if let Some(expression) = &options.expression {
+            // Runtime errors just mean "not ready yet"; syntax errors can never succeed
+            let js = format!(
+                "(() => {{ try {{ return !!eval({}); }} catch (e) {{ if (e instanceof SyntaxError) throw e; return false; }} }})()",
+                serde_json::to_string(expression)?
+            );
+            let condition = format!("expression {}", expression);
+            within(
+                deadline,
+                timeout_ms,
+                &condition,
+                poll(page, &js, &condition),
+            )
+            .await?;
+        }
+
+        if let Some(delay_ms) = options.delay_ms {
+            sleep(Duration::from_millis(delay_ms)).await;
+        }
+
+        Ok(())
+    }
+}
+
+async fn within<F>(deadline: Instant, timeout_ms: u64, condition: &str, fut: F) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    timeout_at(deadline, fut)
+        .await
+        .map_err(|_| RenderError::WaitTimeout {
+            condition: condition.to_string(),
+            timeout_ms,
+        })?
+}
+
+/// Re-evaluate a boolean JS expression until it returns true.
+async fn poll(page: &Page, js: &str, condition: &str) -> Result<()> {
+    loop {
+        match page.evaluate_expression(js).await {
+            Ok(result) => {
+                if result.into_value::<bool>().unwrap_or(false) {
+                    return Ok(());
+                }
+            }
+            Err(CdpError::JavascriptException(_)) => {
+                return Err(RenderError::InvalidWaitCondition(condition.to_string()).into());
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Tracks in-flight requests through the Network domain events of a page.
+struct NetworkIdle {
+    sent: EventStream<EventRequestWillBeSent>,
+    finished: EventStream<EventLoadingFinished>,
+    failed: EventStream<EventLoadingFailed>,
+}
+
+impl NetworkIdle {
+    async fn listen(page: &Page) -> Result<Self> {
+        Ok(Self {
+            sent: page.event_listener::<EventRequestWillBeSent>().await?,
+            finished: page.event_listener::<EventLoadingFinished>().await?,
+            failed: page.event_listener::<EventLoadingFailed>().await?,
+        })
+    }
+
+    /// Resolves once no request has been in flight for [`NETWORK_IDLE_WINDOW`].
+    async fn wait(mut self) -> Result<()> {
+        let mut in_flight = HashSet::new();
+
+        loop {
+            tokio::select! {
+                Some(event) = self.sent.next() => {
+                    in_flight.insert(event.request_id.clone());
+                }
+                Some(event) = self.finished.next() => {
+                    in_flight.remove(&event.request_id);
+                }
+                Some(event) = self.failed.next() => {
+                    in_flight.remove(&event.request_id);
+                }
+                _ = sleep(NETWORK_IDLE_WINDOW), if in_flight.is_empty() => return Ok(()),
+                else => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(value: serde_json::Value) -> WaitOptions {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn delay_is_bounded() {
+        assert!(options(serde_json::json!({})).validate().is_ok());
+        assert!(
+            options(serde_json::json!({ "delayMs": 30_000 }))
+                .validate()
+                .is_ok()
+        );
+
+        let too_long = options(serde_json::json!({ "delayMs": 30_001 })).validate();
+        assert!(matches!(
+            too_long,
+            Err(RenderError::InvalidWaitCondition(_))
+        ));
+    }
+
+    #[test]
+    fn timeout_defaults_and_is_capped() {
+        assert_eq!(
+            options(serde_json::json!({})).timeout_ms(),
+            DEFAULT_TIMEOUT_MS
+        );
+        assert_eq!(
+            options(serde_json::json!({ "timeoutMs": 5_000 })).timeout_ms(),
+            5_000
+        );
+        assert_eq!(
+            options(serde_json::json!({ "timeoutMs": 600_000 })).timeout_ms(),
+            MAX_TIMEOUT_MS
+        );
+    }
+
+    #[test]
+    fn conditions_use_camel_case_names() {
+        let options = options(serde_json::json!({
+            "networkIdle": true,
+            "fonts": true,
+            "selector": "#ready",
+            "expression": "window.__READY__ === true",
+        }));
+
+        assert!(options.network_idle && options.fonts);
+        assert_eq!(options.selector.as_deref(), Some("#ready"));
+        assert!(options.validate().is_ok());
+    }
+}
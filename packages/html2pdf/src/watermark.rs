@@ -0,0 +1,683 @@
+use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose};
+use lopdf::{
+    Dictionary, Document, Object, ObjectId, Stream, StringFormat,
+    content::{Content, Operation},
+    dictionary,
+};
+use serde::Deserialize;
+
+use crate::{
+    error::RenderError,
+    pdf_tools::{self, PageRange},
+};
+
+const DEFAULT_OPACITY: f32 = 0.3;
+const DEFAULT_FONT_SIZE: f32 = 72.0;
+const DEFAULT_COLOR: &str = "#808080";
+const DEFAULT_MARGIN: f32 = 36.0;
+const DEFAULT_IMAGE_WIDTH: f32 = 120.0;
+
+/// Resource names used by the overlays, unlikely to clash with Chrome's own.
+const FONT_NAME: &str = "Html2PdfWmFont";
+const STATE_PREFIX: &str = "Html2PdfWmGs";
+const IMAGE_PREFIX: &str = "Html2PdfWmIm";
+
+/// Text or image drawn on top of the printed pages.
+///
+/// Exactly one of `text` and `image` is set. The watermark is rotated by `rotation` degrees
+/// counter-clockwise around its own center, which is placed according to `position`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Watermark {
+    pub text: Option<String>,
+    /// Base64 encoded PNG or JPEG
+    pub image: Option<String>,
+    /// Text size in points
+    pub font_size: Option<f32>,
+    /// `#rrggbb` text color
+    pub color: Option<String>,
+    /// Image width in points; the height follows from the aspect ratio unless given
+    pub width: Option<f32>,
+    pub height: Option<f32>,
+    pub opacity: Option<f32>,
+    #[serde(default)]
+    pub rotation: f32,
+    #[serde(default)]
+    pub position: Position,
+    /// Distance to the page edges in points, ignored for `center`
+    pub margin: Option<f32>,
+    /// Pages to stamp, e.g. `1`, `2-4`, `1,3,5-`; all pages when omitted
+    pub pages: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Position {
+    #[default]
+    Center,
+    Top,
+    Bottom,
+    Left,
+    Right,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Watermark {
+    pub fn validate(&self) -> Result<(), RenderError> {
+        let invalid = |message: &str| Err(RenderError::InvalidPdfOptions(message.to_string()));
+
+        match (&self.text, &self.image) {
+            (Some(text), None) if text.is_empty() => return invalid("watermark text is empty"),
+            (Some(_), None) | (None, Some(_)) => {}
+            _ => return invalid("a watermark needs either text or image"),
+        }
+        if self
+            .opacity
+            .is_some_and(|opacity| !(0.0..=1.0).contains(&opacity))
+        {
+            return invalid("watermark opacity must be between 0 and 1");
+        }
+        if self
+            .color
+            .as_deref()
+            .is_some_and(|color| parse_color(color).is_none())
+        {
+            return invalid("watermark color must look like #rrggbb");
+        }
+        let sizes = [self.font_size, self.width, self.height];
+        if sizes
+            .into_iter()
+            .flatten()
+            .any(|size| !size.is_finite() || size <= 0.0)
+        {
+            return invalid("watermark sizes must be positive numbers");
+        }
+        if self
+            .margin
+            .is_some_and(|margin| !margin.is_finite() || margin < 0.0)
+        {
+            return invalid("watermark margin must not be negative");
+        }
+        if let Some(pages) = &self.pages {
+            PageRange::parse(pages)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Draw `watermarks` on the pages of `document`, later watermarks on top of earlier ones.
+pub fn apply(document: &mut Document, watermarks: &[Watermark]) -> Result<()> {
+    let pages: Vec<ObjectId> = document.get_pages().into_values().collect();
+    let total = pages.len() as u32;
+
+    let mut font_id = None;
+    let mut overlays = Vec::new();
+    for (index, watermark) in watermarks.iter().enumerate() {
+        overlays.push(Overlay::prepare(document, index, watermark, &mut font_id)?);
+    }
+
+    for (page_index, page_id) in pages.into_iter().enumerate() {
+        let page_number = page_index as u32 + 1;
+        let stamped: Vec<&Overlay> = overlays
+            .iter()
+            .filter(|overlay| overlay.pages.contains(page_number, total))
+            .collect();
+        if !stamped.is_empty() {
+            stamp_page(document, page_id, &stamped)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A watermark with its resources already added to the document.
+struct Overlay<'a> {
+    watermark: &'a Watermark,
+    pages: PageRange,
+    state: (String, ObjectId),
+    kind: OverlayKind,
+}
+
+enum OverlayKind {
+    Text {
+        encoded: Vec<u8>,
+        font_id: ObjectId,
+        width: f32,
+        font_size: f32,
+        color: [f32; 3],
+    },
+    Image {
+        name: String,
+        id: ObjectId,
+        width: f32,
+        height: f32,
+    },
+}
+
+impl<'a> Overlay<'a> {
+    /// `font_id` is shared by all text watermarks and created by the first one.
+    fn prepare(
+        document: &mut Document,
+        index: usize,
+        watermark: &'a Watermark,
+        font_id: &mut Option<ObjectId>,
+    ) -> Result<Self> {
+        watermark.validate()?;
+
+        let opacity = watermark.opacity.unwrap_or(DEFAULT_OPACITY);
+        let state_id = document.add_object(dictionary! {
+            "Type" => "ExtGState",
+            "ca" => opacity,
+            "CA" => opacity,
+        });
+
+        let kind = match (&watermark.text, &watermark.image) {
+            (Some(text), _) => {
+                let font_size = watermark.font_size.unwrap_or(DEFAULT_FONT_SIZE);
+                let encoded = encode_win_ansi(text);
+                let width = text_width(&encoded) * font_size / 1000.0;
+                let color = parse_color(watermark.color.as_deref().unwrap_or(DEFAULT_COLOR))
+                    .unwrap_or_default();
+                let font_id = *font_id.get_or_insert_with(|| {
+                    document.add_object(dictionary! {
+                        "Type" => "Font",
+                        "Subtype" => "Type1",
+                        "BaseFont" => "Helvetica-Bold",
+                        "Encoding" => "WinAnsiEncoding",
+                    })
+                });
+
+                OverlayKind::Text {
+                    encoded,
+                    font_id,
+                    width,
+                    font_size,
+                    color,
+                }
+            }
+            (None, Some(image)) => {
+                let (id, pixel_width, pixel_height) = embed_image(document, image)?;
+                let aspect = pixel_height as f32 / pixel_width as f32;
+                let (width, height) = match (watermark.width, watermark.height) {
+                    (Some(width), Some(height)) => (width, height),
+                    (Some(width), None) => (width, width * aspect),
+                    (None, Some(height)) => (height / aspect, height),
+                    (None, None) => (DEFAULT_IMAGE_WIDTH, DEFAULT_IMAGE_WIDTH * aspect),
+                };
+
+                OverlayKind::Image {
+                    name: format!("{}{}", IMAGE_PREFIX, index),
+                    id,
+                    width,
+                    height,
+                }
+            }
+            (None, None) => unreachable!("checked by validate"),
+        };
+
+        Ok(Self {
+            watermark,
+            pages: watermark
+                .pages
+                .as_deref()
+                .map(PageRange::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            state: (format!("{}{}", STATE_PREFIX, index), state_id),
+            kind,
+        })
+    }
+
+    /// Size of the unrotated watermark in points.
+    fn size(&self) -> (f32, f32) {
+        match &self.kind {
+            // Cap height of Helvetica is about 0.72 em
+            OverlayKind::Text {
+                width, font_size, ..
+            } => (*width, font_size * 0.72),
+            OverlayKind::Image { width, height, .. } => (*width, *height),
+        }
+    }
+
+    fn operations(&self, page_box: [f32; 4]) -> Vec<Operation> {
+        let (width, height) = self.size();
+        let margin = self.watermark.margin.unwrap_or(DEFAULT_MARGIN);
+        let [left, bottom, right, top] = page_box;
+
+        let center_x = (left + right) / 2.0;
+        let center_y = (bottom + top) / 2.0;
+        let start_x = left + margin + width / 2.0;
+        let end_x = right - margin - width / 2.0;
+        let low_y = bottom + margin + height / 2.0;
+        let high_y = top - margin - height / 2.0;
+
+        let (x, y) = match self.watermark.position {
+            Position::Center => (center_x, center_y),
+            Position::Top => (center_x, high_y),
+            Position::Bottom => (center_x, low_y),
+            Position::Left => (start_x, center_y),
+            Position::Right => (end_x, center_y),
+            Position::TopLeft => (start_x, high_y),
+            Position::TopRight => (end_x, high_y),
+            Position::BottomLeft => (start_x, low_y),
+            Position::BottomRight => (end_x, low_y),
+        };
+
+        let (sin, cos) = self.watermark.rotation.to_radians().sin_cos();
+        let mut operations = vec![
+            Operation::new("q", vec![]),
+            Operation::new("gs", vec![Object::Name(self.state.0.clone().into_bytes())]),
+            Operation::new(
+                "cm",
+                vec![
+                    cos.into(),
+                    sin.into(),
+                    (-sin).into(),
+                    cos.into(),
+                    x.into(),
+                    y.into(),
+                ],
+            ),
+        ];
+
+        match &self.kind {
+            OverlayKind::Text {
+                encoded,
+                font_size,
+                color,
+                ..
+            } => operations.extend([
+                Operation::new("rg", color.iter().map(|&c| c.into()).collect()),
+                Operation::new("BT", vec![]),
+                Operation::new(
+                    "Tf",
+                    vec![Object::Name(FONT_NAME.into()), (*font_size).into()],
+                ),
+                Operation::new("Td", vec![(-width / 2.0).into(), (-height / 2.0).into()]),
+                Operation::new(
+                    "Tj",
+                    vec![Object::String(encoded.clone(), StringFormat::Literal)],
+                ),
+                Operation::new("ET", vec![]),
+            ]),
+            OverlayKind::Image { name, .. } => operations.extend([
+                Operation::new(
+                    "cm",
+                    vec![
+                        width.into(),
+                        0.into(),
+                        0.into(),
+                        height.into(),
+                        (-width / 2.0).into(),
+                        (-height / 2.0).into(),
+                    ],
+                ),
+                Operation::new("Do", vec![Object::Name(name.clone().into_bytes())]),
+            ]),
+        }
+
+        operations.push(Operation::new("Q", vec![]));
+        operations
+    }
+}
+
+/// Append the overlays to the page's content, isolated from the page's own graphics state.
+fn stamp_page(document: &mut Document, page_id: ObjectId, overlays: &[&Overlay]) -> Result<()> {
+    let page_box = page_box(document, page_id)?;
+
+    let mut operations = vec![Operation::new("Q", vec![])];
+    for overlay in overlays {
+        operations.extend(overlay.operations(page_box));
+    }
+
+    let prefix_id = document.add_object(Stream::new(
+        Dictionary::new(),
+        Content {
+            operations: vec![Operation::new("q", vec![])],
+        }
+        .encode()?,
+    ));
+    // Viewers join the content streams as they are, and the page's own content may not end in
+    // whitespace that separates its last operator from our `Q`
+    let mut overlay = b"\n".to_vec();
+    overlay.extend(Content { operations }.encode()?);
+    let mut overlay_stream = Stream::new(Dictionary::new(), overlay);
+    // Compression only fails for streams that opt out of it
+    let _ = overlay_stream.compress();
+    let overlay_id = document.add_object(overlay_stream);
+
+    let mut resources = owned_dictionary(
+        document,
+        pdf_tools::page_attribute(document, page_id, b"Resources"),
+    )?;
+    for overlay in overlays {
+        let (state_name, state_id) = &overlay.state;
+        add_resource(
+            document,
+            &mut resources,
+            b"ExtGState",
+            state_name,
+            *state_id,
+        )?;
+        match &overlay.kind {
+            OverlayKind::Text { font_id, .. } => {
+                add_resource(document, &mut resources, b"Font", FONT_NAME, *font_id)?
+            }
+            OverlayKind::Image { name, id, .. } => {
+                add_resource(document, &mut resources, b"XObject", name, *id)?
+            }
+        }
+    }
+
+    let page = document.get_dictionary_mut(page_id)?;
+    let mut contents = vec![Object::Reference(prefix_id)];
+    match page.get(b"Contents") {
+        Ok(Object::Array(existing)) => contents.extend(existing.iter().cloned()),
+        Ok(existing) => contents.push(existing.clone()),
+        Err(_) => {}
+    }
+    contents.push(Object::Reference(overlay_id));
+
+    page.set("Contents", contents);
+    page.set("Resources", resources);
+
+    Ok(())
+}
+
+/// The visible area of a page as `[left, bottom, right, top]`.
+fn page_box(document: &Document, page_id: ObjectId) -> Result<[f32; 4]> {
+    let rect = pdf_tools::page_attribute(document, page_id, b"CropBox")
+        .or_else(|| pdf_tools::page_attribute(document, page_id, b"MediaBox"))
+        .map(|rect| document.dereference(rect))
+        .transpose()?;
+
+    let Some((_, Object::Array(values))) = rect else {
+        // US Letter, the PDF default
+        return Ok([0.0, 0.0, 612.0, 792.0]);
+    };
+    if values.len() != 4 {
+        anyhow::bail!("Invalid page box on page {:?}", page_id);
+    }
+
+    let mut rect = [0.0; 4];
+    for (slot, value) in rect.iter_mut().zip(values) {
+        *slot = value.as_float()?;
+    }
+
+    Ok([
+        rect[0].min(rect[2]),
+        rect[1].min(rect[3]),
+        rect[0].max(rect[2]),
+        rect[1].max(rect[3]),
+    ])
+}
+
+/// A copy of a possibly indirect dictionary, so it can be changed for a single page.
+fn owned_dictionary(document: &Document, value: Option<&Object>) -> Result<Dictionary> {
+    match value {
+        Some(value) => Ok(document.dereference(value)?.1.as_dict()?.clone()),
+        None => Ok(Dictionary::new()),
+    }
+}
+
+fn add_resource(
+    document: &Document,
+    resources: &mut Dictionary,
+    category: &[u8],
+    name: &str,
+    id: ObjectId,
+) -> Result<()> {
+    let mut entries = owned_dictionary(document, resources.get(category).ok())?;
+    entries.set(name, id);
+    resources.set(category, entries);
+    Ok(())
+}
+
+/// Add the image as an XObject, with its alpha channel as soft mask. Returns its id and size
+/// in pixels.
+fn embed_image(document: &mut Document, image: &str) -> Result<(ObjectId, u32, u32)> {
+    let invalid = |message: String| RenderError::InvalidPdfOptions(message);
+
+    let bytes = general_purpose::STANDARD
+        .decode(image.trim())
+        .map_err(|e| invalid(format!("watermark image is not valid base64: {}", e)))?;
+    let image = image::load_from_memory(&bytes)
+        .map_err(|e| invalid(format!("watermark image can not be decoded: {}", e)))?
+        .into_rgba8();
+    let (width, height) = image.dimensions();
+
+    let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+    let mut alpha = Vec::with_capacity((width * height) as usize);
+    for pixel in image.pixels() {
+        rgb.extend_from_slice(&pixel.0[..3]);
+        alpha.push(pixel.0[3]);
+    }
+
+    let mut mask = Stream::new(
+        dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Image",
+            "Width" => width,
+            "Height" => height,
+            "ColorSpace" => "DeviceGray",
+            "BitsPerComponent" => 8,
+        },
+        alpha,
+    );
+    let _ = mask.compress();
+    let mask_id = document.add_object(mask);
+
+    let mut stream = Stream::new(
+        dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Image",
+            "Width" => width,
+            "Height" => height,
+            "ColorSpace" => "DeviceRGB",
+            "BitsPerComponent" => 8,
+            "SMask" => mask_id,
+        },
+        rgb,
+    );
+    let _ = stream.compress();
+
+    Ok((document.add_object(stream), width, height))
+}
+
+fn parse_color(color: &str) -> Option<[f32; 3]> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut rgb = [0.0; 3];
+    for (channel, slot) in rgb.iter_mut().enumerate() {
+        let value = u8::from_str_radix(&hex[channel * 2..channel * 2 + 2], 16).ok()?;
+        *slot = value as f32 / 255.0;
+    }
+    Some(rgb)
+}
+
+/// Encode text for a standard font with `WinAnsiEncoding`. Latin-1 maps onto it directly,
+/// anything else is replaced.
+fn encode_win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c as u32 {
+            code @ (0x20..=0x7e | 0xa0..=0xff) => code as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+/// Width of WinAnsi encoded text in Helvetica-Bold, in thousandths of the font size.
+fn text_width(encoded: &[u8]) -> f32 {
+    encoded
+        .iter()
+        .map(|&code| match code {
+            0x20..=0x7e => HELVETICA_BOLD_WIDTHS[(code - 0x20) as usize],
+            _ => 556,
+        } as f32)
+        .sum()
+}
+
+/// Glyph widths of Helvetica-Bold for the printable ASCII range, from the font's AFM metrics.
+const HELVETICA_BOLD_WIDTHS: [u16; 95] = [
+    278, 333, 474, 556, 556, 889, 722, 238, 333, 333, 389, 584, 278, 333, 278, 278, // ' '-'/'
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, 333, 333, 584, 584, 584, 611, // '0'-'?'
+    975, 722, 722, 722, 722, 667, 611, 778, 722, 278, 556, 722, 611, 833, 722, 778, // '@'-'O'
+    667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 333, 278, 333, 584, 556, // 'P'-'_'
+    333, 556, 611, 556, 611, 556, 333, 611, 611, 278, 278, 556, 278, 889, 611, 611, // '`'-'o'
+    611, 611, 389, 556, 333, 611, 556, 778, 556, 556, 500, 389, 280, 389, 584, // 'p'-'~'
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(value: serde_json::Value) -> Watermark {
+        let mut watermark = serde_json::json!({ "text": "DRAFT" });
+        watermark
+            .as_object_mut()
+            .unwrap()
+            .extend(value.as_object().unwrap().clone());
+        serde_json::from_value(watermark).unwrap()
+    }
+
+    /// A document of `pages` pages, each with its own small content stream.
+    fn document(pages: u32) -> Document {
+        let mut document = Document::with_version("1.7");
+        let pages_id = document.new_object_id();
+        let kids: Vec<Object> = (0..pages)
+            .map(|_| {
+                let content_id = document.add_object(Stream::new(
+                    Dictionary::new(),
+                    b"1 0 0 1 10 10 cm 0 0 m 100 100 l S".to_vec(),
+                ));
+                document
+                    .add_object(dictionary! {
+                        "Type" => "Page",
+                        "Parent" => pages_id,
+                        "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+                        "Contents" => content_id,
+                    })
+                    .into()
+            })
+            .collect();
+        document.objects.insert(
+            pages_id,
+            dictionary! { "Type" => "Pages", "Count" => pages, "Kids" => kids }.into(),
+        );
+        let catalog_id = document.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        document.trailer.set("Root", catalog_id);
+        document
+    }
+
+    fn operators(document: &Document, page_id: ObjectId) -> Vec<String> {
+        let content = document.get_page_content(page_id).unwrap();
+        Content::decode(&content)
+            .unwrap()
+            .operations
+            .into_iter()
+            .map(|operation| operation.operator)
+            .collect()
+    }
+
+    #[test]
+    fn colors_are_six_digit_hex() {
+        assert_eq!(parse_color("#ff8000"), Some([1.0, 128.0 / 255.0, 0.0]));
+        assert_eq!(parse_color("#FFFFFF"), Some([1.0, 1.0, 1.0]));
+        assert_eq!(parse_color("ff8000"), None);
+        assert_eq!(parse_color("#fff"), None);
+        assert_eq!(parse_color("#gg0000"), None);
+        assert_eq!(parse_color("#ÿÿÿ"), None);
+    }
+
+    #[test]
+    fn sizes_must_be_positive() {
+        assert!(text(serde_json::json!({})).validate().is_ok());
+        assert!(text(serde_json::json!({ "margin": 0 })).validate().is_ok());
+
+        for invalid in [
+            serde_json::json!({ "fontSize": 0 }),
+            serde_json::json!({ "fontSize": -12 }),
+            serde_json::json!({ "width": 0 }),
+            serde_json::json!({ "height": 0 }),
+            serde_json::json!({ "margin": -1 }),
+            serde_json::json!({ "opacity": 1.5 }),
+            serde_json::json!({ "color": "red" }),
+            serde_json::json!({ "pages": "3-1" }),
+        ] {
+            assert!(text(invalid.clone()).validate().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn only_selected_pages_are_stamped() {
+        let mut document = document(4);
+        apply(
+            &mut document,
+            &[text(serde_json::json!({ "pages": "2,4-" }))],
+        )
+        .unwrap();
+
+        let pages = document.get_pages();
+        let stamped: Vec<u32> = pages
+            .iter()
+            .filter(|(_, page_id)| operators(&document, **page_id).contains(&"Tj".to_string()))
+            .map(|(number, _)| *number)
+            .collect();
+        assert_eq!(stamped, vec![2, 4]);
+    }
+
+    #[test]
+    fn overlays_are_isolated_from_the_page_content() {
+        let mut document = document(1);
+        apply(
+            &mut document,
+            &[text(serde_json::json!({ "rotation": 45 }))],
+        )
+        .unwrap();
+
+        let page_id = document.page_iter().next().unwrap();
+        let operators = operators(&document, page_id);
+        // The page's own content runs inside q/Q, the overlay in a graphics state of its own
+        assert_eq!(
+            operators,
+            vec![
+                "q", "cm", "m", "l", "S", "Q", "q", "gs", "cm", "rg", "BT", "Tf", "Td", "Tj", "ET",
+                "Q"
+            ]
+        );
+
+        let resources = document
+            .get_dictionary(page_id)
+            .unwrap()
+            .get(b"Resources")
+            .and_then(Object::as_dict)
+            .unwrap();
+        assert!(
+            resources
+                .get(b"Font")
+                .unwrap()
+                .as_dict()
+                .unwrap()
+                .has(FONT_NAME.as_bytes())
+        );
+        assert!(
+            resources
+                .get(b"ExtGState")
+                .unwrap()
+                .as_dict()
+                .unwrap()
+                .has(format!("{}0", STATE_PREFIX).as_bytes())
+        );
+    }
+}